@@ -0,0 +1,212 @@
+//! ICY metadata of the webradios, the `StreamTitle` interleaved with the audio.
+
+enum IcyState {
+    /// Audio bytes left before the next metadata block
+    Audio(usize),
+    /// Next byte is the metadata block length, in 16 bytes units
+    Length,
+    /// Metadata bytes left in the current block
+    Metadata(usize),
+}
+
+/// Strips the ICY metadata blocks interleaved every `interval` bytes of audio.
+///
+/// Metadata blocks are accumulated across reads, so a title split between two buffers is
+/// only reported once complete.
+pub struct IcyMetadata {
+    interval: usize,
+    state: IcyState,
+    block: Vec<u8>,
+    title: Option<String>,
+}
+
+impl IcyMetadata {
+    /// `interval` is the `icy-metaint` header of the response, which must not be 0.
+    pub fn new(interval: usize) -> Self {
+        Self {
+            interval,
+            state: IcyState::Audio(interval),
+            block: Vec::new(),
+            title: None,
+        }
+    }
+
+    /// Audio bytes between two metadata blocks.
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    /// Removes metadata from `data` in place, returns the length of the remaining audio.
+    pub fn strip(&mut self, data: &mut [u8]) -> usize {
+        let mut audio_len = 0;
+        for i in 0..data.len() {
+            let byte = data[i];
+            self.state = match self.state {
+                IcyState::Audio(remaining) => {
+                    data[audio_len] = byte;
+                    audio_len += 1;
+                    if remaining > 1 {
+                        IcyState::Audio(remaining - 1)
+                    } else {
+                        IcyState::Length
+                    }
+                }
+                IcyState::Length if byte == 0 => IcyState::Audio(self.interval),
+                IcyState::Length => {
+                    self.block.clear();
+                    IcyState::Metadata(byte as usize * 16)
+                }
+                IcyState::Metadata(remaining) => {
+                    self.block.push(byte);
+                    if remaining > 1 {
+                        IcyState::Metadata(remaining - 1)
+                    } else {
+                        if let Some(title) = parse_stream_title(&self.block) {
+                            self.title = Some(title);
+                        }
+                        IcyState::Audio(self.interval)
+                    }
+                }
+            };
+        }
+        audio_len
+    }
+
+    /// Returns the last `StreamTitle` received, only once.
+    pub fn take_title(&mut self) -> Option<String> {
+        self.title.take()
+    }
+}
+
+/// Extracts `StreamTitle` from a block like `StreamTitle='Artist - Song';StreamUrl='';`
+pub fn parse_stream_title(block: &[u8]) -> Option<String> {
+    const TITLE_START: &str = "StreamTitle='";
+    let text = String::from_utf8_lossy(block);
+    let start = text.find(TITLE_START)? + TITLE_START.len();
+    let end = text[start..]
+        .find("';")
+        .or_else(|| text[start..].rfind('\''))
+        .map_or(text.len(), |end| start + end);
+    Some(text[start..end].trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A metadata block as sent by the server: its length in 16 bytes units, then the text
+    /// padded with zeros.
+    fn block(text: &str) -> Vec<u8> {
+        let units = text.len().div_ceil(16);
+        let mut block = vec![units as u8];
+        block.extend_from_slice(text.as_bytes());
+        block.resize(1 + units * 16, 0);
+        block
+    }
+
+    #[test]
+    fn strip_removes_block_at_metaint_boundary() {
+        let mut icy = IcyMetadata::new(4);
+        let mut data = b"abcd".to_vec();
+        data.extend(block("StreamTitle='Song';"));
+        data.extend_from_slice(b"efgh");
+
+        let len = icy.strip(&mut data);
+        assert_eq!(&data[..len], b"abcdefgh");
+        assert_eq!(icy.take_title().as_deref(), Some("Song"));
+        assert_eq!(icy.take_title(), None);
+    }
+
+    #[test]
+    fn strip_handles_buffer_ending_on_metaint() {
+        let mut icy = IcyMetadata::new(4);
+        let mut first = b"abcd".to_vec();
+        assert_eq!(icy.strip(&mut first), 4);
+
+        let mut second = block("StreamTitle='Song';");
+        second.extend_from_slice(b"ef");
+        let len = icy.strip(&mut second);
+        assert_eq!(&second[..len], b"ef");
+        assert_eq!(icy.take_title().as_deref(), Some("Song"));
+    }
+
+    #[test]
+    fn strip_handles_length_byte_ending_buffer() {
+        let mut icy = IcyMetadata::new(2);
+        let metadata = block("StreamTitle='Song';");
+        let mut first = b"ab".to_vec();
+        first.push(metadata[0]);
+        assert_eq!(icy.strip(&mut first), 2);
+        assert_eq!(icy.take_title(), None);
+
+        let mut second = metadata[1..].to_vec();
+        second.extend_from_slice(b"cd");
+        let len = icy.strip(&mut second);
+        assert_eq!(&second[..len], b"cd");
+        assert_eq!(icy.take_title().as_deref(), Some("Song"));
+    }
+
+    #[test]
+    fn strip_reports_title_split_across_buffers() {
+        let mut icy = IcyMetadata::new(2);
+        let mut data = b"ab".to_vec();
+        data.extend(block("StreamTitle='Artist - Song';"));
+        data.extend_from_slice(b"cd");
+        let (first, second) = data.split_at_mut(10);
+
+        assert_eq!(icy.strip(first), 2);
+        assert_eq!(icy.take_title(), None);
+        let len = icy.strip(second);
+        assert_eq!(&second[..len], b"cd");
+        assert_eq!(icy.take_title().as_deref(), Some("Artist - Song"));
+    }
+
+    #[test]
+    fn strip_skips_zero_length_block() {
+        let mut icy = IcyMetadata::new(2);
+        let mut data = b"ab\0cd\0ef".to_vec();
+        let len = icy.strip(&mut data);
+        assert_eq!(&data[..len], b"abcdef");
+        assert_eq!(icy.take_title(), None);
+    }
+
+    #[test]
+    fn strip_keeps_last_title_when_block_has_none() {
+        let mut icy = IcyMetadata::new(1);
+        let mut data = b"a".to_vec();
+        data.extend(block("StreamTitle='Song';"));
+        data.push(b'b');
+        data.extend(block("StreamUrl='';"));
+        data.push(b'c');
+
+        let len = icy.strip(&mut data);
+        assert_eq!(&data[..len], b"abc");
+        assert_eq!(icy.take_title().as_deref(), Some("Song"));
+    }
+
+    #[test]
+    fn parse_stream_title_reads_title() {
+        assert_eq!(
+            parse_stream_title(b"StreamTitle='Artist - Song';StreamUrl='';").as_deref(),
+            Some("Artist - Song")
+        );
+        assert_eq!(parse_stream_title(b"StreamTitle='';").as_deref(), Some(""));
+        assert_eq!(parse_stream_title(b"StreamUrl='http://radio';"), None);
+    }
+
+    #[test]
+    fn parse_stream_title_keeps_semicolon_in_title() {
+        assert_eq!(
+            parse_stream_title(b"StreamTitle='a;b';").as_deref(),
+            Some("a;b")
+        );
+    }
+
+    #[test]
+    fn parse_stream_title_ignores_padding() {
+        assert_eq!(
+            parse_stream_title(b"StreamTitle='Song';\0\0\0").as_deref(),
+            Some("Song")
+        );
+    }
+}
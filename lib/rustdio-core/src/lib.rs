@@ -0,0 +1,6 @@
+//! Logic of rustdio which doesn't need the ESP-IDF, so that `cargo test` runs it on the host.
+
+pub mod icy;
+pub mod mqtt;
+pub mod radios;
+pub mod vs1053;
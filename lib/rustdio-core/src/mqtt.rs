@@ -0,0 +1,113 @@
+//! Commands received over MQTT.
+
+/// Received on the `<prefix>/set/...` topics.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MqttCommand {
+    /// `set/station`, with the station id as payload
+    Station(String),
+    /// `set/volume`, 0 to 100
+    Volume(u8),
+    /// `set/playback`, `play` or `pause`
+    Paused(bool),
+}
+
+impl MqttCommand {
+    /// Parses a message received on `topic`, `None` if it isn't a valid command.
+    ///
+    /// Volumes above 100 are capped to 100, as the web API does, the state published
+    /// afterwards telling the volume applied.
+    pub fn parse(topic_prefix: &str, topic: &str, payload: &[u8]) -> Option<Self> {
+        let command = topic.strip_prefix(topic_prefix)?.strip_prefix("/set/")?;
+        let payload = std::str::from_utf8(payload).ok()?.trim();
+        match command {
+            "station" => Some(MqttCommand::Station(payload.to_string())),
+            "volume" => payload
+                .parse::<u8>()
+                .ok()
+                .map(|volume| MqttCommand::Volume(volume.min(100))),
+            "playback" => match payload {
+                "play" => Some(MqttCommand::Paused(false)),
+                "pause" => Some(MqttCommand::Paused(true)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(topic: &str, payload: &str) -> Option<MqttCommand> {
+        MqttCommand::parse("rustdio", topic, payload.as_bytes())
+    }
+
+    #[test]
+    fn parse_reads_commands() {
+        assert_eq!(
+            parse("rustdio/set/station", "fip"),
+            Some(MqttCommand::Station("fip".to_string()))
+        );
+        assert_eq!(
+            parse("rustdio/set/volume", " 42\n"),
+            Some(MqttCommand::Volume(42))
+        );
+        assert_eq!(
+            parse("rustdio/set/playback", "play"),
+            Some(MqttCommand::Paused(false))
+        );
+        assert_eq!(
+            parse("rustdio/set/playback", "pause"),
+            Some(MqttCommand::Paused(true))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_other_topics() {
+        assert_eq!(parse("other/set/volume", "42"), None);
+        assert_eq!(parse("rustdio2/set/volume", "42"), None);
+        assert_eq!(parse("rustdio/state", "42"), None);
+        assert_eq!(parse("rustdio/set/unknown", "42"), None);
+        assert_eq!(parse("rustdio/set/", "42"), None);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_volumes() {
+        assert_eq!(parse("rustdio/set/volume", "loud"), None);
+        assert_eq!(parse("rustdio/set/volume", ""), None);
+        assert_eq!(parse("rustdio/set/volume", "-1"), None);
+        assert_eq!(parse("rustdio/set/volume", "256"), None);
+    }
+
+    #[test]
+    fn parse_caps_volume_to_100() {
+        assert_eq!(
+            parse("rustdio/set/volume", "100"),
+            Some(MqttCommand::Volume(100))
+        );
+        assert_eq!(
+            parse("rustdio/set/volume", "101"),
+            Some(MqttCommand::Volume(100))
+        );
+        assert_eq!(
+            parse("rustdio/set/volume", "255"),
+            Some(MqttCommand::Volume(100))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_other_playback_payloads() {
+        assert_eq!(parse("rustdio/set/playback", "stop"), None);
+        assert_eq!(parse("rustdio/set/playback", "Play"), None);
+        assert_eq!(parse("rustdio/set/playback", ""), None);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_utf8() {
+        assert_eq!(
+            MqttCommand::parse("rustdio", "rustdio/set/station", &[0xff, 0xfe]),
+            None
+        );
+    }
+}
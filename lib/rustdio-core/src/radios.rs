@@ -0,0 +1,415 @@
+use crate::vs1053::Codec;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// Lower bound of the EuropeUS FM band
+pub const FM_BAND_MIN_MHZ: f32 = 87.5;
+/// Upper bound of the EuropeUS FM band
+pub const FM_BAND_MAX_MHZ: f32 = 108.0;
+
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Station<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    /// `None` for the web-only stations
+    pub fm_frequency: Option<f32>,
+    pub web_url: &'a str,
+    /// Genre used to group the stations, e.g. `music`, `news`, `talk` or `jazz`
+    pub category: &'a str,
+    /// Volume switched to, for the stations mastered louder or quieter than the others
+    pub default_volume: Option<u8>,
+}
+
+/// A station as received from the API or stored in NVS, owning its strings.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct StationData {
+    pub id: String,
+    pub name: String,
+    /// Missing or `null` for a web-only station
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fm_frequency: Option<f32>,
+    pub web_url: String,
+    /// Missing from the stations stored before categories existed
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub category: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub default_volume: Option<u8>,
+}
+
+impl StationData {
+    // Stations stored before web-only ones existed used an out of band frequency instead
+    fn fm_frequency(&self) -> Option<f32> {
+        self.fm_frequency
+            .filter(|frequency| *frequency >= FM_BAND_MIN_MHZ)
+    }
+
+    fn default_volume(&self) -> Option<u8> {
+        self.default_volume.map(|volume| volume.min(100))
+    }
+
+    /// Borrows the strings, e.g. to store the station before leaking it.
+    pub fn as_station(&self) -> Station<'_> {
+        Station {
+            id: &self.id,
+            name: &self.name,
+            fm_frequency: self.fm_frequency(),
+            web_url: &self.web_url,
+            category: &self.category,
+            default_volume: self.default_volume(),
+        }
+    }
+
+    /// Keeps the station for as long as the firmware runs, like the built-in ones.
+    pub fn leak(self) -> &'static Station<'static> {
+        // Read before the strings are moved out
+        let (fm_frequency, default_volume) = (self.fm_frequency(), self.default_volume());
+        Box::leak(Box::new(Station {
+            id: self.id.leak(),
+            name: self.name.leak(),
+            fm_frequency,
+            web_url: self.web_url.leak(),
+            category: self.category.leak(),
+            default_volume,
+        }))
+    }
+}
+
+/// Stations added by the user, merged after the built-in ones
+pub static USER_STATIONS: Mutex<Vec<&'static Station<'static>>> = Mutex::new(Vec::new());
+
+static STATIONS: [Station; 18] = [
+    Station {
+        id: "bfm_business",
+        name: "BFM Business",
+        fm_frequency: Some(96.4),
+        web_url: "",
+        category: "news",
+        default_volume: None,
+    },
+    Station {
+        id: "cherie_fm",
+        name: "Cherie FM",
+        fm_frequency: Some(91.3),
+        web_url: "",
+        category: "music",
+        default_volume: None,
+    },
+    Station {
+        id: "europe_1",
+        name: "Europe 1",
+        fm_frequency: Some(104.7),
+        web_url: "",
+        category: "talk",
+        default_volume: None,
+    },
+    Station {
+        id: "europe_2",
+        name: "Europe 2",
+        fm_frequency: Some(103.5),
+        web_url: "http://europe2.lmn.fm/europe2.mp3",
+        category: "music",
+        default_volume: None,
+    },
+    Station {
+        id: "fip",
+        name: "FIP",
+        fm_frequency: Some(105.1),
+        web_url: "http://icecast.radiofrance.fr/fip-hifi.aac",
+        category: "music",
+        default_volume: Some(60),
+    },
+    Station {
+        id: "france_info",
+        name: "France Info",
+        fm_frequency: Some(105.5),
+        web_url: "http://icecast.radiofrance.fr/franceinfo-hifi.aac",
+        category: "news",
+        default_volume: None,
+    },
+    Station {
+        id: "france_inter",
+        name: "France Inter",
+        fm_frequency: Some(87.6),
+        web_url: "",
+        category: "talk",
+        default_volume: None,
+    },
+    Station {
+        id: "france_inter_2",
+        name: "France Inter Test 2",
+        fm_frequency: Some(87.8),
+        web_url: "",
+        category: "talk",
+        default_volume: None,
+    },
+    Station {
+        id: "le_mouv",
+        name: "Le Mouv",
+        fm_frequency: Some(92.1),
+        web_url: "",
+        category: "music",
+        default_volume: Some(45),
+    },
+    Station {
+        id: "nostalgie",
+        name: "Nostalgie",
+        fm_frequency: Some(90.4),
+        web_url: "https://scdn.nrjaudio.fm/adwz2/fr/30601/mp3_128.mp3",
+        category: "music",
+        default_volume: None,
+    },
+    Station {
+        id: "nrj",
+        name: "NRJ",
+        fm_frequency: Some(100.3),
+        web_url: "https://scdn.nrjaudio.fm/adwz2/fr/30001/mp3_128.mp3",
+        category: "music",
+        default_volume: Some(40),
+    },
+    Station {
+        id: "radio_enghien",
+        name: "Station Enghien",
+        fm_frequency: Some(98.0),
+        web_url: "",
+        category: "local",
+        default_volume: None,
+    },
+    Station {
+        id: "rfm",
+        name: "RFM",
+        fm_frequency: Some(103.9),
+        web_url: "http://stream.rfm.fr/rfm.mp3",
+        category: "music",
+        default_volume: None,
+    },
+    Station {
+        id: "rire_et_chansons",
+        name: "Rire & Chansons",
+        fm_frequency: Some(97.4),
+        web_url: "https://scdn.nrjaudio.fm/adwz2/fr/30401/mp3_128.mp3",
+        category: "comedy",
+        default_volume: None,
+    },
+    Station {
+        id: "rmc",
+        name: "RMC",
+        fm_frequency: Some(103.1),
+        web_url: "http://audio.bfmtv.com/rmcradio_128.mp3",
+        category: "talk",
+        default_volume: None,
+    },
+    Station {
+        id: "rtl",
+        name: "RTL",
+        fm_frequency: Some(104.3),
+        web_url: "http://icecast.rtl.fr/rtl-1-44-128?listen=webCwsBCggNCQgLDQUGBAcGBg",
+        category: "talk",
+        default_volume: None,
+    },
+    Station {
+        id: "rtl2",
+        name: "RL2",
+        fm_frequency: Some(105.9),
+        web_url: "http://icecast.rtl2.fr/rtl2-1-44-128?listen=webCwsBCggNCQgLDQUGBAcGBg",
+        category: "music",
+        default_volume: Some(45),
+    },
+    Station {
+        id: "tsf_jazz",
+        name: "TSF Jazz",
+        fm_frequency: None,
+        web_url: "https://tsfjazz.ice.infomaniak.ch/tsfjazz-high.mp3",
+        category: "jazz",
+        default_volume: Some(60),
+    },
+];
+
+impl Station<'_> {
+    pub fn has_webradio(&self) -> bool {
+        !self.web_url.is_empty()
+    }
+
+    pub fn has_fm(&self) -> bool {
+        self.fm_frequency.is_some()
+    }
+
+    /// Codec of the webradio, guessed from the extension of its URL.
+    pub fn codec(&self) -> Codec {
+        let path = self.web_url.split(['?', '#']).next().unwrap_or_default();
+        let extension = path
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_lowercase());
+        match extension.as_deref() {
+            Some("mp3") => Codec::Mp3,
+            Some("aac" | "aacp" | "m4a") => Codec::Aac,
+            _ => Codec::Unknown,
+        }
+    }
+
+    pub fn all() -> Vec<&'static Station<'static>> {
+        let user_stations = USER_STATIONS.lock().unwrap();
+        STATIONS
+            .iter()
+            .chain(user_stations.iter().copied())
+            .collect()
+    }
+
+    pub fn find(id: &str) -> Option<&'static Station<'static>> {
+        Self::all().into_iter().find(|station| station.id == id)
+    }
+
+    /// Case-insensitive substring search on the station names, an empty query matches all.
+    pub fn search_by_name(query: &str) -> Vec<&'static Station<'static>> {
+        let query = query.trim().to_lowercase();
+        Self::all()
+            .into_iter()
+            .filter(|station| station.name.to_lowercase().contains(&query))
+            .collect()
+    }
+
+    /// Stations of a category, case-insensitive.
+    pub fn by_category(category: &str) -> Vec<&'static Station<'static>> {
+        let category = category.trim();
+        Self::all()
+            .into_iter()
+            .filter(|station| station.category.eq_ignore_ascii_case(category))
+            .collect()
+    }
+
+    /// Station whose FM frequency is the closest to `freq`, within `tolerance` MHz.
+    pub fn get_by_frequency(freq: f32, tolerance: f32) -> Option<&'static Station<'static>> {
+        Self::all()
+            .into_iter()
+            .filter_map(|station| Some((station, (station.fm_frequency? - freq).abs())))
+            .filter(|(_, distance)| *distance <= tolerance)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(station, _)| station)
+    }
+
+    pub fn get_name_from_id(id: &str) -> Option<&str> {
+        Self::find(id).map(|station| station.name)
+    }
+
+    /// `None` for the web-only stations too.
+    pub fn get_fm_frequency_from_id(id: &str) -> Option<f32> {
+        Self::find(id).and_then(|station| station.fm_frequency)
+    }
+
+    /// `None` for the stations that are FM only, which have an empty `web_url`.
+    pub fn get_web_url_from_id(id: &str) -> Option<&str> {
+        Self::find(id)
+            .filter(|station| station.has_webradio())
+            .map(|station| station.web_url)
+    }
+
+    pub fn get_codec_from_id(id: &str) -> Option<Codec> {
+        Self::find(id).map(|station| station.codec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The built-in stations, the user ones depending on what the NVS holds
+    fn built_in() -> impl Iterator<Item = &'static Station<'static>> {
+        STATIONS.iter()
+    }
+
+    #[test]
+    fn get_name_from_id_finds_stations() {
+        assert_eq!(Station::get_name_from_id("rtl2"), Some("RL2"));
+        assert_eq!(Station::get_name_from_id("tsf_jazz"), Some("TSF Jazz"));
+        assert_eq!(Station::get_name_from_id("unknown"), None);
+        assert_eq!(Station::get_name_from_id(""), None);
+    }
+
+    #[test]
+    fn get_fm_frequency_from_id_finds_stations() {
+        assert_eq!(Station::get_fm_frequency_from_id("rtl2"), Some(105.9));
+        assert_eq!(
+            Station::get_fm_frequency_from_id("bfm_business"),
+            Some(96.4)
+        );
+        // Known, but web only
+        assert_eq!(Station::get_fm_frequency_from_id("tsf_jazz"), None);
+        assert_eq!(Station::get_fm_frequency_from_id("unknown"), None);
+    }
+
+    #[test]
+    fn get_web_url_from_id_skips_fm_only_stations() {
+        assert_eq!(
+            Station::get_web_url_from_id("europe_2"),
+            Some("http://europe2.lmn.fm/europe2.mp3")
+        );
+        // Known, but FM only
+        assert_eq!(Station::get_web_url_from_id("bfm_business"), None);
+        assert_eq!(Station::get_web_url_from_id("unknown"), None);
+    }
+
+    #[test]
+    fn station_ids_are_unique() {
+        let mut ids: Vec<_> = built_in().map(|station| station.id).collect();
+        let count = ids.len();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), count, "duplicate station id");
+    }
+
+    #[test]
+    fn fm_frequencies_are_in_band() {
+        for station in built_in() {
+            let Some(frequency) = station.fm_frequency else {
+                continue;
+            };
+            assert!(
+                (FM_BAND_MIN_MHZ..=FM_BAND_MAX_MHZ).contains(&frequency),
+                "{} is out of the FM band at {} MHz",
+                station.id,
+                frequency
+            );
+        }
+    }
+
+    #[test]
+    fn stations_play_something() {
+        for station in built_in() {
+            assert!(
+                station.has_fm() || station.has_webradio(),
+                "{} plays nothing",
+                station.id
+            );
+        }
+    }
+
+    #[test]
+    fn default_volumes_are_percentages() {
+        for station in built_in() {
+            if let Some(volume) = station.default_volume {
+                assert!(volume <= 100, "{} defaults to {}%", station.id, volume);
+            }
+        }
+    }
+
+    #[test]
+    fn webradio_urls_are_plausible() {
+        for station in built_in().filter(|station| station.has_webradio()) {
+            let url = station.web_url;
+            let host = url
+                .strip_prefix("http://")
+                .or_else(|| url.strip_prefix("https://"));
+            assert!(
+                host.is_some_and(|host| !host.is_empty() && !host.starts_with('/')),
+                "{} has no http(s) URL: {url}",
+                station.id
+            );
+            assert!(
+                !url.contains(char::is_whitespace),
+                "{} URL has whitespace: {url}",
+                station.id
+            );
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! SCI register values of the VS1053, computed without talking to the chip.
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+fn map(x: i64, in_min: i64, in_max: i64, out_min: i64, out_max: i64) -> i64 {
+    (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min
+}
+
+/// 0..100% to the SCI_VOL attenuation of a channel, 0xFE (silent) to 0x00 (loudest).
+pub fn volume_to_attenuation(percent: u8) -> u8 {
+    // Above 100 map() goes negative, which wraps around to a loud value once cast
+    map(percent.min(100).into(), 0, 100, 0xFE, 0x00) as u8
+}
+
+/// SCI_VOL for `volume`, 0..100%, the left channel in the high byte.
+///
+/// `balance`, -100 (left only) to 100 (right only), lowers the opposite channel.
+pub fn sci_vol(volume: u8, balance: i8) -> u16 {
+    let volume = volume.min(100);
+    let (mut left, mut right) = (volume, volume);
+    match balance.clamp(-100, 100) {
+        // Towards the left, the right channel is the one lowered
+        balance if balance < 0 => right = volume.saturating_sub(balance.unsigned_abs()),
+        balance if balance > 0 => left = volume.saturating_sub(balance as u8),
+        _ => {}
+    };
+    ((volume_to_attenuation(left) as u16) << 8) | volume_to_attenuation(right) as u16
+}
+
+/// Packs the bass and treble settings into SCI_BASS, `None` if one doesn't fit its 4 bits.
+pub fn sci_bass(bass_freq: u8, bass_amp: u8, treble_freq: u8, treble_amp: u8) -> Option<u16> {
+    if [bass_freq, bass_amp, treble_freq, treble_amp]
+        .iter()
+        .any(|nibble| *nibble > 0xF)
+    {
+        return None;
+    }
+    Some(
+        ((treble_amp as u16) << 12)
+            | ((treble_freq as u16) << 8)
+            | ((bass_amp as u16) << 4)
+            | bass_freq as u16,
+    )
+}
+
+/// Audio format of a stream
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum Codec {
+    Mp3,
+    Aac,
+    Wav,
+    Wma,
+    Ogg,
+    Flac,
+    Midi,
+    Unknown,
+}
+
+/// Stream being decoded, as detected by the decoder, see `VS1053::stream_info` of the firmware
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct StreamInfo {
+    pub codec: Codec,
+    /// `None` until known, or for free format MP3
+    pub bitrate_kbps: Option<u16>,
+    /// 0 until the first frame is decoded
+    pub sample_rate: u32,
+    pub stereo: bool,
+}
+
+// HDAT1 of the non MP3 formats, see the datasheet 9.6.10 SCI_HDAT0 and SCI_HDAT1
+const HDAT1_WAV: u16 = 0x7665; // "ve"
+const HDAT1_AAC_ADTS: u16 = 0x4154; // "AT"
+const HDAT1_AAC_ADIF: u16 = 0x4144; // "AD"
+const HDAT1_AAC_MP4: u16 = 0x4D34; // "M4"
+const HDAT1_WMA: u16 = 0x574D; // "WM"
+const HDAT1_MIDI: u16 = 0x4D54; // "MT"
+const HDAT1_OGG: u16 = 0x4F67; // "Og"
+const HDAT1_FLAC: u16 = 0x664C; // "fL"
+/// MP3 frame sync, HDAT1 holding the rest of the frame header
+const HDAT1_MP3_SYNC: u16 = 0xFFE0;
+
+/// Bitrates of the MP3 header bitrate index, 1 to 14, in kbit/s
+const MPEG1_LAYER1_BITRATES: [u16; 14] = [
+    32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+];
+const MPEG1_LAYER2_BITRATES: [u16; 14] = [
+    32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+];
+const MPEG1_LAYER3_BITRATES: [u16; 14] = [
+    32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const MPEG2_LAYER1_BITRATES: [u16; 14] = [
+    32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+];
+const MPEG2_LAYER23_BITRATES: [u16; 14] =
+    [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+impl StreamInfo {
+    /// Decodes SCI_HDAT0, SCI_HDAT1 and SCI_AUDATA.
+    pub fn from_registers(hdat0: u16, hdat1: u16, audata: u16) -> Self {
+        let codec = match hdat1 {
+            HDAT1_WAV => Codec::Wav,
+            HDAT1_AAC_ADTS | HDAT1_AAC_ADIF | HDAT1_AAC_MP4 => Codec::Aac,
+            HDAT1_WMA => Codec::Wma,
+            HDAT1_MIDI => Codec::Midi,
+            HDAT1_OGG => Codec::Ogg,
+            HDAT1_FLAC => Codec::Flac,
+            _ if hdat1 & HDAT1_MP3_SYNC == HDAT1_MP3_SYNC => Codec::Mp3,
+            _ => Codec::Unknown,
+        };
+        let bitrate_kbps = match codec {
+            Codec::Mp3 => mp3_bitrate_kbps(hdat0, hdat1),
+            // The other formats give their average byte rate
+            _ => Some((hdat0 as u32 * 8 / 1000) as u16).filter(|bitrate| *bitrate > 0),
+        };
+        StreamInfo {
+            codec,
+            bitrate_kbps,
+            // Bits 15:1 hold half the sample rate, bit 0 whether stereo
+            sample_rate: (audata & 0xFFFE) as u32,
+            stereo: audata & 1 != 0,
+        }
+    }
+}
+
+/// Bitrate of the MP3 frame header, HDAT1 holding the version and layer, HDAT0 the index.
+fn mp3_bitrate_kbps(hdat0: u16, hdat1: u16) -> Option<u16> {
+    let is_mpeg1 = (hdat1 >> 3) & 0b11 == 0b11;
+    let bitrates = match ((hdat1 >> 1) & 0b11, is_mpeg1) {
+        (0b11, true) => &MPEG1_LAYER1_BITRATES,
+        (0b10, true) => &MPEG1_LAYER2_BITRATES,
+        (0b01, true) => &MPEG1_LAYER3_BITRATES,
+        (0b11, false) => &MPEG2_LAYER1_BITRATES,
+        (0b10 | 0b01, false) => &MPEG2_LAYER23_BITRATES,
+        _ => return None,
+    };
+    // Index 0 is free format, and 15 invalid
+    let index = (hdat0 >> 12) as usize;
+    bitrates.get(index.checked_sub(1)?).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_endpoints() {
+        assert_eq!(map(0, 0, 100, 0xFE, 0x00), 0xFE);
+        assert_eq!(map(100, 0, 100, 0xFE, 0x00), 0x00);
+        assert_eq!(map(50, 0, 100, 0xFE, 0x00), 0x7F);
+    }
+
+    #[test]
+    fn volume_to_attenuation_clamps_above_100() {
+        assert_eq!(volume_to_attenuation(0), 0xFE);
+        assert_eq!(volume_to_attenuation(50), 0x7F);
+        assert_eq!(volume_to_attenuation(100), 0x00);
+        assert_eq!(volume_to_attenuation(101), 0x00);
+        assert_eq!(volume_to_attenuation(u8::MAX), 0x00);
+    }
+
+    #[test]
+    fn sci_vol_maps_percent_to_attenuation() {
+        assert_eq!(sci_vol(100, 0), 0x0000);
+        assert_eq!(sci_vol(50, 0), 0x7F7F);
+        assert_eq!(sci_vol(0, 0), 0xFEFE);
+        assert_eq!(sci_vol(200, 0), 0x0000);
+    }
+
+    #[test]
+    fn balance_lowers_the_opposite_channel() {
+        // Left in the high byte, 80% for 0x33 and 60% for 0x66
+        assert_eq!(sci_vol(80, -20), 0x3366);
+        assert_eq!(sci_vol(80, 20), 0x6633);
+        assert_eq!(sci_vol(80, -100), 0x33FE);
+    }
+
+    #[test]
+    fn balance_never_raises_a_channel_above_the_volume() {
+        // Used to give the right channel 110%, mapped to a negative value wrapping to 0xE7
+        assert_eq!(sci_vol(90, -20), 0x1A4D);
+        assert_eq!(sci_vol(100, -100), 0x00FE);
+    }
+
+    #[test]
+    fn balance_clamps_to_100() {
+        assert_eq!(sci_vol(80, i8::MIN), sci_vol(80, -100));
+        assert_eq!(sci_vol(80, i8::MAX), sci_vol(80, 100));
+    }
+
+    #[test]
+    fn sci_bass_packs_nibbles() {
+        // 60 Hz / 15 dB bass, 3 kHz / -12 dB treble
+        assert_eq!(sci_bass(6, 15, 3, 8), Some(0x83F6));
+        assert_eq!(sci_bass(0, 0, 0, 0), Some(0));
+    }
+
+    #[test]
+    fn sci_bass_rejects_wide_nibbles() {
+        assert_eq!(sci_bass(16, 0, 0, 0), None);
+        assert_eq!(sci_bass(0, 0, 0, u8::MAX), None);
+    }
+
+    #[test]
+    fn stream_info_decodes_mp3_headers() {
+        // MPEG1 layer III, 128 kbit/s at 44.1 kHz
+        let info = StreamInfo::from_registers(0x9000, 0xFFFB, 44101);
+        assert_eq!(
+            info,
+            StreamInfo {
+                codec: Codec::Mp3,
+                bitrate_kbps: Some(128),
+                sample_rate: 44100,
+                stereo: true,
+            }
+        );
+        // MPEG2 layer III, 64 kbit/s at 22.05 kHz mono
+        let info = StreamInfo::from_registers(0x8000, 0xFFF3, 22050);
+        assert_eq!(info.bitrate_kbps, Some(64));
+        assert_eq!(info.sample_rate, 22050);
+        assert!(!info.stereo);
+        // Free format
+        let info = StreamInfo::from_registers(0x0000, 0xFFFB, 44101);
+        assert_eq!(info.bitrate_kbps, None);
+    }
+
+    #[test]
+    fn stream_info_decodes_other_formats() {
+        // 12000 bytes per second of ADTS AAC
+        let info = StreamInfo::from_registers(12000, HDAT1_AAC_ADTS, 48001);
+        assert_eq!(info.codec, Codec::Aac);
+        assert_eq!(info.bitrate_kbps, Some(96));
+        assert_eq!(info.sample_rate, 48000);
+        assert_eq!(
+            StreamInfo::from_registers(0, HDAT1_OGG, 0).codec,
+            Codec::Ogg
+        );
+        // Nothing decoded yet
+        let info = StreamInfo::from_registers(0, 0, 0);
+        assert_eq!(info.codec, Codec::Unknown);
+        assert_eq!(info.bitrate_kbps, None);
+    }
+}
@@ -1,33 +1,170 @@
 use anyhow::{bail, Result};
+use core::str;
 // use esp_idf_hal::delay::FreeRtos;
-use esp_idf_svc::nvs::{EspNvsPartition, NvsDefault};
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    hal::peripheral,
-    sntp::{EspSntp, SyncStatus},
-    wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
+    hal::{peripheral, reset},
+    http::{
+        server::{Configuration as HttpServerConfiguration, EspHttpServer},
+        Headers, Method,
+    },
+    io::{Read, Write},
+    ipv4::{
+        ClientConfiguration as IpClientConfiguration, ClientSettings as IpClientSettings,
+        Configuration as IpConfiguration, Ipv4Addr, Mask, Subnet,
+    },
+    mdns::EspMdns,
+    netif::{EspNetif, NetifConfiguration, NetifStack},
+    sys::{esp, esp_wifi_sta_get_ap_info, wifi_ap_record_t},
+    wifi::{
+        AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration, Configuration,
+        EspWifi, WifiDriver,
+    },
 };
-use log::info;
-// use std::{thread::sleep, time::Duration};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const PROVISIONING_SSID: &str = "Rustdio-Setup";
+const PROVISIONING_HTML: &str = include_str!("provisioning.html");
+const MAX_PROVISIONING_PAYLOAD_LEN: usize = 256;
+const CREDENTIALS_NAMESPACE: &str = "wifi";
+const SSID_NVS_KEY: &str = "ssid";
+const PASS_NVS_KEY: &str = "pass";
+const MAX_KNOWN_NETWORKS: usize = 5;
+const HOSTNAME_NVS_KEY: &str = "hostname";
+const DEFAULT_HOSTNAME: &str = "rustdio";
+const MAX_HOSTNAME_LEN: usize = 32;
+const HTTP_PORT: u16 = 80;
+
+/// Longest time to associate and get an IP address, before giving up on the network
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+const MONITOR_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const MONITOR_THREAD_STACK_SIZE: usize = 4 * 1024;
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+/// Consecutive failed reconnections before considering the access point gone
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+/// Once the access point is considered gone, only retry this often
+const LOST_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Static IPv4 configuration of the station interface, instead of DHCP.
+#[derive(Clone, Copy, Debug)]
+pub struct IpConfig {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    /// Prefix length of the subnet, e.g. 24 for 255.255.255.0
+    pub mask: u8,
+    pub dns: Ipv4Addr,
+}
 
+/// Connection state of the station interface, fields are `None` while disconnected.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WifiStatus {
+    pub ssid: Option<String>,
+    /// Signal strength of the access point, in dBm
+    pub rssi: Option<i8>,
+    pub ip: Option<Ipv4Addr>,
+    pub connected: bool,
+}
+
+/// Access point found by `scan_networks`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ScannedNetwork {
+    pub ssid: String,
+    /// Signal strength, in dBm
+    pub rssi: i8,
+    pub channel: u8,
+    /// Authentication method, e.g. `WPA2Personal`, `None` if unknown
+    pub auth: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkStatus {
+    Connected,
+    /// Reconnecting, with the number of failed attempts so far
+    Reconnecting(u32),
+    /// Gave up after `MAX_RECONNECT_ATTEMPTS`, retrying every `LOST_RETRY_INTERVAL`
+    Lost,
+}
+
+static LINK_STATUS: Mutex<LinkStatus> = Mutex::new(LinkStatus::Connected);
+/// Advertises the hostname for as long as it is kept
+static MDNS: Mutex<Option<EspMdns>> = Mutex::new(None);
+
+/// Last link status seen by the reconnect monitor.
+pub fn link_status() -> LinkStatus {
+    *LINK_STATUS.lock().unwrap()
+}
+
+fn set_link_status(status: LinkStatus) {
+    *LINK_STATUS.lock().unwrap() = status;
+}
+
+/// Creates the WiFi driver, with a statically configured station interface if `ip_config`
+/// is given.
+fn new_esp_wifi(
+    modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
+    sysloop: EspSystemEventLoop,
+    nvs_default_partition: EspNvsPartition<NvsDefault>,
+    ip_config: Option<&IpConfig>,
+) -> Result<EspWifi<'static>> {
+    let Some(ip_config) = ip_config else {
+        return Ok(EspWifi::new(modem, sysloop, Some(nvs_default_partition))?);
+    };
+
+    let sta_netif = EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: IpConfiguration::Client(IpClientConfiguration::Fixed(IpClientSettings {
+            ip: ip_config.ip,
+            subnet: Subnet {
+                gateway: ip_config.gateway,
+                mask: Mask(ip_config.mask),
+            },
+            dns: Some(ip_config.dns),
+            secondary_dns: None,
+        })),
+        ..NetifConfiguration::wifi_default_client()
+    })?;
+
+    Ok(EspWifi::wrap_all(
+        WifiDriver::new(modem, sysloop, Some(nvs_default_partition))?,
+        sta_netif,
+        EspNetif::new(NetifStack::Ap)?,
+    )?)
+}
+
+/// Connects to the `ssid` access point.
+///
+/// Falls back to the provisioning access point when not connected within `connect_timeout`.
 pub fn wifi(
     ssid: &str,
     pass: &str,
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
     sysloop: EspSystemEventLoop,
     nvs_default_partition: EspNvsPartition<NvsDefault>,
+    ip_config: Option<IpConfig>,
+    connect_timeout: Duration,
 ) -> Result<Box<EspWifi<'static>>> {
-    let mut auth_method = AuthMethod::WPA2Personal;
     if ssid.is_empty() {
         bail!("Missing WiFi name")
     }
-    if pass.is_empty() {
-        auth_method = AuthMethod::None;
-        info!("Wifi password is empty");
-    }
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs_default_partition))?;
+    let mut esp_wifi = new_esp_wifi(
+        modem,
+        sysloop.clone(),
+        nvs_default_partition.clone(),
+        ip_config.as_ref(),
+    )?;
 
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
+    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop.clone())?;
 
     wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
 
@@ -41,20 +178,74 @@ pub fn wifi(
 
     let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
 
-    let channel = if let Some(ours) = ours {
+    let (channel, scanned_auth) = if let Some(ours) = ours {
         info!(
-            "Found configured access point {} on channel {}",
-            ssid, ours.channel
+            "Found configured access point {} on channel {} ({:?})",
+            ssid, ours.channel, ours.auth_method
         );
-        Some(ours.channel)
+        (Some(ours.channel), ours.auth_method)
     } else {
         info!(
             "Configured access point {} not found during scanning, will go with unknown channel",
             ssid
         );
-        None
+        (None, None)
     };
 
+    let connected = connect_client(
+        &mut wifi,
+        ssid,
+        pass,
+        channel,
+        scanned_auth,
+        ip_config.is_some(),
+        connect_timeout,
+    );
+    if let Err(err) = connected {
+        warn!("Unable to connect to {}: {:?}", ssid, err);
+        wifi.stop()?;
+        drop(wifi);
+        run_provisioning_ap(esp_wifi, sysloop, nvs_default_partition)?;
+        unreachable!("Provisioning reboots once the credentials are saved");
+    }
+    advertise(&load_hostname(nvs_default_partition));
+
+    Ok(Box::new(esp_wifi))
+}
+
+/// Authentication to connect with, given the one the access point advertised when scanned.
+///
+/// Unknown or enterprise methods fall back to WPA2, which most access points accept.
+fn client_auth_method(pass: &str, scanned_auth: Option<AuthMethod>) -> AuthMethod {
+    if pass.is_empty() {
+        info!("Wifi password is empty");
+        return AuthMethod::None;
+    }
+    match scanned_auth {
+        Some(
+            auth @ (AuthMethod::WPA3Personal
+            | AuthMethod::WPA2WPA3Personal
+            | AuthMethod::WPAWPA2Personal
+            | AuthMethod::WPA2Personal
+            | AuthMethod::WPA
+            | AuthMethod::WEP),
+        ) => auth,
+        _ => AuthMethod::WPA2Personal,
+    }
+}
+
+fn connect_client(
+    wifi: &mut BlockingWifi<&mut EspWifi<'static>>,
+    ssid: &str,
+    pass: &str,
+    channel: Option<u8>,
+    scanned_auth: Option<AuthMethod>,
+    static_ip: bool,
+    timeout: Duration,
+) -> Result<()> {
+    let auth_method = client_auth_method(pass, scanned_auth);
+    info!("Using {:?} authentication", auth_method);
+
     wifi.set_configuration(&Configuration::Client(ClientConfiguration {
         ssid: ssid
             .try_into()
@@ -69,25 +260,497 @@ pub fn wifi(
 
     info!("Connecting wifi...");
 
-    wifi.connect()?;
+    // BlockingWifi::connect would wait forever on a misconfigured access point
+    let started = Instant::now();
+    wifi.wifi_mut().connect()?;
+    wifi.wifi_wait_while(
+        || wifi.is_connected().map(|connected| !connected),
+        Some(timeout),
+    )?;
+
+    if static_ip {
+        let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+
+        info!("Wifi Connected: static IP info: {:?}", ip_info);
+
+        return Ok(());
+    }
 
     info!("Waiting for DHCP lease...");
 
-    wifi.wait_netif_up()?;
+    let remaining = timeout.saturating_sub(started.elapsed());
+    wifi.ip_wait_while(|| wifi.is_up().map(|up| !up), Some(remaining))?;
 
     let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
 
     info!("Wifi Connected: DHCP info: {:?}", ip_info);
 
-    // Synchronize NTP
-    println!("Synchronizing with NTP Server");
-    match EspSntp::new_default() {
-        Ok(ntp) => {
-            while ntp.get_sync_status() != SyncStatus::Completed {}
-            info!("NTP Time Sync Completed");
-        }
-        Err(err) => info!("NTP Time Sync not done in a sec:{:#?}", err),
+    Ok(())
+}
+
+/// Connects to the strongest access point among the known networks saved in NVS.
+///
+/// Falls back to the provisioning access point when none of them is visible, or when not
+/// connected within `connect_timeout`.
+pub fn connect_best_known(
+    nvs_default_partition: EspNvsPartition<NvsDefault>,
+    modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
+    sysloop: EspSystemEventLoop,
+    ip_config: Option<IpConfig>,
+    connect_timeout: Duration,
+) -> Result<Box<EspWifi<'static>>> {
+    let known_networks = load_known_networks(nvs_default_partition.clone())?;
+    let mut esp_wifi = new_esp_wifi(
+        modem,
+        sysloop.clone(),
+        nvs_default_partition.clone(),
+        ip_config.as_ref(),
+    )?;
+
+    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop.clone())?;
+
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+
+    info!("Starting wifi...");
+
+    wifi.start()?;
+
+    info!("Scanning for {} known networks...", known_networks.len());
+
+    let best = wifi
+        .scan()?
+        .into_iter()
+        .filter_map(|ap| {
+            known_networks
+                .iter()
+                .find(|(ssid, _)| ap.ssid == ssid.as_str())
+                .map(|network| (ap, network))
+        })
+        .max_by_key(|(ap, _)| ap.signal_strength);
+
+    let Some((ap, (ssid, pass))) = best else {
+        warn!("None of the known networks is visible");
+        wifi.stop()?;
+        drop(wifi);
+        run_provisioning_ap(esp_wifi, sysloop, nvs_default_partition)?;
+        unreachable!("Provisioning reboots once the credentials are saved");
+    };
+
+    info!(
+        "Best known access point is {} on channel {} ({} dBm)",
+        ssid, ap.channel, ap.signal_strength
+    );
+
+    let connected = connect_client(
+        &mut wifi,
+        ssid,
+        pass,
+        Some(ap.channel),
+        ap.auth_method,
+        ip_config.is_some(),
+        connect_timeout,
+    );
+    if let Err(err) = connected {
+        warn!("Unable to connect to {}: {:?}", ssid, err);
+        wifi.stop()?;
+        drop(wifi);
+        run_provisioning_ap(esp_wifi, sysloop, nvs_default_partition)?;
+        unreachable!("Provisioning reboots once the credentials are saved");
     }
+    advertise(&load_hostname(nvs_default_partition));
 
     Ok(Box::new(esp_wifi))
 }
+
+/// Reads the current access point info from the driver.
+pub fn wifi_status(wifi: &EspWifi<'static>) -> WifiStatus {
+    if !wifi.is_connected().unwrap_or(false) {
+        return WifiStatus::default();
+    }
+
+    let mut ap_info = wifi_ap_record_t::default();
+    // Fails with ESP_ERR_WIFI_NOT_CONNECT if the link dropped meanwhile
+    if let Err(err) = esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }) {
+        warn!("Couldn't get access point info: {:?}", err);
+        return WifiStatus::default();
+    }
+    let ssid_len = ap_info
+        .ssid
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(ap_info.ssid.len());
+
+    WifiStatus {
+        ssid: Some(String::from_utf8_lossy(&ap_info.ssid[..ssid_len]).into_owned()),
+        rssi: Some(ap_info.rssi),
+        ip: wifi
+            .sta_netif()
+            .get_ip_info()
+            .ok()
+            .map(|ip_info| ip_info.ip),
+        connected: true,
+    }
+}
+
+/// Scans the visible access points, strongest first, keeping the strongest of those sharing
+/// an SSID. Hidden networks are left out.
+///
+/// The station interface must be started, the scan blocking for a few seconds.
+pub fn scan_networks(wifi: &mut EspWifi<'static>) -> Result<Vec<ScannedNetwork>> {
+    wifi.start_scan(&Default::default(), true)?;
+    let mut access_points = wifi.get_scan_result()?;
+    access_points.sort_by_key(|ap| core::cmp::Reverse(ap.signal_strength));
+
+    let mut networks: Vec<ScannedNetwork> = Vec::new();
+    for ap in access_points {
+        if ap.ssid.is_empty()
+            || networks
+                .iter()
+                .any(|network| ap.ssid == network.ssid.as_str())
+        {
+            continue;
+        }
+        networks.push(ScannedNetwork {
+            ssid: ap.ssid.to_string(),
+            rssi: ap.signal_strength,
+            channel: ap.channel,
+            auth: ap.auth_method.map(|auth| format!("{auth:?}")),
+        });
+    }
+    Ok(networks)
+}
+
+fn reconnect(wifi: &Mutex<EspWifi<'static>>, sysloop: &EspSystemEventLoop) -> Result<()> {
+    let mut wifi = wifi.lock().unwrap();
+    let mut wifi = BlockingWifi::wrap(&mut *wifi, sysloop.clone())?;
+    wifi.connect()?;
+    wifi.wait_netif_up()?;
+    info!(
+        "Wifi reconnected: DHCP info: {:?}",
+        wifi.wifi().sta_netif().get_ip_info()?
+    );
+    Ok(())
+}
+
+/// Watches the connection in a background thread, reconnecting with an exponential backoff
+/// whenever the access point drops.
+///
+/// After `MAX_RECONNECT_ATTEMPTS` consecutive failures the link is reported as `Lost` and
+/// only retried every `LOST_RETRY_INTERVAL`, see `link_status()`.
+pub fn spawn_reconnect_monitor(
+    wifi: Arc<Mutex<EspWifi<'static>>>,
+    sysloop: EspSystemEventLoop,
+) -> Result<JoinHandle<()>> {
+    let handle = thread::Builder::new()
+        .name("wifi-monitor".into())
+        .stack_size(MONITOR_THREAD_STACK_SIZE)
+        .spawn(move || {
+            let mut attempts = 0;
+            let mut delay = RECONNECT_MIN_DELAY;
+            loop {
+                let connected = wifi.lock().unwrap().is_connected().unwrap_or(false);
+                if connected {
+                    if attempts > 0 {
+                        info!("Wifi link is back after {attempts} attempts");
+                    }
+                    attempts = 0;
+                    delay = RECONNECT_MIN_DELAY;
+                    set_link_status(LinkStatus::Connected);
+                    sleep(MONITOR_CHECK_INTERVAL);
+                    continue;
+                }
+
+                attempts += 1;
+                warn!("Wifi disconnected, reconnection attempt {attempts}");
+                match reconnect(&wifi, &sysloop) {
+                    Ok(()) => continue,
+                    Err(err) => warn!("Wifi reconnection attempt {attempts} failed: {err:?}"),
+                }
+
+                if attempts >= MAX_RECONNECT_ATTEMPTS {
+                    if attempts == MAX_RECONNECT_ATTEMPTS {
+                        error!(
+                            "Wifi access point seems gone, retrying every {LOST_RETRY_INTERVAL:?}"
+                        );
+                    }
+                    set_link_status(LinkStatus::Lost);
+                    sleep(LOST_RETRY_INTERVAL);
+                } else {
+                    set_link_status(LinkStatus::Reconnecting(attempts));
+                    sleep(delay);
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                }
+            }
+        })?;
+    Ok(handle)
+}
+
+/// NVS keys of the known network at `index`, the most recent one using the plain
+/// `ssid`/`pass` keys.
+fn network_nvs_keys(index: usize) -> (String, String) {
+    if index == 0 {
+        (SSID_NVS_KEY.to_string(), PASS_NVS_KEY.to_string())
+    } else {
+        (
+            format!("{SSID_NVS_KEY}{index}"),
+            format!("{PASS_NVS_KEY}{index}"),
+        )
+    }
+}
+
+/// Known networks saved in the "wifi" NVS namespace, most recently saved first.
+pub fn load_known_networks(
+    nvs_default_partition: EspNvsPartition<NvsDefault>,
+) -> Result<Vec<(String, String)>> {
+    let nvs = EspNvs::new(nvs_default_partition, CREDENTIALS_NAMESPACE, true)?;
+    // SSIDs are at most 32 bytes and passwords 64, plus the NUL terminator
+    let mut ssid_buf = [0; 33];
+    let mut pass_buf = [0; 65];
+    let mut networks = Vec::new();
+    for index in 0..MAX_KNOWN_NETWORKS {
+        let (ssid_key, pass_key) = network_nvs_keys(index);
+        let Some(ssid) = nvs.get_str(&ssid_key, &mut ssid_buf)? else {
+            break;
+        };
+        let pass = nvs.get_str(&pass_key, &mut pass_buf)?.unwrap_or("");
+        networks.push((ssid.to_string(), pass.to_string()));
+    }
+    Ok(networks)
+}
+
+/// Saves the WiFi credentials in the "wifi" NVS namespace as the most recent known network.
+///
+/// Saving an already known SSID updates its password, and the oldest network is forgotten
+/// once `MAX_KNOWN_NETWORKS` are known.
+pub fn save_credentials(
+    nvs_default_partition: EspNvsPartition<NvsDefault>,
+    ssid: &str,
+    pass: &str,
+) -> Result<()> {
+    let mut networks = load_known_networks(nvs_default_partition.clone())?;
+    networks.retain(|(known_ssid, _)| known_ssid != ssid);
+    networks.insert(0, (ssid.to_string(), pass.to_string()));
+    networks.truncate(MAX_KNOWN_NETWORKS);
+
+    let mut nvs = EspNvs::new(nvs_default_partition, CREDENTIALS_NAMESPACE, true)?;
+    for (index, (ssid, pass)) in networks.iter().enumerate() {
+        let (ssid_key, pass_key) = network_nvs_keys(index);
+        nvs.set_str(&ssid_key, ssid)?;
+        nvs.set_str(&pass_key, pass)?;
+    }
+    info!("Saved credentials for WiFi {}", ssid);
+    Ok(())
+}
+
+/// Most recently saved WiFi credentials, if any.
+pub fn load_credentials(
+    nvs_default_partition: EspNvsPartition<NvsDefault>,
+) -> Result<Option<(String, String)>> {
+    Ok(load_known_networks(nvs_default_partition)?
+        .into_iter()
+        .next())
+}
+
+/// Hostname advertised over mDNS, `rustdio` unless another one was saved in NVS.
+pub fn load_hostname(nvs_default_partition: EspNvsPartition<NvsDefault>) -> String {
+    let mut buf = [0; MAX_HOSTNAME_LEN + 1];
+    let hostname =
+        EspNvs::new(nvs_default_partition, CREDENTIALS_NAMESPACE, true).and_then(|nvs| {
+            nvs.get_str(HOSTNAME_NVS_KEY, &mut buf)
+                .map(|hostname| hostname.map(str::to_string))
+        });
+    match hostname {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => DEFAULT_HOSTNAME.to_string(),
+        Err(err) => {
+            warn!("Couldn't get key {} because {:?}", HOSTNAME_NVS_KEY, err);
+            DEFAULT_HOSTNAME.to_string()
+        }
+    }
+}
+
+/// Saves the hostname in the "wifi" NVS namespace, and advertises it right away.
+///
+/// Only letters, digits and hyphens are allowed, up to `MAX_HOSTNAME_LEN` characters.
+pub fn save_hostname(
+    nvs_default_partition: EspNvsPartition<NvsDefault>,
+    hostname: &str,
+) -> Result<()> {
+    let valid = !hostname.is_empty()
+        && hostname.len() <= MAX_HOSTNAME_LEN
+        && hostname
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+        && !hostname.starts_with('-');
+    if !valid {
+        bail!("Invalid hostname {:?}", hostname);
+    }
+    let mut nvs = EspNvs::new(nvs_default_partition, CREDENTIALS_NAMESPACE, true)?;
+    nvs.set_str(HOSTNAME_NVS_KEY, hostname)?;
+    advertise(hostname);
+    Ok(())
+}
+
+/// Advertises `hostname.local` and the HTTP server over mDNS. Failing only logs, the radio
+/// staying reachable by its IP.
+fn advertise(hostname: &str) {
+    let mut mdns = MDNS.lock().unwrap();
+    // Only one instance may exist at a time
+    *mdns = None;
+    let started = EspMdns::take().and_then(|mut new_mdns| {
+        new_mdns.set_hostname(hostname)?;
+        new_mdns.set_instance_name("Rustdio")?;
+        new_mdns.add_service(None, "_http", "_tcp", HTTP_PORT, &[])?;
+        Ok(new_mdns)
+    });
+    match started {
+        Ok(new_mdns) => {
+            info!("Advertised as http://{}.local", hostname);
+            *mdns = Some(new_mdns);
+        }
+        Err(err) => warn!("Unable to advertise {}.local: {:?}", hostname, err),
+    }
+}
+
+/// Decodes an `application/x-www-form-urlencoded` value.
+fn url_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [input.next().unwrap_or(b'0'), input.next().unwrap_or(b'0')];
+                let decoded = str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                bytes.push(decoded.unwrap_or(b'?'));
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn form_value(body: &str, name: &str) -> Option<String> {
+    body.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then(|| url_decode(value))
+    })
+}
+
+/// Brings up an open access point named `PROVISIONING_SSID` serving a form to enter the
+/// WiFi credentials, which are saved to NVS before rebooting into client mode.
+///
+/// Only returns if the access point or the web server could not be started.
+pub fn start_provisioning_ap(
+    modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
+    sysloop: EspSystemEventLoop,
+    nvs_default_partition: EspNvsPartition<NvsDefault>,
+) -> Result<()> {
+    let esp_wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs_default_partition.clone()))?;
+    run_provisioning_ap(esp_wifi, sysloop, nvs_default_partition)
+}
+
+fn run_provisioning_ap(
+    mut esp_wifi: EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    nvs_default_partition: EspNvsPartition<NvsDefault>,
+) -> Result<()> {
+    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
+
+    // The station interface is only there to scan the networks to pick from
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        AccessPointConfiguration {
+            ssid: PROVISIONING_SSID
+                .try_into()
+                .expect("Could not parse the provisioning SSID into WiFi config"),
+            auth_method: AuthMethod::None,
+            channel: 1,
+            ..Default::default()
+        },
+    ))?;
+
+    info!("Starting provisioning access point...");
+
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+
+    // Scanning later would take the access point off its channel while the page is open
+    let networks = match scan_networks(wifi.wifi_mut()) {
+        Ok(networks) => Some(networks),
+        Err(err) => {
+            warn!("WiFi scan failed: {:?}", err);
+            None
+        }
+    };
+
+    let ip_info = wifi.wifi().ap_netif().get_ip_info()?;
+
+    info!(
+        "Connect to {} and open http://{} to configure the WiFi",
+        PROVISIONING_SSID, ip_info.ip
+    );
+
+    let provisioned = Arc::new(AtomicBool::new(false));
+    let mut server = EspHttpServer::new(&HttpServerConfiguration::default())?;
+
+    server.fn_handler::<anyhow::Error, _>("/", Method::Get, |req| {
+        req.into_ok_response()?
+            .write_all(PROVISIONING_HTML.as_bytes())?;
+        Ok(())
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/wifi-scan", Method::Get, move |req| {
+        match &networks {
+            Some(networks) => req
+                .into_response(200, None, &[("Content-Type", "application/json")])?
+                .write_all(&serde_json::to_vec(networks)?)?,
+            None => req
+                .into_status_response(503)?
+                .write_all("WiFi scan failed".as_bytes())?,
+        }
+        Ok(())
+    })?;
+
+    let provisioned_clone = provisioned.clone();
+    server.fn_handler::<anyhow::Error, _>("/provision", Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+
+        if len > MAX_PROVISIONING_PAYLOAD_LEN {
+            req.into_status_response(413)?
+                .write_all("Request too big".as_bytes())?;
+            return Ok(());
+        }
+
+        let mut buf = vec![0; len];
+        req.read_exact(&mut buf)?;
+        let body = String::from_utf8_lossy(&buf);
+
+        let ssid = form_value(&body, "ssid").unwrap_or_default();
+        let pass = form_value(&body, "password").unwrap_or_default();
+        if ssid.is_empty() || ssid.len() > 32 || pass.len() > 64 {
+            req.into_status_response(400)?
+                .write_all("Invalid WiFi name or password".as_bytes())?;
+            return Ok(());
+        }
+
+        save_credentials(nvs_default_partition.clone(), &ssid, &pass)?;
+
+        req.into_ok_response()?
+            .write_all(format!("Saved, rebooting to connect to {ssid}...").as_bytes())?;
+        provisioned_clone.store(true, Ordering::Relaxed);
+        Ok(())
+    })?;
+
+    loop {
+        if provisioned.load(Ordering::Relaxed) {
+            // Let the response reach the browser before rebooting
+            sleep(Duration::from_secs(1));
+            info!("Rebooting into client mode");
+            reset::restart();
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,81 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use esp_idf_svc::{
+    nvs::{EspNvs, NvsDefault},
+    sys::EspError,
+};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+const AUTH_NVS_KEY: &str = "auth_hash";
+pub const AUTH_REALM_HEADER: &str = "Basic realm=\"rustdio\", charset=\"UTF-8\"";
+
+type CredentialsHash = [u8; 32];
+
+/// HTTP Basic Auth credentials, only the SHA-256 of `username:password` being stored.
+pub struct Credentials {
+    hash: Mutex<Option<CredentialsHash>>,
+}
+
+impl Credentials {
+    /// Without stored credentials, every request is authorized.
+    pub fn load_from_nvs(nvs: &EspNvs<NvsDefault>) -> Self {
+        let mut buf = [0; 32];
+        let hash = match nvs.get_raw(AUTH_NVS_KEY, &mut buf) {
+            Ok(Some(data)) => data.try_into().ok(),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Couldn't get key {} because {:?}", AUTH_NVS_KEY, e);
+                None
+            }
+        };
+        if hash.is_some() {
+            info!("Control endpoints are protected by Basic Auth");
+        }
+        Credentials {
+            hash: Mutex::new(hash),
+        }
+    }
+
+    /// Replaces the stored credentials, an empty `username` disabling the authentication.
+    pub fn save_to_nvs(
+        &self,
+        nvs: &mut EspNvs<NvsDefault>,
+        username: &str,
+        password: &str,
+    ) -> Result<(), EspError> {
+        let mut hash = self.hash.lock().unwrap();
+        if username.is_empty() {
+            nvs.remove(AUTH_NVS_KEY)?;
+            *hash = None;
+        } else {
+            let new_hash = hash_credentials(format!("{username}:{password}").as_bytes());
+            nvs.set_raw(AUTH_NVS_KEY, &new_hash)?;
+            *hash = Some(new_hash);
+        }
+        Ok(())
+    }
+
+    /// Checks the value of an `Authorization: Basic <base64>` header.
+    pub fn is_authorized(&self, header: Option<&str>) -> bool {
+        let Some(expected) = *self.hash.lock().unwrap() else {
+            return true;
+        };
+        let Some(decoded) = header
+            .and_then(|header| header.strip_prefix("Basic "))
+            .and_then(|token| STANDARD.decode(token.trim()).ok())
+        else {
+            return false;
+        };
+        constant_time_eq(&hash_credentials(&decoded), &expected)
+    }
+}
+
+fn hash_credentials(credentials: &[u8]) -> CredentialsHash {
+    Sha256::digest(credentials).into()
+}
+
+/// Compares every byte, so the time taken doesn't tell how much of the secret matched.
+fn constant_time_eq(a: &CredentialsHash, b: &CredentialsHash) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
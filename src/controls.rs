@@ -0,0 +1,200 @@
+use esp_idf_svc::{
+    hal::{
+        delay::{TickType, BLOCK},
+        gpio::{AnyIOPin, InterruptType, PinDriver, Pull},
+        task::notification::Notification,
+    },
+    sys::EspError,
+};
+use log::{info, warn};
+use std::{
+    num::NonZeroU32,
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Time for the contacts to settle after the first edge
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(30);
+/// Presses closer than this to the previous one are ignored, held buttons don't repeat either
+const REPEAT_GUARD: Duration = Duration::from_millis(250);
+const CONTROLS_THREAD_STACK_SIZE: usize = 4 * 1024;
+/// Quadrature transitions per detent of the usual encoders
+const TRANSITIONS_PER_DETENT: i8 = 4;
+/// Detents closer than this count double, and triple under `FAST_TURN`
+const QUICK_TURN: Duration = Duration::from_millis(120);
+const FAST_TURN: Duration = Duration::from_millis(40);
+/// Time without turning after which the knob is considered settled
+const KNOB_SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+/// Action requested from the front panel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    PlayPause,
+    Next,
+    Previous,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    /// Detents turned clockwise (or counterclockwise if negative), acceleration included
+    KnobTurned(i8),
+    /// The knob stopped turning for `KNOB_SETTLE_DELAY`
+    KnobSettled,
+}
+
+/// Push button wired between `pin` and the ground, the internal pull-up keeping it high.
+pub struct Button {
+    pub pin: AnyIOPin,
+    pub control: Control,
+}
+
+/// Quadrature rotary encoder, its push button being wired as a `Button`.
+pub struct RotaryEncoder {
+    pub a: AnyIOPin,
+    pub b: AnyIOPin,
+}
+
+/// Calls `on_control` from a dedicated thread for each debounced button press.
+pub fn spawn_buttons<F>(buttons: Vec<Button>, mut on_control: F) -> std::io::Result<JoinHandle<()>>
+where
+    F: FnMut(Control) + Send + 'static,
+{
+    thread::Builder::new()
+        .name("controls".into())
+        .stack_size(CONTROLS_THREAD_STACK_SIZE)
+        .spawn(move || {
+            if let Err(err) = watch_buttons(buttons, &mut on_control) {
+                warn!("Buttons disabled: {:?}", err);
+            }
+        })
+}
+
+fn watch_buttons(
+    buttons: Vec<Button>,
+    on_control: &mut impl FnMut(Control),
+) -> Result<(), EspError> {
+    // Wakes up the thread that created it, each button setting its own bit
+    let notification = Notification::new();
+    let mut drivers = Vec::with_capacity(buttons.len());
+    for (index, button) in buttons.into_iter().enumerate() {
+        let mut driver = PinDriver::input(button.pin)?;
+        driver.set_pull(Pull::Up)?;
+        driver.set_interrupt_type(InterruptType::NegEdge)?;
+        let notifier = notification.notifier();
+        let bit = NonZeroU32::new(1 << index).unwrap();
+        unsafe {
+            driver.subscribe(move || {
+                notifier.notify_and_yield(bit);
+            })?;
+        }
+        driver.enable_interrupt()?;
+        drivers.push((driver, button.control, None::<Instant>));
+    }
+
+    loop {
+        let Some(bits) = notification.wait(BLOCK) else {
+            continue;
+        };
+        sleep(DEBOUNCE_DELAY);
+        for (index, (driver, control, last_press)) in drivers.iter_mut().enumerate() {
+            if bits.get() & (1 << index) == 0 {
+                continue;
+            }
+            // Still pressed once the contacts settled, and not a repeat of the last press
+            let repeated = last_press.is_some_and(|last_press| last_press.elapsed() < REPEAT_GUARD);
+            if driver.is_low() && !repeated {
+                *last_press = Some(Instant::now());
+                info!("Button {:?} pressed", control);
+                on_control(*control);
+            }
+            // The interrupt is disabled after each edge
+            driver.enable_interrupt()?;
+        }
+    }
+}
+
+/// Calls `on_control` from a dedicated thread as the knob turns, then once it settled.
+pub fn spawn_encoder<F>(
+    encoder: RotaryEncoder,
+    mut on_control: F,
+) -> std::io::Result<JoinHandle<()>>
+where
+    F: FnMut(Control) + Send + 'static,
+{
+    thread::Builder::new()
+        .name("encoder".into())
+        .stack_size(CONTROLS_THREAD_STACK_SIZE)
+        .spawn(move || {
+            if let Err(err) = watch_encoder(encoder, &mut on_control) {
+                warn!("Rotary encoder disabled: {:?}", err);
+            }
+        })
+}
+
+fn watch_encoder(
+    encoder: RotaryEncoder,
+    on_control: &mut impl FnMut(Control),
+) -> Result<(), EspError> {
+    let notification = Notification::new();
+    let mut pins = [PinDriver::input(encoder.a)?, PinDriver::input(encoder.b)?];
+    for pin in pins.iter_mut() {
+        pin.set_pull(Pull::Up)?;
+        pin.set_interrupt_type(InterruptType::AnyEdge)?;
+        let notifier = notification.notifier();
+        unsafe {
+            pin.subscribe(move || {
+                notifier.notify_and_yield(NonZeroU32::MIN);
+            })?;
+        }
+        pin.enable_interrupt()?;
+    }
+
+    let read_state = |pins: &[PinDriver<'_, AnyIOPin, _>; 2]| {
+        ((pins[0].is_high() as u8) << 1) | pins[1].is_high() as u8
+    };
+    let mut state = read_state(&pins);
+    let mut transitions = 0;
+    let mut last_detent: Option<Instant> = None;
+    loop {
+        // Only wakes up to report the knob settled once it turned
+        let timeout = if last_detent.is_some() {
+            TickType::from(KNOB_SETTLE_DELAY).ticks()
+        } else {
+            BLOCK
+        };
+        if notification.wait(timeout).is_none() {
+            if last_detent.take().is_some() {
+                on_control(Control::KnobSettled);
+            }
+            continue;
+        }
+        let new_state = read_state(&pins);
+        // Bounces go back and forth between two states, cancelling each other
+        transitions += quadrature_step(state, new_state);
+        state = new_state;
+        if transitions.abs() >= TRANSITIONS_PER_DETENT {
+            let direction = transitions.signum();
+            transitions = 0;
+            let elapsed = last_detent.map(|last_detent| last_detent.elapsed());
+            let acceleration = match elapsed {
+                Some(elapsed) if elapsed < FAST_TURN => 3,
+                Some(elapsed) if elapsed < QUICK_TURN => 2,
+                _ => 1,
+            };
+            last_detent = Some(Instant::now());
+            on_control(Control::KnobTurned(direction * acceleration));
+        }
+        for pin in pins.iter_mut() {
+            pin.enable_interrupt()?;
+        }
+    }
+}
+
+/// +1 or -1 for a valid Gray code transition between the `AB` states, 0 otherwise.
+fn quadrature_step(previous: u8, current: u8) -> i8 {
+    match (previous, current) {
+        (0b00, 0b01) | (0b01, 0b11) | (0b11, 0b10) | (0b10, 0b00) => 1,
+        (0b00, 0b10) | (0b10, 0b11) | (0b11, 0b01) | (0b01, 0b00) => -1,
+        // No change, or a skipped state whose direction can't be told
+        _ => 0,
+    }
+}
@@ -0,0 +1,110 @@
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use esp_idf_svc::hal::i2c::I2cDriver;
+use log::{info, warn};
+use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306};
+use std::{
+    thread::{self, sleep, JoinHandle},
+    time::Duration,
+};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const DISPLAY_THREAD_STACK_SIZE: usize = 4 * 1024;
+/// Characters of `FONT_6X10` fitting on the 128 pixels of a line
+const LINE_LEN: usize = 21;
+const LINE_HEIGHT: i32 = 12;
+const VOLUME_BAR_WIDTH: u32 = 100;
+const VOLUME_BAR_HEIGHT: u32 = 8;
+
+/// What is shown on the display, read again on every refresh.
+pub struct NowPlaying {
+    pub station_name: Option<String>,
+    /// ICY title of the webradio
+    pub title: Option<String>,
+    pub volume: u8,
+    /// Local time, already formatted
+    pub clock: String,
+}
+
+/// Draws what `now_playing` returns on a 128x64 SSD1306, every `REFRESH_INTERVAL`.
+///
+/// The display is given up on the first I2C error, the radio playing on without it.
+pub fn spawn_display<F>(
+    i2c: I2cDriver<'static>,
+    mut now_playing: F,
+) -> std::io::Result<JoinHandle<()>>
+where
+    F: FnMut() -> NowPlaying + Send + 'static,
+{
+    thread::Builder::new()
+        .name("display".into())
+        .stack_size(DISPLAY_THREAD_STACK_SIZE)
+        .spawn(move || {
+            let interface = I2CDisplayInterface::new(i2c);
+            let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
+                .into_buffered_graphics_mode();
+            if let Err(err) = display.init() {
+                warn!("Display disabled, unable to initialize it: {:?}", err);
+                return;
+            }
+            info!("Display initialized");
+            loop {
+                let drawn = draw(&mut display, &now_playing()).and_then(|()| display.flush());
+                if let Err(err) = drawn {
+                    warn!("Display disabled: {:?}", err);
+                    return;
+                }
+                sleep(REFRESH_INTERVAL);
+            }
+        })
+}
+
+/// Draws in the buffer of the display, which is sent by `flush`.
+fn draw<D>(display: &mut D, now_playing: &NowPlaying) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    display.clear(BinaryColor::Off)?;
+    let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let lines = [
+        now_playing.clock.as_str(),
+        now_playing.station_name.as_deref().unwrap_or("-"),
+        now_playing.title.as_deref().unwrap_or(""),
+    ];
+    for (index, line) in lines.iter().enumerate() {
+        let line: String = line.chars().take(LINE_LEN).collect();
+        Text::with_baseline(
+            &line,
+            Point::new(0, index as i32 * LINE_HEIGHT),
+            style,
+            Baseline::Top,
+        )
+        .draw(display)?;
+    }
+
+    let top = lines.len() as i32 * LINE_HEIGHT + 4;
+    Rectangle::new(
+        Point::new(0, top),
+        Size::new(VOLUME_BAR_WIDTH + 2, VOLUME_BAR_HEIGHT),
+    )
+    .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+    .draw(display)?;
+    let filled = VOLUME_BAR_WIDTH * now_playing.volume.min(100) as u32 / 100;
+    Rectangle::new(Point::new(1, top), Size::new(filled, VOLUME_BAR_HEIGHT))
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(display)?;
+    let volume = format!("{}", now_playing.volume);
+    Text::with_baseline(
+        &volume,
+        Point::new(VOLUME_BAR_WIDTH as i32 + 6, top - 1),
+        style,
+        Baseline::Top,
+    )
+    .draw(display)?;
+    Ok(())
+}
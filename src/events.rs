@@ -0,0 +1,207 @@
+use crate::vs1053::PlaybackState;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// The HTTP server handles one request at a time, so the streams get their own listener.
+pub const EVENTS_PORT: u16 = 8081;
+const MAX_CLIENTS: usize = 4;
+/// Events a slow client can lag behind before missing some.
+const SUBSCRIBER_QUEUE_LEN: usize = 8;
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const LISTENER_THREAD_STACK_SIZE: usize = 3 * 1024;
+const CLIENT_THREAD_STACK_SIZE: usize = 4 * 1024;
+
+/// Update pushed to the browsers listening to `/events`.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Station {
+        id: String,
+        name: Option<String>,
+        is_webradio: bool,
+    },
+    /// ICY title of the webradio, `None` when it has none or stopped
+    Title(Option<String>),
+    Volume(u8),
+    /// Whether the webradio buffer is refilling
+    Buffering(bool),
+    Playback(PlaybackState),
+}
+
+impl Event {
+    fn name_and_data(&self) -> (&'static str, Value) {
+        match self {
+            Event::Station {
+                id,
+                name,
+                is_webradio,
+            } => (
+                "station",
+                json!({ "station_id": id, "station_name": name, "is_webradio": is_webradio }),
+            ),
+            Event::Title(title) => ("title", json!({ "title": title })),
+            Event::Volume(volume) => ("volume", json!({ "volume": volume })),
+            Event::Buffering(buffering) => ("buffering", json!({ "buffering": buffering })),
+            Event::Playback(playback) => ("playback", json!({ "playback": playback })),
+        }
+    }
+
+    /// Formats the event as a `text/event-stream` message.
+    fn to_message(&self) -> String {
+        let (name, data) = self.name_and_data();
+        format!("event: {name}\ndata: {data}\n\n")
+    }
+
+    /// Formats the event as a JSON object, its name in `event`, for the WebSocket clients.
+    pub fn to_json(&self) -> String {
+        let (name, data) = self.name_and_data();
+        json!({ "event": name, "data": data }).to_string()
+    }
+}
+
+/// Broadcasts events to every connected browser.
+pub struct EventBus {
+    subscribers: Mutex<Vec<SyncSender<Event>>>,
+}
+
+impl EventBus {
+    const fn new() -> Self {
+        EventBus {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Never blocks, a subscriber whose queue is full misses the event.
+    pub fn publish(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            match subscriber.try_send(event.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                // The client disconnected and dropped its receiver
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Events published from now on, until the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_QUEUE_LEN);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+pub static EVENTS: EventBus = EventBus::new();
+/// Each client holds a thread, so their number is capped.
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the `MAX_CLIENTS` places, given back when dropped.
+struct ClientSlot;
+
+impl ClientSlot {
+    fn reserve() -> Option<Self> {
+        if CLIENTS.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+            CLIENTS.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(ClientSlot)
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        CLIENTS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves `GET /events` as Server-Sent Events on `EVENTS_PORT`.
+pub fn spawn_event_server(cors_origin: &'static str) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("0.0.0.0", EVENTS_PORT))?;
+    info!("Events served on port {}", EVENTS_PORT);
+    thread::Builder::new()
+        .name("events".into())
+        .stack_size(LISTENER_THREAD_STACK_SIZE)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Events connection failed: {:?}", err);
+                        continue;
+                    }
+                };
+                // Refused before spawning, a thread per connection would exhaust the heap
+                let Some(slot) = ClientSlot::reserve() else {
+                    if let Err(err) = stream
+                        .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n")
+                    {
+                        info!("Unable to refuse events client: {:?}", err);
+                    }
+                    continue;
+                };
+                // Dropping the closure when the spawn fails gives the slot back
+                let spawned = thread::Builder::new()
+                    .name("events-client".into())
+                    .stack_size(CLIENT_THREAD_STACK_SIZE)
+                    .spawn(move || {
+                        let _slot = slot;
+                        if let Err(err) = serve_client(stream, cors_origin) {
+                            info!("Events client disconnected: {:?}", err);
+                        }
+                    });
+                if let Err(err) = spawned {
+                    warn!("Unable to serve events client: {:?}", err);
+                }
+            }
+        })
+}
+
+fn serve_client(mut stream: TcpStream, cors_origin: &str) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skips the headers, none of them matters here
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    if !request_line.starts_with("GET ") || path.split('?').next() != Some("/events") {
+        return stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+    }
+    stream_events(&mut stream, cors_origin)
+}
+
+fn stream_events(stream: &mut TcpStream, cors_origin: &str) -> io::Result<()> {
+    let events = EVENTS.subscribe();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/event-stream\r\n\
+         Cache-Control: no-cache\r\n\
+         Connection: keep-alive\r\n\
+         Access-Control-Allow-Origin: {cors_origin}\r\n\r\n"
+    )?;
+
+    // Returning drops the receiver, which unsubscribes on the next publish
+    loop {
+        match events.recv_timeout(KEEP_ALIVE_INTERVAL) {
+            Ok(event) => stream.write_all(event.to_message().as_bytes())?,
+            // A comment, ignored by the browser but failing once the client is gone
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b": keep-alive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use log::warn;
+use rgb_led::{RGB8, WS2812RMT};
+use std::{
+    sync::{Arc, Mutex},
+    thread::{self, sleep, JoinHandle},
+    time::Duration,
+};
+
+const BLINK_INTERVAL: Duration = Duration::from_millis(500);
+const ANIMATION_THREAD_STACK_SIZE: usize = 3 * 1024;
+
+/// State of the device, as shown by the RGB LED.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LedStatus {
+    /// Solid red
+    Booting,
+    /// Solid blue, also while waiting for the provisioning portal
+    WifiConnecting,
+    /// Solid cyan
+    WifiConnected,
+    /// Solid green
+    Playing,
+    /// Solid amber, while a webradio (re)connects
+    Buffering,
+    /// Blinking red
+    Error,
+    /// Dim white
+    Idle,
+}
+
+impl LedStatus {
+    fn color(self) -> RGB8 {
+        match self {
+            LedStatus::Booting => RGB8::new(50, 0, 0),
+            LedStatus::WifiConnecting => RGB8::new(0, 0, 50),
+            LedStatus::WifiConnected => RGB8::new(0, 30, 30),
+            LedStatus::Playing => RGB8::new(0, 50, 0),
+            LedStatus::Buffering => RGB8::new(50, 20, 0),
+            LedStatus::Error => RGB8::new(50, 0, 0),
+            LedStatus::Idle => RGB8::new(5, 5, 5),
+        }
+    }
+}
+
+/// Held while drawing, so the animation never overwrites a newer status.
+static STATUS: Mutex<LedStatus> = Mutex::new(LedStatus::Booting);
+
+pub fn set_status(led: &Mutex<WS2812RMT<'static>>, status: LedStatus) {
+    let mut current = STATUS.lock().unwrap();
+    *current = status;
+    if let Err(err) = led.lock().unwrap().set_pixel(status.color()) {
+        warn!("Unable to show LED status {:?}: {:?}", status, err);
+    }
+}
+
+/// Animates the statuses that are not a solid color, i.e. blinks on `LedStatus::Error`.
+pub fn spawn_animation(led: Arc<Mutex<WS2812RMT<'static>>>) -> std::io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("led".into())
+        .stack_size(ANIMATION_THREAD_STACK_SIZE)
+        .spawn(move || {
+            let mut lit = true;
+            loop {
+                sleep(BLINK_INTERVAL);
+                let status = STATUS.lock().unwrap();
+                if *status != LedStatus::Error {
+                    lit = true;
+                    continue;
+                }
+                lit = !lit;
+                let color = if lit { status.color() } else { RGB8::default() };
+                let _ = led.lock().unwrap().set_pixel(color);
+            }
+        })
+}
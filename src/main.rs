@@ -1,45 +1,95 @@
 use anyhow::Result;
-use chrono::{DateTime, Utc};
+use auth::{Credentials, AUTH_REALM_HEADER};
+use chrono::{DateTime, FixedOffset, Local};
+use controls::{spawn_buttons, spawn_encoder, Button, Control, RotaryEncoder};
 use core::str;
 use embedded_svc::{
-    http::{Headers, Method},
+    http::{
+        server::{Connection, Request, Response},
+        Headers, Method,
+    },
     io::Write,
 };
 use esp_idf_hal::{
-    gpio::AnyOutputPin,
+    gpio::{AnyIOPin, AnyOutputPin, Gpio4, Gpio47, Gpio5, IOPin, Input, Output, Pin, PinDriver},
     io::Read,
-    spi::{
-        config::{Config as SpiConfig, DriverConfig},
-        Dma, SpiDeviceDriver, SpiDriver,
-    },
+    spi::{config::DriverConfig, Dma, SpiDriver},
 };
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{
+        delay::TickType,
         i2c::{I2cConfig, I2cDriver},
         io::EspIOError,
         prelude::*,
+        reset::restart,
     },
-    http::server::{Configuration, EspHttpServer},
+    http::server::{Configuration, EspHttpConnection, EspHttpServer},
+    ipv4::Ipv4Addr,
     nvs::*,
+    ota::EspOta,
+    sys::{
+        esp_get_free_heap_size, esp_get_minimum_free_heap_size, esp_timer_get_time, gpio_set_level,
+        heap_caps_get_largest_free_block, uxTaskGetNumberOfTasks, MALLOC_CAP_8BIT,
+    },
+};
+use events::{spawn_event_server, Event, EVENTS};
+use led::{set_status, spawn_animation, LedStatus};
+use log::{debug, error, info, warn};
+use mqtt::{spawn_mqtt, MqttCommand, MqttConfig, MqttState};
+use vs1053::{
+    fade, ChipVersion, Codec, DSPError, PlaybackState, StreamInfo, Tone, MEMORY_TEST_OK, VS1053,
 };
-use log::{info, warn};
-use vs1053::VS1053;
 mod ntp;
+use ntp::Ntp;
 use postcard::{from_bytes, to_vec};
-use radios::Station;
-use rgb_led::{RGB8, WS2812RMT};
-use serde::{Deserialize, Serialize};
+use radios::{Station, StationData, StationError, StationStore, FM_BAND_MAX_MHZ, FM_BAND_MIN_MHZ};
+use rgb_led::WS2812RMT;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use spi::SpiBusDevice;
 use std::{
-    sync::{Arc, Mutex},
-    thread::sleep,
-    time::{Duration, SystemTime},
+    fs::{self, File},
+    io::Write as _,
+    ops::RangeInclusive,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, Mutex, MutexGuard, TryLockError,
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+use storage::{mount_storage, JINGLE_PATH, JINGLE_UPLOAD_PATH};
+use stream::{
+    play_chunk_size, set_play_chunk_size, StreamSink, StreamThread, PLAY_CHUNK_SIZE_RANGE,
 };
 use tea5767::defs::{BandLimits, SoundMode, TEA5767};
 mod vs1053;
-use wifi::wifi;
+use ring_log::{LogKind, RING_LOG};
+use watchdog::{spawn_watchdog, stall_count};
+use websocket::{register_ws_handler, WsCommand};
+use wifi::{
+    connect_best_known, load_credentials, load_hostname, save_hostname, scan_networks,
+    spawn_reconnect_monitor, start_provisioning_ap, wifi, wifi_status, IpConfig, WifiStatus,
+    DEFAULT_CONNECT_TIMEOUT,
+};
 
+mod auth;
+mod controls;
+#[cfg(feature = "display")]
+mod display;
+mod events;
+mod led;
+mod mqtt;
 mod radios;
+mod ring_log;
+mod spi;
+mod storage;
+mod stream;
+mod vs1053_patches;
+mod watchdog;
+mod websocket;
 
 #[derive(Debug)]
 #[toml_cfg::toml_config]
@@ -48,20 +98,381 @@ pub struct Config {
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
+    /// Static IPv4 address, DHCP is used when empty
+    #[default("")]
+    static_ip: &'static str,
+    #[default("")]
+    static_gateway: &'static str,
+    #[default(24)]
+    static_mask: u8,
+    #[default("")]
+    static_dns: &'static str,
+    /// Offset of the local time from UTC, in minutes
+    #[default(0)]
+    utc_offset_minutes: i32,
+    /// Comma separated NTP servers, tried before the public pools
+    #[default("")]
+    ntp_servers: &'static str,
+    /// I2C clock of the TEA5767 bus, some modules only working at 100 kHz
+    #[default(400_000)]
+    i2c_baudrate_hz: u32,
+    /// High speed SPI clock of the VS1053, kept at 4 MHz if the board doesn't keep up
+    #[default(4_000_000)]
+    spi_speed_hz: u32,
+    /// Longest wait for the VS1053 DREQ line, to raise on boards that are slow to load the patches
+    #[default(2000)]
+    dreq_timeout_ms: u32,
+    /// Soft resets of the VS1053 attempted when DREQ times out while playing
+    #[default(0)]
+    dreq_retries: u8,
+    /// Origin allowed to call the JSON API from another site, `*` for any
+    #[default("*")]
+    cors_origin: &'static str,
+    /// GPIOs of the volume knob, its push button muting
+    #[default(14)]
+    encoder_a_pin: i32,
+    #[default(15)]
+    encoder_b_pin: i32,
+    #[default(16)]
+    encoder_button_pin: i32,
+    /// GPIOs of the SSD1306 display, on its own I2C bus, with the `display` feature. They must
+    /// not be one of the GPIOs taken in `main`, e.g. GPIO8 drives the WS2812
+    #[default(2)]
+    display_sda_pin: i32,
+    #[default(17)]
+    display_scl_pin: i32,
+}
+
+impl Config {
+    fn utc_offset(&self) -> FixedOffset {
+        FixedOffset::east_opt(self.utc_offset_minutes * 60).unwrap_or_else(|| {
+            warn!("Invalid UTC offset {}, using UTC", self.utc_offset_minutes);
+            FixedOffset::east_opt(0).unwrap()
+        })
+    }
+
+    /// Static IP configuration, if `static_ip` and `static_gateway` are valid addresses.
+    fn ip_config(&self) -> Option<IpConfig> {
+        if self.static_ip.is_empty() {
+            return None;
+        }
+        let (Ok(ip), Ok(gateway)) = (self.static_ip.parse(), self.static_gateway.parse()) else {
+            warn!(
+                "Invalid static IP {:?} or gateway {:?}, falling back to DHCP",
+                self.static_ip, self.static_gateway
+            );
+            return None;
+        };
+        Some(IpConfig {
+            ip,
+            gateway,
+            mask: self.static_mask,
+            // Most home routers also act as DNS server
+            dns: self.static_dns.parse().unwrap_or(gateway),
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
-struct FormData<'a> {
+struct FormData {
     // fm_frequency: f32,
-    station: &'a str,
+    station: String,
     is_webradio: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct VolumeData {
+    volume: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkSizeData {
+    /// Bytes handed to the decoder at once by the webradio playback
+    chunk_size: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct FavoriteData {
+    station: String,
+    /// `false` to remove the station from the favorites
+    favorite: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MonoData {
+    mono: bool,
+}
+
+#[derive(Serialize)]
+struct MuteData {
+    muted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BalanceData {
+    /// -100 (left only) to 100 (right only)
+    balance: i8,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PlaybackAction {
+    Play,
+    Pause,
+    Stop,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaybackRequest {
+    action: PlaybackAction,
+}
+
+#[derive(Debug, Serialize)]
+struct PlaybackData {
+    state: PlaybackState,
+    /// Percentage of the webradio buffer waiting to be played, none on FM
+    buffer_fill: Option<u8>,
+    /// Times the webradio buffer ran empty since the station was selected
+    underruns: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct FmStatusData {
+    frequency: f32,
+    stereo: bool,
+    /// ADC level of the tuner, 0..15
+    signal_level: u8,
+    /// Set once a tuning or a search is over
+    ready: bool,
+    station: Option<&'static Station<'static>>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SeekDirection {
+    Up,
+    Down,
+}
+
+#[derive(Deserialize)]
+struct SeekRequest {
+    direction: SeekDirection,
+}
+
+#[derive(Serialize)]
+struct SeekData {
+    frequency: f32,
+    /// Preset matching the frequency, if any
+    station: Option<&'static Station<'static>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HostnameData {
+    /// Advertised over mDNS as `<hostname>.local`
+    hostname: String,
+}
+
+#[derive(Deserialize)]
+struct AuthData {
+    /// Empty to disable the authentication
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct ErrorData<'a> {
+    error: &'a str,
+}
+
+#[derive(Serialize)]
+struct StatusData {
+    /// `fm` or `webradio`
+    source: &'static str,
+    station_id: String,
+    station_name: Option<String>,
+    volume: u8,
+    muted: bool,
+    balance: i8,
+    tone: Tone,
+    playback: PlaybackState,
+    /// Whether the VS1053 answered, the webradios being unavailable otherwise
+    decoder_ok: bool,
+    /// What the decoder detected in the webradio, `None` on FM
+    stream: Option<StreamInfo>,
+    /// WiFi signal strength, in dBm
+    rssi: Option<i8>,
+    ip: Option<Ipv4Addr>,
+    uptime_secs: u64,
+    free_heap: u32,
+    /// Playback stalls the watchdog recovered from since boot
+    stalls: u32,
+}
+
+#[derive(Serialize)]
+struct I2cScanData {
+    /// Devices of the TEA5767 bus found at boot, e.g. `0x60`
+    addresses: Vec<String>,
+}
+
+/// Light enough for the small displays polling it.
+#[derive(Serialize)]
+struct NowPlayingData {
+    /// Name of the station, its id if unknown
+    station: String,
+    /// ICY title of the webradio, empty until known, or the station name on FM
+    title: String,
+}
+
+#[derive(Serialize)]
+struct MetricsData {
+    free_heap: u32,
+    /// Lowest free heap since boot
+    min_free_heap: u32,
+    /// A free heap split in small blocks fails the large allocations, e.g. TLS
+    largest_free_block: usize,
+    uptime_secs: u64,
+    tasks: u32,
+    /// Stream buffer of the webradio, `None` on FM
+    buffer_fill_percent: Option<u8>,
+    buffer_underruns: Option<u32>,
+    /// WiFi signal strength, in dBm
+    rssi: Option<i8>,
+    volume: u8,
+    station_id: String,
+}
+
+impl MetricsData {
+    /// Formats the metrics in the Prometheus text exposition format, skipping the ones
+    /// without a value.
+    fn to_prometheus(&self) -> String {
+        // Label values escape backslashes, quotes and line feeds
+        let station_id = self
+            .station_id
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let station_label = format!("{{station_id=\"{station_id}\"}}");
+        let metrics = [
+            (
+                "free_heap_bytes",
+                "gauge",
+                "Free heap.",
+                "",
+                Some(self.free_heap.to_string()),
+            ),
+            (
+                "min_free_heap_bytes",
+                "gauge",
+                "Lowest free heap since boot.",
+                "",
+                Some(self.min_free_heap.to_string()),
+            ),
+            (
+                "largest_free_block_bytes",
+                "gauge",
+                "Largest allocatable heap block.",
+                "",
+                Some(self.largest_free_block.to_string()),
+            ),
+            (
+                "uptime_seconds",
+                "counter",
+                "Time since boot.",
+                "",
+                Some(self.uptime_secs.to_string()),
+            ),
+            (
+                "tasks",
+                "gauge",
+                "FreeRTOS tasks.",
+                "",
+                Some(self.tasks.to_string()),
+            ),
+            (
+                "buffer_fill_percent",
+                "gauge",
+                "Fill of the webradio stream buffer.",
+                "",
+                self.buffer_fill_percent.map(|fill| fill.to_string()),
+            ),
+            // Counted per stream, starting again from 0 with each station
+            (
+                "buffer_underruns_total",
+                "counter",
+                "Times the webradio stream buffer ran empty.",
+                "",
+                self.buffer_underruns.map(|underruns| underruns.to_string()),
+            ),
+            (
+                "wifi_rssi_dbm",
+                "gauge",
+                "WiFi signal strength.",
+                "",
+                self.rssi.map(|rssi| rssi.to_string()),
+            ),
+            (
+                "volume",
+                "gauge",
+                "Volume, 0 to 100.",
+                "",
+                Some(self.volume.to_string()),
+            ),
+            (
+                "station_info",
+                "gauge",
+                "Station playing, always 1.",
+                station_label.as_str(),
+                Some("1".to_string()),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, labels, value) in metrics {
+            if let Some(value) = value {
+                text.push_str(&format!(
+                    "# HELP rustdio_{name} {help}\n# TYPE rustdio_{name} {kind}\nrustdio_{name}{labels} {value}\n"
+                ));
+            }
+        }
+        text
+    }
+}
+
+#[derive(Serialize)]
+struct JingleData {
+    size: usize,
+}
+
+#[derive(Serialize)]
+struct SelftestData {
+    chip_connected: bool,
+    chip_version: Option<u16>,
+    /// SCI_HDAT0 after the memory test, 0x83FF when all memories passed
+    memory_test: Option<u16>,
+    memory_ok: bool,
+    sine_test: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
-struct LastConfiguration<'a> {
-    last_source: &'a str,
-    last_station: &'a str,
+struct LastConfiguration {
+    last_source: String,
+    last_station: String,
     last_volume: u8,
+    last_balance: i8,
+    last_tone: Tone,
+    last_mono: bool,
+}
+
+impl Default for LastConfiguration {
+    fn default() -> Self {
+        LastConfiguration {
+            last_source: "fm".to_string(),
+            last_station: "france_info".to_string(),
+            last_volume: 50,
+            last_balance: 0,
+            last_tone: Tone::default(),
+            last_mono: false,
+        }
+    }
 }
 
 // struct ProgramAppState {
@@ -69,7 +480,527 @@ struct LastConfiguration<'a> {
 //     //ntp: ntp::Ntp,
 // }
 
+type FmTuner = TEA5767<I2cDriver<'static>>;
+type Mp3Decoder = VS1053<
+    SpiBusDevice,
+    PinDriver<'static, AnyOutputPin, Output>,
+    PinDriver<'static, Gpio5, Output>,
+    PinDriver<'static, Gpio47, Output>,
+    PinDriver<'static, Gpio4, Input>,
+>;
+
+/// Feeds a webradio to the shared decoder, reflecting the connection state on the LED.
+///
+/// The volume fades in up to `volume` once connected, while the first chunks are played.
+struct WebradioSink {
+    decoder: Arc<Mutex<Mp3Decoder>>,
+    led: Arc<Mutex<WS2812RMT<'static>>>,
+    volume: u8,
+    fade_in_start: Option<Instant>,
+}
+
+impl WebradioSink {
+    fn new(
+        decoder: Arc<Mutex<Mp3Decoder>>,
+        led: Arc<Mutex<WS2812RMT<'static>>>,
+        volume: u8,
+    ) -> Self {
+        WebradioSink {
+            decoder,
+            led,
+            volume,
+            // Pending until the first chunks are played
+            fade_in_start: Some(Instant::now()),
+        }
+    }
+
+    /// Fades back in to the volume the user may have changed meanwhile.
+    fn refresh_volume(&mut self) {
+        if self.fade_in_start.is_none() {
+            self.volume = self.decoder.lock().unwrap().get_volume();
+        }
+    }
+}
+
+impl StreamSink for WebradioSink {
+    fn play(&mut self, chunk: &[u8]) -> core::result::Result<(), DSPError> {
+        let mut decoder = self.decoder.lock().unwrap();
+        if let Some(start) = self.fade_in_start {
+            let elapsed = start.elapsed().as_millis() as u32;
+            let volume = if elapsed >= FADE_IN_MS {
+                self.fade_in_start = None;
+                self.volume
+            } else {
+                (self.volume as u32 * elapsed / FADE_IN_MS) as u8
+            };
+            decoder.set_volume(volume)?;
+        }
+        decoder.play(chunk)
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        EVENTS.publish(Event::Title(title.clone()));
+        self.decoder.set_title(title);
+    }
+
+    fn connected(&mut self) {
+        set_status(&self.led, LedStatus::Playing);
+        if let Err(err) = self.decoder.lock().unwrap().set_volume(0) {
+            warn!("Unable to mute before fading in: {:?}", err);
+        }
+        self.fade_in_start = Some(Instant::now());
+    }
+
+    fn reconnecting(&mut self, _delay: Duration) {
+        set_status(&self.led, LedStatus::Buffering);
+        self.refresh_volume();
+    }
+
+    fn buffering(&mut self, buffering: bool) {
+        EVENTS.publish(Event::Buffering(buffering));
+        if buffering {
+            set_status(&self.led, LedStatus::Buffering);
+            self.refresh_volume();
+        } else {
+            set_status(&self.led, LedStatus::Playing);
+        }
+    }
+
+    fn abort(&mut self) {
+        self.decoder.abort();
+        set_status(&self.led, LedStatus::Error);
+    }
+}
+
+/// What plays, shared by the HTTP handlers and the front panel controls.
+#[derive(Clone)]
+struct Player {
+    led: Arc<Mutex<WS2812RMT<'static>>>,
+    /// `None` on the boards without a TEA5767, which only play the webradios
+    tuner: Option<Arc<Mutex<FmTuner>>>,
+    decoder: Arc<Mutex<Mp3Decoder>>,
+    /// Whether the VS1053 answered at boot or on its last reset, the webradios needing it
+    decoder_ok: Arc<AtomicBool>,
+    stream_thread: Arc<Mutex<Option<StreamThread>>>,
+    /// Id of the station last selected
+    current_station: Arc<Mutex<String>>,
+    nvs_partition: EspNvsPartition<NvsDefault>,
+}
+
+impl Player {
+    fn decoder_ok(&self) -> bool {
+        self.decoder_ok.load(Ordering::Relaxed)
+    }
+
+    fn lock_tuner(&self) -> Result<Option<MutexGuard<'_, FmTuner>>> {
+        self.tuner
+            .as_ref()
+            .map(|tuner| {
+                tuner
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("Failed to lock radio tuner mutex"))
+            })
+            .transpose()
+    }
+
+    /// Fades out and stops what is playing, the cancel sequence of the datasheet flushing
+    /// the decoder so the next station starts cleanly.
+    fn fade_out_previous(&self, stream_thread: &mut Option<StreamThread>, volume: u8) {
+        // Only lock the decoder for each step, the stream thread still feeds it
+        if let Err(err) = fade(volume, 0, FADE_OUT_MS, |volume| {
+            self.decoder.lock().unwrap().set_volume(volume)
+        }) {
+            warn!("Unable to fade out: {:?}", err);
+        }
+        if let Some(previous_stream) = stream_thread.take() {
+            previous_stream.stop();
+        }
+        if let Err(err) = self.decoder.lock().unwrap().stop_song() {
+            warn!("Unable to stop the previous song: {:?}", err);
+        }
+    }
+
+    /// Fades out what plays, then tunes the FM `station` or streams it as a webradio.
+    ///
+    /// A station without a web URL is tuned on FM, even if asked as a webradio.
+    fn switch_station(&self, station: &str, is_webradio: bool) -> Result<()> {
+        let station_name = Station::get_name_from_id(station);
+        let is_webradio = if is_webradio && Station::get_web_url_from_id(station).is_none() {
+            warn!("{:?} has no web URL, tuning it on FM", station);
+            false
+        } else if !is_webradio && self.tuner.is_none() {
+            warn!("No FM tuner, playing the webradio of {:?}", station);
+            Station::get_web_url_from_id(station).is_some()
+        } else if !is_webradio && Station::get_fm_frequency_from_id(station).is_none() {
+            warn!("{:?} has no FM frequency, playing its webradio", station);
+            Station::get_web_url_from_id(station).is_some()
+        } else {
+            is_webradio
+        };
+        if is_webradio && !self.decoder_ok() {
+            return Err(anyhow::anyhow!(
+                "No decoder to play the webradio of {:?}",
+                station
+            ));
+        }
+        let last_source: &str;
+        let last_station: &str = station;
+        let mut stream_thread = self
+            .stream_thread
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock webradio stream mutex"))?;
+        let previous_volume = self.decoder.lock().unwrap().get_volume();
+        let nvs = EspNvs::new(self.nvs_partition.clone(), CONFIG_NVS_NAMESPACE, true)?;
+        // Evens out the loudness between stations, the fade in going to their own level
+        let volume = Station::volume_from_nvs(&nvs, station).unwrap_or(previous_volume);
+        drop(nvs);
+        let fm_frequency = Station::get_fm_frequency_from_id(station);
+        let mut stopped = false;
+        // The next station gets ready while the previous one still plays, so the cut over
+        // is only the fade out and the decoder flush
+        let next_stream = match Station::get_web_url_from_id(station).filter(|_| is_webradio) {
+            Some(url) => {
+                // Two streams at once need room for a second connection and buffer
+                if unsafe { esp_get_free_heap_size() } < SEAMLESS_SWITCH_MIN_FREE_HEAP {
+                    info!("Not enough heap to prebuffer {:?}, stopping first", station);
+                    self.fade_out_previous(&mut stream_thread, previous_volume);
+                    stopped = true;
+                }
+                info!("WebRadio set to: {:?}, URL:{}", station, url);
+                let next_stream = StreamThread::spawn_held(
+                    url.to_string(),
+                    WebradioSink::new(self.decoder.clone(), self.led.clone(), volume),
+                )?;
+                if !next_stream.wait_prefilled(SWITCH_PREFILL_TIMEOUT) {
+                    warn!("{:?} still buffering, switching to it anyway", station);
+                }
+                Some(next_stream)
+            }
+            None => None,
+        };
+        // Leaving a webradio, the tuner settles muted before the cut over
+        let pretune = fm_frequency.filter(|_| !is_webradio && stream_thread.is_some());
+        let pretuned = match (pretune, self.lock_tuner()?) {
+            (Some(freq), Some(mut fm_radio_tuner)) => {
+                fm_radio_tuner
+                    .mute()
+                    .map_err(|_| anyhow::anyhow!("Failed to mute radio tuner"))?;
+                fm_radio_tuner
+                    .set_frequency(freq)
+                    .map_err(|_| anyhow::anyhow!("Failed to set radio tuner frequency"))?;
+                true
+            }
+            _ => false,
+        };
+        if !stopped {
+            self.fade_out_previous(&mut stream_thread, previous_volume);
+        }
+        match next_stream {
+            Some(next_stream) => {
+                last_source = "webradio";
+                let mut mp3_decoder = self.decoder.lock().unwrap();
+                // Smooths out network jitter by letting the decoder adapt its speed
+                if let Err(err) = mp3_decoder.stream_mode_on() {
+                    warn!("Unable to enter VS1053 stream mode: {:?}", err);
+                }
+                let codec = Station::get_codec_from_id(station).unwrap_or(Codec::Unknown);
+                info!("Webradio codec: {:?}", codec);
+                if let Err(err) = mp3_decoder.set_codec(codec) {
+                    warn!("Unable to set up the VS1053 for {:?}: {:?}", codec, err);
+                }
+                drop(mp3_decoder);
+                // Leaving FM, the tuner would keep playing over the stream
+                if let Some(mut fm_radio_tuner) = self.lock_tuner()? {
+                    fm_radio_tuner
+                        .mute()
+                        .map_err(|_| anyhow::anyhow!("Failed to mute radio tuner"))?;
+                }
+                set_status(&self.led, LedStatus::Buffering);
+                next_stream.release();
+                *stream_thread = Some(next_stream);
+            }
+            None => {
+                last_source = "fm";
+                if let Err(err) = self.decoder.lock().unwrap().stream_mode_off() {
+                    warn!("Unable to leave VS1053 stream mode: {:?}", err);
+                }
+                match (fm_frequency, self.lock_tuner()?) {
+                    (Some(freq), Some(mut fm_radio_tuner)) => {
+                        if !pretuned {
+                            fm_radio_tuner.set_frequency(freq).map_err(|_| {
+                                anyhow::anyhow!("Failed to set radio tuner frequency")
+                            })?;
+                        }
+                        // The tuner may have been muted by a pause or a stop
+                        if !self.decoder.lock().unwrap().is_muted() {
+                            fm_radio_tuner
+                                .unmute()
+                                .map_err(|_| anyhow::anyhow!("Failed to unmute radio tuner"))?;
+                        }
+                        if let Err(err) = self.decoder.lock().unwrap().start_song() {
+                            warn!("Unable to start the FM playback: {:?}", err);
+                        }
+                        info!("FM Radio set to: {:?}, frequency:{}", station, freq);
+
+                        set_status(&self.led, LedStatus::Playing);
+                    }
+                    (Some(_), None) => warn!("No FM tuner to play {:?}", station),
+                    (None, _) => warn!("FM Radio {:?} [{:?}] not found", station_name, station),
+                }
+                if let Err(err) = self.decoder.lock().unwrap().fade_to(volume, FADE_IN_MS) {
+                    warn!("Unable to fade in: {:?}", err);
+                }
+            }
+        }
+        let mp3_decoder = self.decoder.lock().unwrap();
+        let key_raw_struct_data = LastConfiguration {
+            last_source: last_source.to_string(),
+            last_station: last_station.to_string(),
+            last_volume: volume,
+            last_balance: mp3_decoder.get_balance(),
+            last_tone: mp3_decoder.get_tone(),
+            last_mono: mp3_decoder.is_mono(),
+        };
+        drop(mp3_decoder);
+        *self.current_station.lock().unwrap() = station.to_string();
+        RING_LOG.record(
+            LogKind::Station,
+            format!("{} on {}", station_name.unwrap_or(station), last_source),
+        );
+        if volume != previous_volume {
+            EVENTS.publish(Event::Volume(volume));
+        }
+        EVENTS.publish(Event::Station {
+            id: station.to_string(),
+            name: station_name.map(str::to_string),
+            is_webradio,
+        });
+        let mut nvs_clone = EspNvs::new(self.nvs_partition.clone(), CONFIG_NVS_NAMESPACE, true)?;
+        nvs_clone.set_str("last_station", station)?;
+        store_last_configuration(&mut nvs_clone, CONFIG_NVS_KEY, &key_raw_struct_data);
+        Ok(())
+    }
+
+    /// Switches `step` stations forward (or backward if negative) within the current source.
+    fn step_station(&self, step: isize) -> Result<()> {
+        // Without a webradio thread, the FM tuner is the one playing, if any
+        let is_webradio = self.tuner.is_none() || self.stream_thread.lock().unwrap().is_some();
+        let current_station = self.current_station.lock().unwrap().clone();
+        let stations: Vec<&Station> = Station::all()
+            .into_iter()
+            .filter(|station| {
+                if is_webradio {
+                    station.has_webradio()
+                } else {
+                    station.has_fm()
+                }
+            })
+            .collect();
+        if stations.is_empty() {
+            return Ok(());
+        }
+        let index = stations
+            .iter()
+            .position(|station| station.id == current_station)
+            .map_or(0, |index| {
+                (index as isize + step).rem_euclid(stations.len() as isize) as usize
+            });
+        self.switch_station(stations[index].id, is_webradio)
+    }
+
+    /// Pauses or resumes the current source, a stopped one stays stopped.
+    fn set_paused(&self, paused: bool) -> Result<PlaybackState> {
+        let stream_thread = self
+            .stream_thread
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock webradio stream mutex"))?;
+        let mut fm_radio_tuner = self.lock_tuner()?;
+        let mut mp3_decoder = self.decoder.lock().unwrap();
+        if mp3_decoder.playback_state() == PlaybackState::Stopped {
+            return Ok(PlaybackState::Stopped);
+        }
+        // Without a webradio thread, the FM tuner is the one playing
+        let is_fm = stream_thread.is_none();
+        if paused {
+            mp3_decoder.pause();
+            if let Some(fm_radio_tuner) = fm_radio_tuner.as_mut().filter(|_| is_fm) {
+                fm_radio_tuner
+                    .mute()
+                    .map_err(|_| anyhow::anyhow!("Failed to mute radio tuner"))?;
+            }
+            set_status(&self.led, LedStatus::Idle);
+        } else {
+            mp3_decoder.resume();
+            let unmute = is_fm && !mp3_decoder.is_muted();
+            if let Some(fm_radio_tuner) = fm_radio_tuner.as_mut().filter(|_| unmute) {
+                fm_radio_tuner
+                    .unmute()
+                    .map_err(|_| anyhow::anyhow!("Failed to unmute radio tuner"))?;
+            }
+            set_status(&self.led, LedStatus::Playing);
+        }
+        EVENTS.publish(Event::Playback(mp3_decoder.playback_state()));
+        Ok(mp3_decoder.playback_state())
+    }
+
+    /// Stops the webradio stream or mutes the tuner, until a station is selected again.
+    fn stop(&self) -> Result<PlaybackState> {
+        let mut stream_thread = self
+            .stream_thread
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock webradio stream mutex"))?;
+        // Stop feeding the decoder before cancelling the song
+        if let Some(previous_stream) = stream_thread.take() {
+            previous_stream.stop();
+        }
+        if let Some(mut fm_radio_tuner) = self.lock_tuner()? {
+            fm_radio_tuner
+                .mute()
+                .map_err(|_| anyhow::anyhow!("Failed to mute radio tuner"))?;
+        }
+        let mut mp3_decoder = self.decoder.lock().unwrap();
+        if let Err(err) = mp3_decoder.stop_song() {
+            warn!("Unable to stop the song: {:?}", err);
+        }
+        set_status(&self.led, LedStatus::Idle);
+        EVENTS.publish(Event::Playback(mp3_decoder.playback_state()));
+        Ok(mp3_decoder.playback_state())
+    }
+
+    /// Stops what plays and resets the decoder, its patches, tone and mono output included.
+    fn reset_decoder(&self) -> Result<()> {
+        self.stop()?;
+        let mut mp3_decoder = self.decoder.lock().unwrap();
+        let reset = mp3_decoder.reset();
+        // A decoder missing at boot may answer once reset, e.g. after reseating it
+        self.decoder_ok.store(reset.is_ok(), Ordering::Relaxed);
+        reset.map_err(|err| anyhow::anyhow!("Failed to reset the decoder: {:?}", err))?;
+        if let Ok(ChipVersion::Vs1053) = mp3_decoder.chip_model() {
+            if let Err(err) = mp3_decoder.load_default_vs1053_patches() {
+                warn!("Unable to reload VS1053 patches: {:?}", err);
+            }
+        }
+        // The reset clears the tone, and the patches start stereo
+        let (mono, tone) = (mp3_decoder.is_mono(), mp3_decoder.get_tone());
+        let _ = mp3_decoder.set_mono(mono);
+        let _ = mp3_decoder.set_tone_settings(tone);
+        Ok(())
+    }
+
+    /// Sets the volume, clamped to 100, without saving it.
+    fn set_volume(&self, volume: u8) -> Result<u8> {
+        let volume = volume.min(100);
+        self.decoder
+            .lock()
+            .unwrap()
+            .set_volume(volume)
+            .map_err(|err| anyhow::anyhow!("Failed to set volume: {:?}", err))?;
+        info!("Volume set to: {}", volume);
+        EVENTS.publish(Event::Volume(volume));
+        Ok(volume)
+    }
+
+    /// Changes the volume by `delta`, which only takes effect on unmute while muted.
+    fn adjust_volume(&self, delta: i16) -> Result<u8> {
+        let volume = self.decoder.lock().unwrap().get_volume();
+        self.set_volume((volume as i16 + delta).clamp(0, 100) as u8)
+    }
+
+    /// Mutes the decoder, and the tuner when on FM, or unmutes them. Returns whether muted.
+    fn toggle_mute(&self) -> Result<bool> {
+        let stream_thread = self
+            .stream_thread
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock webradio stream mutex"))?;
+        let mut fm_radio_tuner = self.lock_tuner()?;
+        let mut mp3_decoder = self.decoder.lock().unwrap();
+        let muted = !mp3_decoder.is_muted();
+        // Without a webradio thread, the FM tuner is the one playing
+        let is_fm = stream_thread.is_none();
+        if muted {
+            mp3_decoder
+                .mute()
+                .map_err(|err| anyhow::anyhow!("Failed to mute: {:?}", err))?;
+            if let Some(fm_radio_tuner) = fm_radio_tuner.as_mut().filter(|_| is_fm) {
+                fm_radio_tuner
+                    .mute()
+                    .map_err(|_| anyhow::anyhow!("Failed to mute radio tuner"))?;
+            }
+        } else {
+            mp3_decoder
+                .unmute()
+                .map_err(|err| anyhow::anyhow!("Failed to unmute: {:?}", err))?;
+            // A paused tuner stays muted until resumed
+            let unmute = is_fm && mp3_decoder.playback_state() == PlaybackState::Playing;
+            if let Some(fm_radio_tuner) = fm_radio_tuner.as_mut().filter(|_| unmute) {
+                fm_radio_tuner
+                    .unmute()
+                    .map_err(|_| anyhow::anyhow!("Failed to unmute radio tuner"))?;
+            }
+        }
+        info!("Muted: {}", muted);
+        Ok(muted)
+    }
+
+    /// Saves the volume set by the user, also remembered as the level of the current station.
+    fn save_volume(&self, volume: u8) -> Result<()> {
+        let mut nvs = EspNvs::new(self.nvs_partition.clone(), CONFIG_NVS_NAMESPACE, true)?;
+        let mut configuration = read_config(&nvs).unwrap_or_default();
+        configuration.last_volume = volume;
+        store_last_configuration(&mut nvs, CONFIG_NVS_KEY, &configuration);
+        let station = self.current_station.lock().unwrap().clone();
+        if let Err(err) = Station::save_volume_to_nvs(&mut nvs, &station, volume) {
+            warn!("Unable to remember the volume of {:?}: {:?}", station, err);
+        }
+        Ok(())
+    }
+}
+
 const MAX_CONTROL_PAYLOAD_LEN: usize = 128;
+/// 7-bit addresses outside of the ones reserved by the I2C specification
+const I2C_SCAN_ADDRESSES: RangeInclusive<u8> = 0x08..=0x77;
+const I2C_PROBE_TIMEOUT: Duration = Duration::from_millis(10);
+const TEA5767_I2C_ADDRESS: u8 = 0x60;
+/// Registering more handlers than this fails with `ESP_ERR_HTTPD_HANDLERS_FULL`, raise it
+/// with the endpoints: about 40 are registered, the default being 32
+const HTTP_MAX_URI_HANDLERS: usize = 56;
+/// The handlers run on the server task, parsing JSON while holding the player locks, which
+/// needs more than the default 6 KB
+const HTTP_STACK_SIZE: usize = 10 * 1024;
+/// The WebSocket clients keep their socket open besides the page requests. The server uses
+/// 3 more, all of them within `CONFIG_LWIP_MAX_SOCKETS`
+const HTTP_MAX_OPEN_SOCKETS: usize = 8;
+/// Volume change of a front panel button press
+const VOLUME_STEP: u8 = 5;
+const CONFIG_NVS_NAMESPACE: &str = "test_ns";
+const CONFIG_NVS_KEY: &str = "config";
+const CHUNK_SIZE_NVS_KEY: &str = "chunk_size";
+/// Room for the postcard encoded `LastConfiguration`, grow it with its fields
+const CONFIG_BLOB_SIZE: usize = 256;
+const FADE_OUT_MS: u32 = 300;
+const FADE_IN_MS: u32 = 1000;
+/// Longest wait for the next webradio to buffer while the previous station plays
+const SWITCH_PREFILL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Heap for a second stream buffer, TLS connection and threads while switching webradios
+const SEAMLESS_SWITCH_MIN_FREE_HEAP: u32 = 96 * 1024;
+const MAX_STATION_PAYLOAD_LEN: usize = 512;
+const MAX_MQTT_PAYLOAD_LEN: usize = 512;
+const MAX_JINGLE_LEN: usize = 256 * 1024;
+const JINGLE_CHUNK_SIZE: usize = 1024;
+const OTA_CHUNK_SIZE: usize = 4096;
+/// First byte of an ESP-IDF application image
+const ESP_IMAGE_MAGIC: u8 = 0xE9;
+/// Lets the response reach the client before restarting
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+/// Default tolerance of the FM preset lookup, in MHz
+const FM_PRESET_TOLERANCE: f32 = 0.1;
+const SEEK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const SEEK_TIMEOUT: Duration = Duration::from_secs(3);
+const NTP_SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+const CLOCK_TICK_INTERVAL: Duration = Duration::from_secs(60);
+/// 1 kHz, see `VS1053::sine_test`
+const SELFTEST_SINE: u8 = 0x44;
+const SELFTEST_SINE_MS: u32 = 1000;
 static CONTROL_RADIO_HTML: &str = include_str!("control-radio.html");
 
 fn main() -> Result<()> {
@@ -78,7 +1009,7 @@ fn main() -> Result<()> {
 
     let nvs_default_partition: EspNvsPartition<NvsDefault> = EspDefaultNvsPartition::take()?;
 
-    let test_namespace = "test_ns";
+    let test_namespace = CONFIG_NVS_NAMESPACE;
     let nvs = match EspNvs::new(nvs_default_partition.clone(), test_namespace, true) {
         Ok(nvs) => {
             info!("Got namespace {:?} from default partition", test_namespace);
@@ -87,30 +1018,19 @@ fn main() -> Result<()> {
         Err(e) => panic!("Could't get namespace {:?}", e),
     };
 
-    let key_raw_struct: &str = "config";
-    let key_raw_struct_data: &mut [u8] = &mut [0; 100];
-    let mut last_configuration = LastConfiguration {
-        last_source: "fm",
-        last_station: "france_info",
-        last_volume: 50,
-    };
+    let mut last_configuration = read_config(&nvs).unwrap_or_default();
+    // Guard against a corrupted blob
+    last_configuration.last_volume = last_configuration.last_volume.min(100);
+    info!("{:?} = {:#?}", CONFIG_NVS_KEY, last_configuration);
 
-    match nvs.get_raw(key_raw_struct, key_raw_struct_data) {
-        Ok(v) => {
-            if let Some(the_struct) = v {
-                info!(
-                    "{:?} = {:#?}",
-                    key_raw_struct,
-                    from_bytes::<LastConfiguration>(the_struct)
-                );
-                match from_bytes::<LastConfiguration>(the_struct) {
-                    Ok(res) => last_configuration = res,
-                    Err(e) => warn!("Converting {:#?} failed because: {:?}", the_struct, e),
-                }
-            }
+    Station::merge_user_stations(Station::load_from_nvs(&nvs));
+    if let Ok(Some(chunk_size)) = nvs.get_u16(CHUNK_SIZE_NVS_KEY) {
+        if set_play_chunk_size(chunk_size.into()) {
+            info!("Webradio chunk size: {} bytes", chunk_size);
         }
-        Err(e) => warn!("Couldn't get key {} because {:?}", key_raw_struct, e),
-    };
+    }
+    let credentials = Arc::new(Credentials::load_from_nvs(&nvs));
+    let mqtt_config = MqttConfig::load_from_nvs(&nvs);
 
     let peripherals = Peripherals::take()?;
     let sysloop = EspSystemEventLoop::take()?;
@@ -124,12 +1044,15 @@ fn main() -> Result<()> {
         peripherals.pins.gpio8,
         peripherals.rmt.channel0,
     )?));
-    {
-        let mut led = led.lock().unwrap();
-        led.set_pixel(RGB8::new(50, 0, 0))?;
-    }
+    set_status(&led, LedStatus::Booting);
+    let _led_animation = spawn_animation(led.clone())?;
     info!("Post led");
 
+    // Local files are optional, the radio works without them
+    if let Err(err) = mount_storage() {
+        warn!("Unable to mount the storage partition: {:?}", err);
+    }
+
     // Initialize radio tuner
     let sda = peripherals.pins.gpio6;
     let scl = peripherals.pins.gpio7;
@@ -137,12 +1060,17 @@ fn main() -> Result<()> {
     // let _rst = peripherals.pins.gpio1;
     // let _gpio1 = peripherals.pins.gpio10;
     // let _gpio2 = peripherals.pins.gpio11;
-    let config = I2cConfig::new().baudrate(400.kHz().into());
-    let i2c = I2cDriver::new(peripherals.i2c0, sda, scl, &config)?;
+    let config = I2cConfig::new().baudrate(app_config.i2c_baudrate_hz.Hz().into());
+    let mut i2c = I2cDriver::new(peripherals.i2c0, sda, scl, &config)?;
+    // The tuner owns the bus afterwards, GET /i2c-scan reports this scan
+    let i2c_devices = scan_i2c(&mut i2c);
+    if !i2c_devices.contains(&TEA5767_I2C_ADDRESS) {
+        warn!("No TEA5767 at {:#04x}", TEA5767_I2C_ADDRESS);
+    }
 
     let default_station_frequency =
         // Station::get_fm_frequency_from_id("france_info").unwrap_or(105.5);
-        Station::get_fm_frequency_from_id(last_configuration.last_station).unwrap_or(105.5);
+        Station::get_fm_frequency_from_id(&last_configuration.last_station).unwrap_or(105.5);
 
     let fm_radio_tuner = match TEA5767::new(
         i2c,
@@ -150,10 +1078,11 @@ fn main() -> Result<()> {
         BandLimits::EuropeUS,
         SoundMode::Stereo,
     ) {
-        Ok(tuner) => Arc::new(Mutex::new(tuner)),
+        Ok(tuner) => Some(Arc::new(Mutex::new(tuner))),
+        // Many boards ship without the FM module, the webradios play without it
         Err(err) => {
-            warn!("Unable to initialize TEA5767 I2C:{}", err);
-            return Err(err.into());
+            warn!("FM disabled, unable to initialize TEA5767 I2C:{}", err);
+            None
         }
     };
 
@@ -167,69 +1096,288 @@ fn main() -> Result<()> {
     let mosi_pin = peripherals.pins.gpio21; //(instead of 23 normally, but not available on yurobot)
     let miso_pin = peripherals.pins.gpio19;
 
-    // Set up SPI configuration
-    let spi_config = SpiConfig::default().baudrate(4.MHz().into());
-    let low_spi_config = SpiConfig::default().baudrate(200.kHz().into());
-
-    // Initialize SPI bus
-    let spi_driver = SpiDriver::new(
+    // Initialize SPI bus, leaked so the decoder can be shared with the HTTP handlers
+    let spi_driver: &'static SpiDriver<'static> = Box::leak(Box::new(SpiDriver::new(
         peripherals.spi2,
         sck_pin,
         mosi_pin,
         Some(miso_pin),
         &DriverConfig::default().dma(Dma::Auto(4096)),
-    )?;
+    )?));
 
-    // Create an SPI device driver on the bus
-    let spi_device = SpiDeviceDriver::new(&spi_driver, None::<AnyOutputPin>, &spi_config)?;
-    let low_spi_device =
-        SpiDeviceDriver::new(&spi_driver, Option::<AnyOutputPin>::None, &low_spi_config)?;
-    // you can create different SpiDeviceDrivers, with different configs, and they all can have a different baudrate set on the same bus.
-    // If you want to control CS yourself you can just not provide a CS pin in the new() constructor since its a option
+    // Create the SPI device on the bus, begin() lowers the clock for the first writes
+    let spi_device = SpiBusDevice::new(spi_driver, 4_000_000)?;
 
     //VS1053 player(VS1053_CS, VS1053_DCS, VS1053_DREQ);
     // WiFiClient client;
     // uint8_t mp3buff[64];
-    //let mut mp3_decoder = VS1053::new(spi_driver, /*xrst_pin,*/ xcs_pin, xdcs_pin, dreq_pin);
 
-    let mut mp3_decoder = VS1053::new(spi_device, low_spi_device, xcs_pin, xdcs_pin, dreq_pin);
+    // No XRST wired yet, begin() resets through CS/DCS: pass Some(PinDriver::output(xrst_pin.downgrade_output())?) once available
+    let chip_select_pins = [xcs_pin.pin(), xdcs_pin.pin()];
+    let mut mp3_decoder: Mp3Decoder = VS1053::new(
+        spi_device,
+        None,
+        PinDriver::output(xcs_pin)?,
+        PinDriver::output(xdcs_pin)?,
+        PinDriver::input(dreq_pin)?,
+    );
+    mp3_decoder.set_dreq_timeout(Duration::from_millis(app_config.dreq_timeout_ms.into()));
+    mp3_decoder.set_dreq_retries(app_config.dreq_retries);
     log::info!(
-        "VS1053 connected:{:?}, chip version:{:?} volume:{:?}",
+        "VS1053 connected:{:?}, chip:{:?} volume:{:?}",
         mp3_decoder.is_chip_connected(),
-        mp3_decoder.get_chip_version(),
+        mp3_decoder.chip_model(),
         mp3_decoder.get_volume()
     );
 
-    // player.begin();
-    // if (player.getChipVersion() == 4) { // Only perform an update if we really are using a VS1053, not. eg. VS1003
-    //     player.loadDefaultVs1053Patches();
-    // }
-    // player.switchToMp3Mode();
-    // player.setVolume(VOLUME);
-
     let res = mp3_decoder.begin();
     log::info!("VS1053.begin():{:#?}", res);
-    mp3_decoder.switch_to_mp3_mode();
-    let _ = mp3_decoder.set_volume(last_configuration.last_volume);
-    mp3_decoder.set_balance(0);
+    // The FM and the web UI keep working without the decoder, e.g. to diagnose it
+    let decoder_ok = res.is_ok();
+    if !decoder_ok {
+        warn!("VS1053 unavailable, only FM and the web UI will work");
+        set_status(&led, LedStatus::Error);
+    } else if let Err(err) = mp3_decoder.set_spi_speed(app_config.spi_speed_hz) {
+        warn!(
+            "Unable to set the SPI clock to {} Hz: {:?}",
+            app_config.spi_speed_hz, err
+        );
+    }
+    // Only perform an update if we really are using a VS1053, not. eg. VS1003
+    if decoder_ok {
+        // The mode switch soft resets the decoder, which would drop the patches
+        mp3_decoder.switch_to_mp3_mode();
+        if let Ok(ChipVersion::Vs1053) = mp3_decoder.chip_model() {
+            if let Err(err) = mp3_decoder.load_default_vs1053_patches() {
+                warn!("Unable to load VS1053 patches: {:?}", err);
+            }
+        }
+        let _ = mp3_decoder.set_volume(last_configuration.last_volume);
+        let _ = mp3_decoder.set_balance(last_configuration.last_balance);
+        if let Err(err) = mp3_decoder.set_tone_settings(last_configuration.last_tone) {
+            warn!("Unable to restore the tone: {:?}", err);
+        }
+        if let Err(err) = mp3_decoder.set_mono(last_configuration.last_mono) {
+            warn!("Unable to restore the mono output: {:?}", err);
+        }
+    }
+    // A jingle uploaded with POST /jingle plays before the radio starts
+    if decoder_ok && Path::new(JINGLE_PATH).exists() {
+        if let Some(fm_radio_tuner) = &fm_radio_tuner {
+            let _ = fm_radio_tuner.lock().unwrap().mute();
+        }
+        if let Err(err) = mp3_decoder.play_file(JINGLE_PATH) {
+            warn!("Unable to play the jingle: {:?}", err);
+        }
+        if let Some(fm_radio_tuner) = &fm_radio_tuner {
+            let _ = fm_radio_tuner.lock().unwrap().unmute();
+        }
+    }
+    // Without a tuner, the last FM station is played as a webradio
+    let restore_webradio =
+        decoder_ok && (last_configuration.last_source == "webradio" || fm_radio_tuner.is_none());
+    if restore_webradio {
+        // Silent until the stream starts, once connected to the WiFi
+        if let Some(fm_radio_tuner) = &fm_radio_tuner {
+            let _ = fm_radio_tuner.lock().unwrap().mute();
+        }
+    } else if let Err(err) = mp3_decoder.start_song() {
+        // The tuner plays its default station from boot
+        warn!("Unable to start the FM playback: {:?}", err);
+    }
     log::info!(
-        "VS1053 MP3 decoder connected:{:?}, chip version:{:?} volume:{:?}",
+        "VS1053 MP3 decoder connected:{:?}, chip:{:?} volume:{:?}",
         mp3_decoder.is_chip_connected(),
-        mp3_decoder.get_chip_version(),
+        mp3_decoder.chip_model(),
         mp3_decoder.get_volume()
     );
 
-    let _wifi = wifi(
-        app_config.wifi_ssid,
-        app_config.wifi_psk,
-        peripherals.modem,
-        sysloop,
-        nvs_default_partition.clone(),
+    let mp3_decoder = Arc::new(Mutex::new(mp3_decoder));
+    install_panic_hook(mp3_decoder.clone(), chip_select_pins);
+    let stream_thread: Arc<Mutex<Option<StreamThread>>> = Arc::new(Mutex::new(None));
+    // The tuner starts on the last station, see `default_station_frequency`
+    let current_station = Arc::new(Mutex::new(last_configuration.last_station.clone()));
+    let player = Player {
+        led: led.clone(),
+        tuner: fm_radio_tuner.clone(),
+        decoder: mp3_decoder.clone(),
+        decoder_ok: Arc::new(AtomicBool::new(decoder_ok)),
+        stream_thread: stream_thread.clone(),
+        current_station: current_station.clone(),
+        nvs_partition: nvs_default_partition.clone(),
+    };
+
+    // Front panel buttons, each wired to the ground
+    let buttons = vec![
+        Button {
+            pin: peripherals.pins.gpio9.downgrade(),
+            control: Control::PlayPause,
+        },
+        Button {
+            pin: peripherals.pins.gpio10.downgrade(),
+            control: Control::Next,
+        },
+        Button {
+            pin: peripherals.pins.gpio11.downgrade(),
+            control: Control::Previous,
+        },
+        Button {
+            pin: peripherals.pins.gpio12.downgrade(),
+            control: Control::VolumeUp,
+        },
+        Button {
+            pin: peripherals.pins.gpio13.downgrade(),
+            control: Control::VolumeDown,
+        },
+        Button {
+            // Safety: the knob GPIOs are not used by anything else
+            pin: unsafe { AnyIOPin::new(CONFIG.encoder_button_pin) },
+            control: Control::Mute,
+        },
+    ];
+    let player_clone = player.clone();
+    let _buttons = spawn_buttons(buttons, move |control| on_control(&player_clone, control))?;
+    let encoder = unsafe {
+        RotaryEncoder {
+            a: AnyIOPin::new(CONFIG.encoder_a_pin),
+            b: AnyIOPin::new(CONFIG.encoder_b_pin),
+        }
+    };
+    let player_clone = player.clone();
+    let _encoder = spawn_encoder(encoder, move |control| on_control(&player_clone, control))?;
+
+    set_status(&led, LedStatus::WifiConnecting);
+    // Networks saved in NVS take precedence over the one baked in cfg.toml
+    let esp_wifi = if load_credentials(nvs_default_partition.clone())?.is_some() {
+        connect_best_known(
+            nvs_default_partition.clone(),
+            peripherals.modem,
+            sysloop.clone(),
+            app_config.ip_config(),
+            DEFAULT_CONNECT_TIMEOUT,
+        )?
+    } else if !app_config.wifi_ssid.is_empty() {
+        info!("No WiFi credentials stored, using the compile-time ones");
+        wifi(
+            app_config.wifi_ssid,
+            app_config.wifi_psk,
+            peripherals.modem,
+            sysloop.clone(),
+            nvs_default_partition.clone(),
+            app_config.ip_config(),
+            DEFAULT_CONNECT_TIMEOUT,
+        )?
+    } else {
+        warn!("No WiFi credentials stored, starting provisioning access point");
+        start_provisioning_ap(peripherals.modem, sysloop, nvs_default_partition.clone())?;
+        unreachable!("Provisioning reboots once the credentials are saved");
+    };
+    let wifi = Arc::new(Mutex::new(*esp_wifi));
+    set_status(&led, LedStatus::WifiConnected);
+    let _wifi_monitor = spawn_reconnect_monitor(wifi.clone(), sysloop)?;
+
+    let ntp_servers: Vec<&'static str> = app_config.ntp_servers.split(',').map(str::trim).collect();
+    let ntp = Arc::new(Ntp::new(app_config.utc_offset(), &ntp_servers)?);
+    // The clock is only needed for display, the radio plays without it
+    ntp.sync_blocking(NTP_SYNC_TIMEOUT);
+    let _ntp_thread = ntp.start_time_thread();
+
+    #[cfg(feature = "display")]
+    let _display = {
+        // Safety: the default GPIO2 and GPIO17 are free, the LED, tuner, decoder and buttons
+        // using GPIO4 to GPIO16, GPIO18, GPIO19, GPIO21 and GPIO47
+        let (sda, scl) = unsafe {
+            (
+                AnyIOPin::new(CONFIG.display_sda_pin),
+                AnyIOPin::new(CONFIG.display_scl_pin),
+            )
+        };
+        let i2c = I2cDriver::new(
+            peripherals.i2c1,
+            sda,
+            scl,
+            &I2cConfig::new().baudrate(400.kHz().into()),
+        )?;
+        let ntp_clone = ntp.clone();
+        let player_clone = player.clone();
+        display::spawn_display(i2c, move || {
+            let station_id = player_clone.current_station.lock().unwrap().clone();
+            let decoder = player_clone.decoder.lock().unwrap();
+            display::NowPlaying {
+                station_name: Station::get_name_from_id(&station_id).map(str::to_string),
+                title: decoder.current_stream_title(),
+                volume: decoder.get_volume(),
+                clock: ntp_clone
+                    .current_time()
+                    .map_or("--:--".to_string(), |time| time.format("%H:%M").to_string()),
+            }
+        })?
+    };
+
+    if restore_webradio {
+        info!("Restoring webradio {:?}", last_configuration.last_station);
+        if let Err(err) = player.switch_station(&last_configuration.last_station, true) {
+            warn!("Unable to restore the webradio: {:?}", err);
+        }
+    }
+
+    let player_clone = player.clone();
+    let restart_player = player.clone();
+    let _watchdog = spawn_watchdog(
+        move || {
+            // The decoded time only advances while a webradio feeds the decoder, not while it
+            // buffers or waits to reconnect
+            let streaming = player_clone
+                .stream_thread
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(StreamThread::is_feeding);
+            let mut mp3_decoder = player_clone.decoder.lock().unwrap();
+            if !streaming || mp3_decoder.playback_state() != PlaybackState::Playing {
+                return None;
+            }
+            mp3_decoder.get_decoded_time().ok()
+        },
+        move || {
+            let station = restart_player.current_station.lock().unwrap().clone();
+            RING_LOG.record(LogKind::Stall, format!("{station} stalled, restarting it"));
+            if let Err(err) = restart_player.switch_station(&station, true) {
+                warn!("Unable to restart {:?}: {:?}", station, err);
+                RING_LOG.record(
+                    LogKind::Error,
+                    format!("Unable to restart {station}: {err:?}"),
+                );
+            }
+        },
     )?;
 
-    let _default_station_url =
-        // Station::get_fm_frequency_from_id("france_info").unwrap_or(105.5);
-        Station::get_web_url_from_id(last_configuration.last_station).unwrap_or("http://europe2.lmn.fm/europe2.mp3");
+    let _mqtt = match mqtt_config {
+        Some(mqtt_config) => {
+            let state_player = player.clone();
+            let command_player = player.clone();
+            let spawned = spawn_mqtt(
+                mqtt_config,
+                move || {
+                    let station_id = state_player.current_station.lock().unwrap().clone();
+                    let mut decoder = state_player.decoder.lock().unwrap();
+                    MqttState {
+                        station_name: Station::get_name_from_id(&station_id).map(str::to_string),
+                        station_id,
+                        volume: decoder.get_volume(),
+                        playback: decoder.playback_state(),
+                        title: decoder.current_stream_title(),
+                    }
+                },
+                move |command| on_mqtt_command(&command_player, command),
+            );
+            // The radio is still usable without the broker
+            spawned
+                .map_err(|err| warn!("MQTT disabled: {:?}", err))
+                .ok()
+        }
+        None => None,
+    };
 
     // mp3_decoder.play_chunk(data, len);
 
@@ -244,137 +1392,1308 @@ fn main() -> Result<()> {
     // radio.set_channel_spacing(ChannelSpacing::Khz100).map_err(|e| format!("Channel spacing error: {:?}", e));
     // radio.unmute().map_err(|e: si4703::Error<esp_idf_hal::i2c::I2cError>| format!("Unmute error: {:?}", e));
 
-    let mut server = EspHttpServer::new(&Configuration::default())?;
+    let mut server = start_http_server()?;
 
-    // Clone the Arc to pass to the closure
-    let led_clone = led.clone();
+    server.fn_handler("/*", Method::Options, |req| {
+        req.into_response(
+            204,
+            None,
+            &[
+                ("Access-Control-Allow-Origin", CONFIG.cors_origin),
+                ("Access-Control-Allow-Methods", "GET, POST, OPTIONS"),
+                (
+                    "Access-Control-Allow-Headers",
+                    "Content-Type, Authorization",
+                ),
+                ("Access-Control-Max-Age", "86400"),
+            ],
+        )
+        .map(|_| ())
+    })?;
+
+    let player_clone = player.clone();
+    let ntp_clone = ntp.clone();
     server.fn_handler(
         "/",
         Method::Get,
         move |request| -> core::result::Result<(), EspIOError> {
-            let html = index_html();
+            let station_id = player_clone.current_station.lock().unwrap().clone();
+            let volume = player_clone.decoder.lock().unwrap().get_volume();
+            let html = index_html(
+                Station::get_name_from_id(&station_id).unwrap_or(&station_id),
+                volume,
+                ntp_clone.current_time(),
+            );
             let mut response = request.into_ok_response()?;
             response.write_all(html.as_bytes())?;
-            let mut led = led_clone.lock().unwrap();
-            let _ = led.set_pixel(RGB8::new(0, 50, 0));
             Ok(())
         },
     )?;
 
     server.fn_handler("/radio", Method::Get, |req| {
         req.into_ok_response()?
-            .write_all(CONTROL_RADIO_HTML.as_bytes())
+            .write_all(control_radio_html().as_bytes())
             .map(|_| ())
     })?;
 
-    let led_clone = led.clone();
-    let fm_radio_tuner_clone = fm_radio_tuner.clone();
-    server.fn_handler::<anyhow::Error, _>("/post-radio-form", Method::Post, move |mut req| {
-        let len = req.content_len().unwrap_or(0) as usize;
-
-        if len > MAX_CONTROL_PAYLOAD_LEN {
-            req.into_status_response(413)?
-                .write_all("Request too big".as_bytes())?;
+    let player_clone = player.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/post-radio-form", Method::Post, move |req| {
+        let Some((req, form)) =
+            read_authorized_json::<FormData>(req, &credentials_clone, MAX_CONTROL_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        // Checked before stopping anything, so the current station keeps playing. Stations
+        // without a web URL fall back to FM, and the web-only ones to their webradio
+        if Station::get_name_from_id(&form.station).is_none() {
+            warn!("Station {:?} not found", form);
+            json_response(req, 404)?.write_all(&serde_json::to_vec(&ErrorData {
+                error: "Unknown station",
+            })?)?;
             return Ok(());
         }
-
-        let mut buf = vec![0; len];
-        req.read_exact(&mut buf)?;
-        let mut resp = req.into_ok_response()?;
-
-        if let Ok(form) = serde_json::from_slice::<FormData>(&buf) {
-            let station_name = Station::get_name_from_id(form.station);
-            let last_source: &str;
-            let last_station: &str = form.station;
-            if !form.is_webradio {
-                last_source = "fm";
-                let fm_frequency = Station::get_fm_frequency_from_id(form.station);
-                match fm_frequency {
-                    Some(freq) => {
-                        let mut fm_radio_tuner = fm_radio_tuner_clone
-                            .lock()
-                            .map_err(|_| anyhow::anyhow!("Failed to lock radio tuner mutex"))?;
-                        fm_radio_tuner
-                            .set_frequency(freq)
-                            .map_err(|_| anyhow::anyhow!("Failed to set radio tuner frequency"))?;
-                        info!("FM Radio set to: {:?}, frequency:{}", form, freq);
-
-                        let mut led = led_clone.lock().unwrap();
-                        let _ = led.set_pixel(RGB8::new(0, 0, 0));
-                        sleep(Duration::from_millis(100));
-                        let _ = led.set_pixel(RGB8::new(0, 50, 0));
-                    }
-                    None => warn!("FM Radio {:?} [{:?}] not found", station_name, form),
-                }
-            } else {
-                last_source = "webradio";
-                let station_url = Station::get_web_url_from_id(form.station);
-                match station_url {
-                    Some(url) => {
-                        info!("WebRadio set to: {:?}, URL:{}", form, url);
-                    }
-                    None => warn!("Webradio {:?} [{:?}] not found", station_name, form),
-                }
-            }
-            let key_raw_struct_data = LastConfiguration {
-                last_source,
-                last_station,
-                last_volume: 50,
-            };
-            let mut nvs_clone =
-                EspNvs::new(nvs_default_partition.clone(), test_namespace, true).unwrap();
-            nvs_clone
-                .set_str("last_station", form.station)
-                .expect("Failed to set last_station at runtime");
-            match nvs_clone.set_raw(
-                key_raw_struct,
-                &to_vec::<LastConfiguration, 100>(&key_raw_struct_data).unwrap(),
-            ) {
-                Ok(_) => info!("Key {} updated", key_raw_struct),
-                Err(e) => info!("key {} not updated {:?}", key_raw_struct, e),
-            };
-            write!(
-                resp,
-                "Requested {} station and {} webradio",
-                form.station,
-                form.is_webradio // "Requested {} FM and {} station",
-                                 // form.fm_frequency, form.web_station
-            )?;
-        } else {
-            resp.write_all("JSON error".as_bytes())?;
+        if form.is_webradio && !player_clone.decoder_ok() {
+            status_response(req, 503)?.write_all("Decoder unavailable".as_bytes())?;
+            return Ok(());
+        }
+        if let Err(err) = player_clone.switch_station(&form.station, form.is_webradio) {
+            warn!("Unable to switch to {:?}: {:?}", form.station, err);
+            json_response(req, 500)?.write_all(&serde_json::to_vec(&ErrorData {
+                error: "Station switch failed",
+            })?)?;
+            return Ok(());
         }
+        let mut resp = status_response(req, 200)?;
+        write!(
+            resp,
+            "Requested {} station and {} webradio",
+            form.station,
+            form.is_webradio // "Requested {} FM and {} station",
+                             // form.fm_frequency, form.web_station
+        )?;
 
         Ok(())
     })?;
 
-    // fm_radio_tuner.set_frequency(fm_frequency).unwrap();
+    let player_clone = player.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/volume", Method::Post, move |req| {
+        let Some((req, data)) =
+            read_authorized_json::<VolumeData>(req, &credentials_clone, MAX_CONTROL_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        let volume = player_clone.set_volume(data.volume)?;
+        player_clone.save_volume(volume)?;
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&VolumeData { volume })?)?;
+        Ok(())
+    })?;
+
+    let mp3_decoder_clone = mp3_decoder.clone();
+    let nvs_partition_clone = nvs_default_partition.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/balance", Method::Post, move |req| {
+        let Some((req, data)) =
+            read_authorized_json::<BalanceData>(req, &credentials_clone, MAX_CONTROL_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        let mut mp3_decoder = mp3_decoder_clone.lock().unwrap();
+        mp3_decoder
+            .set_balance(data.balance)
+            .map_err(|err| anyhow::anyhow!("Failed to set balance: {:?}", err))?;
+        let balance = mp3_decoder.get_balance();
+        drop(mp3_decoder);
+        info!("Balance set to: {}", balance);
+
+        let mut nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        let mut configuration = read_config(&nvs_clone).unwrap_or_default();
+        configuration.last_balance = balance;
+        store_last_configuration(&mut nvs_clone, CONFIG_NVS_KEY, &configuration);
+
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&BalanceData { balance })?)?;
+        Ok(())
+    })?;
+
+    let mp3_decoder_clone = mp3_decoder.clone();
+    let nvs_partition_clone = nvs_default_partition.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/mono", Method::Post, move |req| {
+        let Some((req, data)) =
+            read_authorized_json::<MonoData>(req, &credentials_clone, MAX_CONTROL_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        mp3_decoder_clone
+            .lock()
+            .unwrap()
+            .set_mono(data.mono)
+            .map_err(|err| anyhow::anyhow!("Failed to set mono: {:?}", err))?;
+        info!("Mono output set to: {}", data.mono);
+
+        let mut nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        let mut configuration = read_config(&nvs_clone).unwrap_or_default();
+        configuration.last_mono = data.mono;
+        store_last_configuration(&mut nvs_clone, CONFIG_NVS_KEY, &configuration);
+
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&data)?)?;
+        Ok(())
+    })?;
+
+    let player_clone = player.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/mute", Method::Post, move |req| {
+        if !credentials_clone.is_authorized(req.header("Authorization")) {
+            unauthorized_response(req)?.write_all("Unauthorized".as_bytes())?;
+            return Ok(());
+        }
+        let muted = player_clone.toggle_mute()?;
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&MuteData { muted })?)?;
+        Ok(())
+    })?;
+
+    let mp3_decoder_clone = mp3_decoder.clone();
+    server.fn_handler::<anyhow::Error, _>("/balance", Method::Get, move |req| {
+        let balance = mp3_decoder_clone.lock().unwrap().get_balance();
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&BalanceData { balance })?)?;
+        Ok(())
+    })?;
+
+    let mp3_decoder_clone = mp3_decoder.clone();
+    let nvs_partition_clone = nvs_default_partition.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/tone", Method::Post, move |req| {
+        let Some((req, tone)) =
+            read_authorized_json::<Tone>(req, &credentials_clone, MAX_CONTROL_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        match mp3_decoder_clone.lock().unwrap().set_tone_settings(tone) {
+            Ok(()) => {}
+            Err(DSPError::InvalidParameter) => {
+                status_response(req, 400)?.write_all("Tone values must be 0..15".as_bytes())?;
+                return Ok(());
+            }
+            Err(err) => return Err(anyhow::anyhow!("Failed to set tone: {:?}", err)),
+        }
+        info!("Tone set to: {:?}", tone);
+
+        let mut nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        let mut configuration = read_config(&nvs_clone).unwrap_or_default();
+        configuration.last_tone = tone;
+        store_last_configuration(&mut nvs_clone, CONFIG_NVS_KEY, &configuration);
+
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&tone)?)?;
+        Ok(())
+    })?;
+
+    let mp3_decoder_clone = mp3_decoder.clone();
+    server.fn_handler::<anyhow::Error, _>("/volume", Method::Get, move |req| {
+        let volume = mp3_decoder_clone.lock().unwrap().get_volume();
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&VolumeData { volume })?)?;
+        Ok(())
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/chunk-size", Method::Get, |req| {
+        let chunk_size = ChunkSizeData {
+            chunk_size: play_chunk_size(),
+        };
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&chunk_size)?)?;
+        Ok(())
+    })?;
+
+    let nvs_partition_clone = nvs_default_partition.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/chunk-size", Method::Post, move |req| {
+        let Some((req, data)) = read_authorized_json::<ChunkSizeData>(
+            req,
+            &credentials_clone,
+            MAX_CONTROL_PAYLOAD_LEN,
+        )?
+        else {
+            return Ok(());
+        };
+        // Applied to the stream playing, from its next chunk
+        if !set_play_chunk_size(data.chunk_size) {
+            status_response(req, 400)?.write_all(
+                format!(
+                    "Chunk size out of {}..={}",
+                    PLAY_CHUNK_SIZE_RANGE.start(),
+                    PLAY_CHUNK_SIZE_RANGE.end()
+                )
+                .as_bytes(),
+            )?;
+            return Ok(());
+        }
+        let mut nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        // Fits, the range ending at 1024
+        nvs_clone.set_u16(CHUNK_SIZE_NVS_KEY, data.chunk_size as u16)?;
+        info!("Webradio chunk size set to {} bytes", data.chunk_size);
+
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&data)?)?;
+        Ok(())
+    })?;
+
+    let nvs_partition_clone = nvs_default_partition.clone();
+    server.fn_handler::<anyhow::Error, _>("/last-config", Method::Get, move |req| {
+        let nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        // Defaults are what the radio boots with when nothing was saved yet
+        let configuration = read_config(&nvs_clone).unwrap_or_default();
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&configuration)?)?;
+        Ok(())
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/stations", Method::Get, |req| {
+        let webradio_only = query_param(req.uri(), "webradio_only") == Some("true");
+        let stations = match query_param(req.uri(), "category") {
+            Some(category) => Station::by_category(&url_decode(category)),
+            None => Station::all(),
+        };
+        let stations: Vec<&Station> = stations
+            .into_iter()
+            .filter(|station| !webradio_only || station.has_webradio())
+            .collect();
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&stations)?)?;
+        Ok(())
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/stations/search", Method::Get, |req| {
+        let query = query_param(req.uri(), "q")
+            .map(url_decode)
+            .unwrap_or_default();
+        let stations = Station::search_by_name(&query);
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&stations)?)?;
+        Ok(())
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/stations/by-frequency", Method::Get, |req| {
+        let Some(freq) = query_param(req.uri(), "freq").and_then(|freq| freq.parse().ok()) else {
+            status_response(req, 400)?.write_all("Missing or invalid freq".as_bytes())?;
+            return Ok(());
+        };
+        let tolerance = query_param(req.uri(), "tolerance")
+            .and_then(|tolerance| tolerance.parse().ok())
+            .unwrap_or(FM_PRESET_TOLERANCE);
+        match Station::get_by_frequency(freq, tolerance) {
+            Some(station) => {
+                json_response(req, 200)?.write_all(&serde_json::to_vec(station)?)?;
+            }
+            None => {
+                status_response(req, 404)?
+                    .write_all("No station near this frequency".as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    let nvs_partition_clone = nvs_default_partition.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/stations", Method::Post, move |req| {
+        let Some((req, station)) =
+            read_authorized_json::<StationData>(req, &credentials_clone, MAX_STATION_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        let mut nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        match Station::add_to_nvs(&mut nvs_clone, station) {
+            Ok(station) => {
+                info!("Station {} added", station.id);
+                json_response(req, 201)?.write_all(&serde_json::to_vec(station)?)?;
+            }
+            Err(StationError::AlreadyExists) => {
+                status_response(req, 409)?.write_all("Station already exists".as_bytes())?;
+            }
+            Err(StationError::NoSpace) => {
+                status_response(req, 507)?
+                    .write_all("Not enough space to store the station list".as_bytes())?;
+            }
+            Err(err) => return Err(anyhow::anyhow!("Failed to store station: {:?}", err)),
+        }
+        Ok(())
+    })?;
+
+    let nvs_partition_clone = nvs_default_partition.clone();
+    server.fn_handler::<anyhow::Error, _>("/favorites", Method::Get, move |req| {
+        let nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        let favorites = Station::favorites(&nvs_clone);
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&favorites)?)?;
+        Ok(())
+    })?;
+
+    let nvs_partition_clone = nvs_default_partition.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/favorites", Method::Post, move |req| {
+        let Some((req, data)) =
+            read_authorized_json::<FavoriteData>(req, &credentials_clone, MAX_CONTROL_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        let mut nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        match Station::set_favorite(&mut nvs_clone, &data.station, data.favorite) {
+            Ok(()) => {
+                info!("Station {} favorite: {}", data.station, data.favorite);
+                let favorites = Station::favorites(&nvs_clone);
+                json_response(req, 200)?.write_all(&serde_json::to_vec(&favorites)?)?;
+            }
+            Err(StationError::NotFound) => {
+                status_response(req, 404)?.write_all("Unknown station".as_bytes())?;
+            }
+            Err(StationError::NoSpace) => {
+                status_response(req, 507)?
+                    .write_all("Not enough space to store the favorites".as_bytes())?;
+            }
+            Err(err) => return Err(anyhow::anyhow!("Failed to store favorites: {:?}", err)),
+        }
+        Ok(())
+    })?;
+
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/jingle", Method::Post, move |mut req| {
+        if !credentials_clone.is_authorized(req.header("Authorization")) {
+            unauthorized_response(req)?.write_all("Unauthorized".as_bytes())?;
+            return Ok(());
+        }
+        let len = req.content_len().unwrap_or(0) as usize;
+
+        if len > MAX_JINGLE_LEN {
+            status_response(req, 413)?.write_all("Jingle too big".as_bytes())?;
+            return Ok(());
+        }
+
+        let mut header = [0; 4];
+        if len < header.len() {
+            status_response(req, 400)?.write_all("Empty jingle".as_bytes())?;
+            return Ok(());
+        }
+        req.read_exact(&mut header)?;
+        if !is_mp3(&header) {
+            status_response(req, 415)?.write_all("Not an MP3 file".as_bytes())?;
+            return Ok(());
+        }
+
+        // Streamed to flash, the jingle doesn't fit in RAM
+        let mut file = File::create(JINGLE_UPLOAD_PATH)?;
+        file.write_all(&header)?;
+        let mut buf = vec![0; JINGLE_CHUNK_SIZE];
+        let mut size = header.len();
+        while size < len {
+            let read = req.read(&mut buf[..(len - size).min(JINGLE_CHUNK_SIZE)])?;
+            if read == 0 {
+                break;
+            }
+            file.write_all(&buf[..read])?;
+            size += read;
+        }
+        drop(file);
+        if size < len {
+            let _ = fs::remove_file(JINGLE_UPLOAD_PATH);
+            status_response(req, 400)?.write_all("Incomplete upload".as_bytes())?;
+            return Ok(());
+        }
+        // SPIFFS doesn't rename over an existing file
+        let _ = fs::remove_file(JINGLE_PATH);
+        fs::rename(JINGLE_UPLOAD_PATH, JINGLE_PATH)?;
+        info!("Jingle of {} bytes stored", size);
+
+        json_response(req, 201)?.write_all(&serde_json::to_vec(&JingleData { size })?)?;
+        Ok(())
+    })?;
+
+    let credentials_clone = credentials.clone();
+    let nvs_partition_clone = nvs_default_partition.clone();
+    server.fn_handler::<anyhow::Error, _>("/auth", Method::Post, move |req| {
+        let Some((req, auth)) =
+            read_authorized_json::<AuthData>(req, &credentials_clone, MAX_CONTROL_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        let mut nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        credentials_clone.save_to_nvs(&mut nvs_clone, &auth.username, &auth.password)?;
+        if auth.username.is_empty() {
+            info!("Basic Auth disabled");
+        } else {
+            info!("Basic Auth enabled for {}", auth.username);
+        }
+
+        status_response(req, 200)?;
+        Ok(())
+    })?;
+
+    let nvs_partition_clone = nvs_default_partition.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/mqtt", Method::Post, move |req| {
+        let Some((req, mqtt_config)) =
+            read_authorized_json::<MqttConfig>(req, &credentials_clone, MAX_MQTT_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        let mut nvs_clone = EspNvs::new(nvs_partition_clone.clone(), test_namespace, true)?;
+        mqtt_config.save_to_nvs(&mut nvs_clone)?;
+        // The client is only started at boot
+        info!(
+            "MQTT broker set to {:?}, applied on reboot",
+            mqtt_config.url
+        );
+
+        status_response(req, 200)?;
+        Ok(())
+    })?;
+
+    let nvs_partition_clone = nvs_default_partition.clone();
+    server.fn_handler::<anyhow::Error, _>("/hostname", Method::Get, move |req| {
+        let hostname = load_hostname(nvs_partition_clone.clone());
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&HostnameData { hostname })?)?;
+        Ok(())
+    })?;
+
+    let credentials_clone = credentials.clone();
+    let nvs_partition_clone = nvs_default_partition.clone();
+    server.fn_handler::<anyhow::Error, _>("/hostname", Method::Post, move |req| {
+        let Some((req, data)) =
+            read_authorized_json::<HostnameData>(req, &credentials_clone, MAX_CONTROL_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        if let Err(err) = save_hostname(nvs_partition_clone.clone(), &data.hostname) {
+            warn!("Unable to change the hostname: {:?}", err);
+            status_response(req, 400)?.write_all("Invalid hostname".as_bytes())?;
+            return Ok(());
+        }
+
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&data)?)?;
+        Ok(())
+    })?;
+
+    let stream_thread_clone = stream_thread.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/ota", Method::Post, move |mut req| {
+        if !credentials_clone.is_authorized(req.header("Authorization")) {
+            unauthorized_response(req)?.write_all("Unauthorized".as_bytes())?;
+            return Ok(());
+        }
+        let len = req.content_len().unwrap_or(0) as usize;
+        // Without the length, a truncated upload could not be told apart from a complete one
+        if len == 0 {
+            status_response(req, 411)?.write_all("Content-Length required".as_bytes())?;
+            return Ok(());
+        }
+        let mut buf = vec![0; OTA_CHUNK_SIZE];
+        let read = req.read(&mut buf[..len.min(OTA_CHUNK_SIZE)])?;
+        if read == 0 || buf[0] != ESP_IMAGE_MAGIC {
+            status_response(req, 415)?.write_all("Not an ESP32 firmware image".as_bytes())?;
+            return Ok(());
+        }
+
+        // Leave the network and CPU to the upload
+        if let Some(previous_stream) = stream_thread_clone.lock().unwrap().take() {
+            previous_stream.stop();
+        }
+        let mut ota = EspOta::new()?;
+        let mut update = ota.initiate_update()?;
+        info!("OTA update of {} bytes started", len);
+        let mut written = 0;
+        let mut last_percent = 0;
+        let mut chunk_len = read;
+        while chunk_len > 0 {
+            if let Err(err) = update.write_all(&buf[..chunk_len]) {
+                update.abort()?;
+                return Err(err.into());
+            }
+            written += chunk_len;
+            let percent = written * 100 / len.max(1);
+            if percent >= last_percent + 10 {
+                info!("OTA update: {}% ({} bytes)", percent, written);
+                last_percent = percent;
+            }
+            if written >= len {
+                break;
+            }
+            chunk_len = match req.read(&mut buf[..(len - written).min(OTA_CHUNK_SIZE)]) {
+                Ok(chunk_len) => chunk_len,
+                Err(err) => {
+                    update.abort()?;
+                    return Err(err.into());
+                }
+            };
+        }
+
+        // The boot partition only changes once the whole image is written and verified
+        if written < len {
+            warn!("OTA update aborted after {} of {} bytes", written, len);
+            update.abort()?;
+            status_response(req, 400)?.write_all("Incomplete upload".as_bytes())?;
+            return Ok(());
+        }
+        if let Err(err) = update.complete() {
+            warn!("OTA image rejected: {:?}", err);
+            status_response(req, 400)?.write_all("Invalid firmware image".as_bytes())?;
+            return Ok(());
+        }
+        info!("OTA update complete, rebooting");
+        status_response(req, 200)?.write_all("Update complete, rebooting".as_bytes())?;
+        thread::spawn(|| {
+            sleep(REBOOT_DELAY);
+            restart();
+        });
+        Ok(())
+    })?;
+
+    let player_clone = player.clone();
+    let credentials_clone = credentials.clone();
+    register_ws_handler(
+        &mut server,
+        move |authorization| credentials_clone.is_authorized(authorization),
+        move |command| on_ws_command(&player_clone, command),
+    )?;
+
+    let player_clone = player.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/reboot", Method::Post, move |req| {
+        if !credentials_clone.is_authorized(req.header("Authorization")) {
+            unauthorized_response(req)?.write_all("Unauthorized".as_bytes())?;
+            return Ok(());
+        }
+        // The knob volume is only saved once it settled
+        let volume = player_clone.decoder.lock().unwrap().get_volume();
+        if let Err(err) = player_clone.save_volume(volume) {
+            warn!("Unable to save the volume before rebooting: {:?}", err);
+        }
+        if let Err(err) = player_clone.stop() {
+            warn!("Unable to stop the playback before rebooting: {:?}", err);
+        }
+        info!("Reboot requested");
+        status_response(req, 200)?.write_all("Rebooting".as_bytes())?;
+        thread::spawn(|| {
+            sleep(REBOOT_DELAY);
+            restart();
+        });
+        Ok(())
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/logs", Method::Get, |req| {
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&RING_LOG.entries())?)?;
+        Ok(())
+    })?;
+
+    let player_clone = player.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/decoder/reset", Method::Post, move |req| {
+        if !credentials_clone.is_authorized(req.header("Authorization")) {
+            unauthorized_response(req)?.write_all("Unauthorized".as_bytes())?;
+            return Ok(());
+        }
+        let station = player_clone.current_station.lock().unwrap().clone();
+        let is_webradio = player_clone.stream_thread.lock().unwrap().is_some();
+        if let Err(err) = player_clone.reset_decoder() {
+            warn!("Unable to reset the decoder: {:?}", err);
+            RING_LOG.record(LogKind::Error, format!("Decoder reset failed: {err:?}"));
+            status_response(req, 500)?.write_all("Decoder reset failed".as_bytes())?;
+            return Ok(());
+        }
+        // The webradio starts again from a clean decoder, FM only needs the line input back
+        if let Err(err) = player_clone.switch_station(&station, is_webradio) {
+            warn!("Unable to resume {:?} after the reset: {:?}", station, err);
+        }
+        status_response(req, 200)?.write_all("Decoder reset".as_bytes())?;
+        Ok(())
+    })?;
+
+    let player_clone = player.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/playback", Method::Post, move |req| {
+        let Some((req, request)) = read_authorized_json::<PlaybackRequest>(
+            req,
+            &credentials_clone,
+            MAX_CONTROL_PAYLOAD_LEN,
+        )?
+        else {
+            return Ok(());
+        };
+        let state = match request.action {
+            PlaybackAction::Play => {
+                if player_clone.decoder.lock().unwrap().playback_state() == PlaybackState::Stopped {
+                    status_response(req, 409)?
+                        .write_all("Nothing to resume, select a station".as_bytes())?;
+                    return Ok(());
+                }
+                player_clone.set_paused(false)?
+            }
+            PlaybackAction::Pause => player_clone.set_paused(true)?,
+            PlaybackAction::Stop => player_clone.stop()?,
+        };
+        info!("Playback {:?}, now {:?}", request.action, state);
+
+        let stream_thread = player_clone.stream_thread.lock().unwrap();
+        let playback = PlaybackData {
+            state,
+            buffer_fill: stream_thread
+                .as_ref()
+                .map(StreamThread::buffer_fill_percent),
+            underruns: stream_thread.as_ref().map(StreamThread::underruns),
+        };
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&playback)?)?;
+        Ok(())
+    })?;
+
+    let fm_radio_tuner_clone = fm_radio_tuner.clone();
+    let stream_thread_clone = stream_thread.clone();
+    let credentials_clone = credentials.clone();
+    server.fn_handler::<anyhow::Error, _>("/seek", Method::Post, move |req| {
+        let Some((req, request)) =
+            read_authorized_json::<SeekRequest>(req, &credentials_clone, MAX_CONTROL_PAYLOAD_LEN)?
+        else {
+            return Ok(());
+        };
+        let Some(fm_radio_tuner_clone) = &fm_radio_tuner_clone else {
+            status_response(req, 503)?.write_all("No FM tuner".as_bytes())?;
+            return Ok(());
+        };
+        if stream_thread_clone.lock().unwrap().is_some() {
+            status_response(req, 409)?.write_all("Seeking is only available on FM".as_bytes())?;
+            return Ok(());
+        }
+
+        let mut fm_radio_tuner = fm_radio_tuner_clone
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock radio tuner mutex"))?;
+        let tuner_error = |_| anyhow::anyhow!("Radio tuner seek failed");
+        fm_radio_tuner.mute().map_err(tuner_error)?;
+
+        // The second pass restarts from the other end of the band once a limit is reached
+        for _ in 0..2 {
+            match request.direction {
+                SeekDirection::Up => fm_radio_tuner.search_up().map_err(tuner_error)?,
+                SeekDirection::Down => fm_radio_tuner.search_down().map_err(tuner_error)?,
+            }
+            // The search is over once the tuner stops moving
+            let started = Instant::now();
+            let mut previous = f32::NAN;
+            let mut frequency = fm_radio_tuner.get_frequency().map_err(tuner_error)?;
+            while frequency != previous && started.elapsed() < SEEK_TIMEOUT {
+                sleep(SEEK_POLL_INTERVAL);
+                previous = frequency;
+                frequency = fm_radio_tuner.get_frequency().map_err(tuner_error)?;
+            }
+
+            let wrap_to = match request.direction {
+                SeekDirection::Up if frequency >= FM_BAND_MAX_MHZ - FM_PRESET_TOLERANCE => {
+                    FM_BAND_MIN_MHZ
+                }
+                SeekDirection::Down if frequency <= FM_BAND_MIN_MHZ + FM_PRESET_TOLERANCE => {
+                    FM_BAND_MAX_MHZ
+                }
+                _ => break,
+            };
+            info!(
+                "Band limit reached at {}, wrapping to {}",
+                frequency, wrap_to
+            );
+            fm_radio_tuner.set_frequency(wrap_to).map_err(tuner_error)?;
+        }
+
+        let frequency = fm_radio_tuner.get_frequency().map_err(tuner_error)?;
+        fm_radio_tuner.unmute().map_err(tuner_error)?;
+        let station = Station::get_by_frequency(frequency, FM_PRESET_TOLERANCE);
+        info!(
+            "Seek {:?} stopped at {}, preset: {:?}",
+            request.direction,
+            frequency,
+            station.map(|station| station.id)
+        );
+
+        json_response(req, 200)?
+            .write_all(&serde_json::to_vec(&SeekData { frequency, station })?)?;
+        Ok(())
+    })?;
+
+    let fm_radio_tuner_clone = fm_radio_tuner.clone();
+    server.fn_handler::<anyhow::Error, _>("/fm-status", Method::Get, move |req| {
+        let Some(fm_radio_tuner_clone) = &fm_radio_tuner_clone else {
+            status_response(req, 503)?.write_all("No FM tuner".as_bytes())?;
+            return Ok(());
+        };
+        // Seeking holds the tuner for a while, don't wait for it
+        let Ok(mut fm_radio_tuner) = fm_radio_tuner_clone.try_lock() else {
+            status_response(req, 503)?.write_all("Radio tuner busy".as_bytes())?;
+            return Ok(());
+        };
+        let tuner_error = |_| anyhow::anyhow!("Failed to read radio tuner status");
+        let frequency = fm_radio_tuner.get_frequency().map_err(tuner_error)?;
+        let status = FmStatusData {
+            frequency,
+            stereo: fm_radio_tuner.is_stereo().map_err(tuner_error)?,
+            signal_level: fm_radio_tuner.get_signal_level().map_err(tuner_error)?,
+            ready: fm_radio_tuner.is_ready().map_err(tuner_error)?,
+            station: Station::get_by_frequency(frequency, FM_PRESET_TOLERANCE),
+        };
+        drop(fm_radio_tuner);
+
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&status)?)?;
+        Ok(())
+    })?;
+
+    server.fn_handler::<anyhow::Error, _>("/i2c-scan", Method::Get, move |req| {
+        let addresses: Vec<String> = i2c_devices
+            .iter()
+            .map(|address| format!("{:#04x}", address))
+            .collect();
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&I2cScanData { addresses })?)?;
+        Ok(())
+    })?;
+
+    let mp3_decoder_clone = mp3_decoder.clone();
+    let stream_thread_clone = stream_thread.clone();
+    server.fn_handler::<anyhow::Error, _>("/playback", Method::Get, move |req| {
+        let state = mp3_decoder_clone.lock().unwrap().playback_state();
+        let stream_thread = stream_thread_clone.lock().unwrap();
+        let playback = PlaybackData {
+            state,
+            buffer_fill: stream_thread
+                .as_ref()
+                .map(StreamThread::buffer_fill_percent),
+            underruns: stream_thread.as_ref().map(StreamThread::underruns),
+        };
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&playback)?)?;
+        Ok(())
+    })?;
+
+    let mp3_decoder_clone = mp3_decoder.clone();
+    let stream_thread_clone = stream_thread.clone();
+    let current_station_clone = current_station.clone();
+    server.fn_handler::<anyhow::Error, _>("/now-playing", Method::Get, move |req| {
+        let is_webradio = stream_thread_clone.lock().unwrap().is_some();
+        let station_id = current_station_clone.lock().unwrap().clone();
+        let station = Station::get_name_from_id(&station_id)
+            .map(str::to_string)
+            .unwrap_or(station_id);
+        // FM has no song titles, and the webradios none until their first metadata block
+        let title = if is_webradio {
+            mp3_decoder_clone
+                .lock()
+                .unwrap()
+                .current_stream_title()
+                .unwrap_or_default()
+        } else {
+            station.clone()
+        };
+        json_response(req, 200)?
+            .write_all(&serde_json::to_vec(&NowPlayingData { station, title })?)?;
+        Ok(())
+    })?;
+
+    let mp3_decoder_clone = mp3_decoder.clone();
+    let stream_thread_clone = stream_thread.clone();
+    let current_station_clone = current_station.clone();
+    let wifi_clone = wifi.clone();
+    let player_clone = player.clone();
+    server.fn_handler::<anyhow::Error, _>("/status", Method::Get, move |req| {
+        // Without a webradio thread, the FM tuner is the one playing
+        let source = if stream_thread_clone.lock().unwrap().is_some() {
+            "webradio"
+        } else {
+            "fm"
+        };
+        let station_id = current_station_clone.lock().unwrap().clone();
+        let mut mp3_decoder = mp3_decoder_clone.lock().unwrap();
+        let (volume, muted, balance, tone, playback) = (
+            mp3_decoder.get_volume(),
+            mp3_decoder.is_muted(),
+            mp3_decoder.get_balance(),
+            mp3_decoder.get_tone(),
+            mp3_decoder.playback_state(),
+        );
+        let stream = if source == "webradio" {
+            mp3_decoder
+                .stream_info()
+                .map_err(|err| warn!("Unable to read the stream info: {:?}", err))
+                .ok()
+        } else {
+            None
+        };
+        drop(mp3_decoder);
+        // The reconnect monitor holds the driver while reconnecting
+        let wifi = match wifi_clone.try_lock() {
+            Ok(wifi) => wifi_status(&wifi),
+            Err(_) => WifiStatus::default(),
+        };
+
+        let status = StatusData {
+            source,
+            station_name: Station::get_name_from_id(&station_id).map(str::to_string),
+            station_id,
+            volume,
+            muted,
+            balance,
+            tone,
+            playback,
+            decoder_ok: player_clone.decoder_ok(),
+            stream,
+            rssi: wifi.rssi,
+            ip: wifi.ip,
+            uptime_secs: (unsafe { esp_timer_get_time() } / 1_000_000) as u64,
+            free_heap: unsafe { esp_get_free_heap_size() },
+            stalls: stall_count(),
+        };
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&status)?)?;
+        Ok(())
+    })?;
+
+    let stream_thread_clone = stream_thread.clone();
+    let mp3_decoder_clone = mp3_decoder.clone();
+    let current_station_clone = current_station.clone();
+    let wifi_clone = wifi.clone();
+    server.fn_handler::<anyhow::Error, _>("/metrics", Method::Get, move |req| {
+        let (buffer_fill_percent, buffer_underruns) = match &*stream_thread_clone.lock().unwrap() {
+            Some(stream) => (Some(stream.buffer_fill_percent()), Some(stream.underruns())),
+            None => (None, None),
+        };
+        let metrics = MetricsData {
+            free_heap: unsafe { esp_get_free_heap_size() },
+            min_free_heap: unsafe { esp_get_minimum_free_heap_size() },
+            largest_free_block: unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) },
+            uptime_secs: (unsafe { esp_timer_get_time() } / 1_000_000) as u64,
+            tasks: unsafe { uxTaskGetNumberOfTasks() },
+            buffer_fill_percent,
+            buffer_underruns,
+            // The reconnect monitor holds the driver while reconnecting
+            rssi: wifi_clone
+                .try_lock()
+                .ok()
+                .and_then(|wifi| wifi_status(&wifi).rssi),
+            volume: mp3_decoder_clone.lock().unwrap().get_volume(),
+            station_id: current_station_clone.lock().unwrap().clone(),
+        };
+        if query_param(req.uri(), "format") == Some("prometheus") {
+            req.into_response(
+                200,
+                None,
+                &[
+                    ("Content-Type", "text/plain; version=0.0.4"),
+                    ("Access-Control-Allow-Origin", CONFIG.cors_origin),
+                ],
+            )?
+            .write_all(metrics.to_prometheus().as_bytes())?;
+            return Ok(());
+        }
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&metrics)?)?;
+        Ok(())
+    })?;
+
+    let led_clone = led.clone();
+    let fm_radio_tuner_clone = fm_radio_tuner.clone();
+    let mp3_decoder_clone = mp3_decoder.clone();
+    let stream_thread_clone = stream_thread.clone();
+    let credentials_clone = credentials.clone();
+    let player_clone = player.clone();
+    server.fn_handler::<anyhow::Error, _>("/selftest", Method::Get, move |req| {
+        if !credentials_clone.is_authorized(req.header("Authorization")) {
+            unauthorized_response(req)?.write_all("Unauthorized".as_bytes())?;
+            return Ok(());
+        }
+        let station = player_clone.current_station.lock().unwrap().clone();
+        // The tests reset the decoder, stop whatever is playing first
+        let mut stream_thread = stream_thread_clone
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock webradio stream mutex"))?;
+        let is_webradio = stream_thread.is_some();
+        if let Some(previous_stream) = stream_thread.take() {
+            previous_stream.stop();
+        }
+        if let Some(fm_radio_tuner) = &fm_radio_tuner_clone {
+            fm_radio_tuner
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Failed to lock radio tuner mutex"))?
+                .mute()
+                .map_err(|_| anyhow::anyhow!("Failed to mute radio tuner"))?;
+        }
+        let mut mp3_decoder = mp3_decoder_clone.lock().unwrap();
+        if let Err(err) = mp3_decoder.stop_song() {
+            warn!("Unable to stop the song: {:?}", err);
+        }
+
+        let chip_version = mp3_decoder.get_chip_version().ok();
+        let memory_test = mp3_decoder.run_memory_test();
+        info!("VS1053 memory test:{:?}", memory_test);
+        mp3_decoder.switch_to_mp3_mode();
+        if chip_version.map(ChipVersion::from) == Some(ChipVersion::Vs1053) {
+            if let Err(err) = mp3_decoder.load_default_vs1053_patches() {
+                warn!("Unable to reload VS1053 patches: {:?}", err);
+            }
+        }
+        // The patches start stereo
+        let mono = mp3_decoder.is_mono();
+        let _ = mp3_decoder.set_mono(mono);
+        let sine_test = mp3_decoder.sine_test(SELFTEST_SINE, SELFTEST_SINE_MS);
+        info!("VS1053 sine test:{:?}", sine_test);
+
+        let selftest = SelftestData {
+            chip_connected: mp3_decoder.is_chip_connected().unwrap_or(false),
+            chip_version,
+            memory_test: memory_test.as_ref().ok().copied(),
+            memory_ok: memory_test.is_ok_and(|result| result == MEMORY_TEST_OK),
+            sine_test: sine_test.is_ok(),
+        };
+        drop(mp3_decoder);
+        drop(stream_thread);
+        set_status(&led_clone, LedStatus::Idle);
+        // The tests left the tuner muted and the decoder silent
+        if let Err(err) = player_clone.switch_station(&station, is_webradio) {
+            warn!(
+                "Unable to resume {:?} after the self-test: {:?}",
+                station, err
+            );
+        }
+
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&selftest)?)?;
+        Ok(())
+    })?;
+
+    let wifi_clone = wifi.clone();
+    server.fn_handler::<anyhow::Error, _>("/wifi-scan", Method::Get, move |req| {
+        // The reconnect monitor holds the driver while reconnecting
+        let networks = match wifi_clone.try_lock() {
+            Ok(mut wifi) => scan_networks(&mut wifi),
+            Err(_) => Err(anyhow::anyhow!("WiFi driver busy")),
+        };
+        match networks {
+            Ok(networks) => {
+                json_response(req, 200)?.write_all(&serde_json::to_vec(&networks)?)?;
+            }
+            Err(err) => {
+                warn!("WiFi scan failed: {:?}", err);
+                status_response(req, 503)?.write_all("WiFi scan failed".as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    let wifi_clone = wifi.clone();
+    server.fn_handler::<anyhow::Error, _>("/wifi-status", Method::Get, move |req| {
+        // The reconnect monitor holds the driver while reconnecting
+        let status = match wifi_clone.try_lock() {
+            Ok(wifi) => wifi_status(&wifi),
+            Err(_) => WifiStatus::default(),
+        };
+        json_response(req, 200)?.write_all(&serde_json::to_vec(&status)?)?;
+        Ok(())
+    })?;
+
+    // fm_radio_tuner.set_frequency(fm_frequency).unwrap();
     // let _ = fm_radio_tuner.mute();
     // fm_radio_tuner.set_standby();
     // fm_radio_tuner.reset_standby();
     // fm_radio_tuner.set_soft_mute();
     // fm_radio_tuner.search_up();
 
+    if let Err(err) = spawn_event_server(CONFIG.cors_origin) {
+        warn!("Unable to serve events: {:?}", err);
+    }
     warn!("Server awaiting connection");
+    // Booted fine, keep this firmware in case the bootloader rolls back failed updates
+    if let Err(err) = EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        warn!("Unable to mark the firmware as valid: {:?}", err);
+    }
+    if mp3_decoder.lock().unwrap().playback_state() == PlaybackState::Playing {
+        set_status(&led, LedStatus::Playing);
+    }
 
+    // The webradio threads feed the decoder, the sink sets the LED and the display refreshes
+    // itself: the main task only follows what happens and ticks the clock, never holding
+    // the decoder
+    let events = EVENTS.subscribe();
+    let mut next_tick = Instant::now();
     loop {
-        // Obtain System Time
-        let st_now = SystemTime::now();
-        // Convert to UTC Time
-        let dt_now_utc: DateTime<Utc> = st_now.into();
-        // Format Time String
-        let formatted = format!("{}", dt_now_utc.format("%d/%m/%Y %H:%M:%S"));
-        // Print Time
-        info!("Time: {}", formatted);
-        sleep(Duration::from_millis(1000));
+        match events.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+            Ok(event) => debug!("{:?}", event),
+            Err(RecvTimeoutError::Timeout) => {
+                match ntp.current_time() {
+                    Some(time) => info!("Time: {}", time.format("%d/%m/%Y %H:%M:%S")),
+                    None => info!("Time: unknown, waiting for NTP"),
+                }
+                next_tick += CLOCK_TICK_INTERVAL;
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!("The event bus is static"),
+        }
+    }
+}
+
+/// Starts the HTTP server, its `Configuration` being sized for the handlers of `main`.
+fn start_http_server() -> Result<EspHttpServer<'static>> {
+    let configuration = Configuration {
+        // Lets a single handler answer the CORS preflight of every URI
+        uri_match_wildcard: true,
+        max_uri_handlers: HTTP_MAX_URI_HANDLERS,
+        stack_size: HTTP_STACK_SIZE,
+        max_open_sockets: HTTP_MAX_OPEN_SOCKETS,
+        ..Default::default()
+    };
+    info!(
+        "HTTP server: {} URI handlers, {} bytes of stack, {} sockets",
+        HTTP_MAX_URI_HANDLERS, HTTP_STACK_SIZE, HTTP_MAX_OPEN_SOCKETS
+    );
+    EspHttpServer::new(&configuration)
+        .map_err(|err| anyhow::anyhow!("Unable to start the HTTP server: {:?}", err))
+}
+
+/// Addresses answering on the bus, probed with an empty write.
+fn scan_i2c(i2c: &mut I2cDriver<'_>) -> Vec<u8> {
+    let timeout = TickType::from(I2C_PROBE_TIMEOUT).ticks();
+    let devices: Vec<u8> = I2C_SCAN_ADDRESSES
+        .filter(|address| i2c.write(*address, &[], timeout).is_ok())
+        .collect();
+    let found: Vec<String> = devices
+        .iter()
+        .map(|address| format!("{:#04x}", address))
+        .collect();
+    info!("I2C devices: [{}]", found.join(", "));
+    devices
+}
+
+fn query_param<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Decodes a query string value, `+` and `%XX` escapes included.
+fn url_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [input.next().unwrap_or(b'0'), input.next().unwrap_or(b'0')];
+                let decoded = str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                bytes.push(decoded.unwrap_or(b'?'));
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Checks a file starts with an ID3v2 tag or an MPEG audio layer III frame header.
+fn is_mp3(header: &[u8; 4]) -> bool {
+    if header.starts_with(b"ID3") {
+        return true;
+    }
+    let sync = header[0] == 0xFF && header[1] & 0xE0 == 0xE0;
+    let version = (header[1] >> 3) & 0b11; // 0b01 is reserved
+    let layer = (header[1] >> 1) & 0b11; // 0b01 is layer III
+    let bitrate = header[2] >> 4; // 0b1111 is invalid
+    let sample_rate = (header[2] >> 2) & 0b11; // 0b11 is reserved
+    sync && version != 0b01 && layer == 0b01 && bitrate != 0b1111 && sample_rate != 0b11
+}
+
+/// Applies what was requested from the front panel buttons or knob.
+fn on_control(player: &Player, control: Control) {
+    let result = match control {
+        Control::PlayPause => {
+            let playing = player.decoder.lock().unwrap().playback_state() == PlaybackState::Playing;
+            player.set_paused(playing).map(|_| ())
+        }
+        Control::Next => player.step_station(1),
+        Control::Previous => player.step_station(-1),
+        Control::VolumeUp => player
+            .adjust_volume(VOLUME_STEP.into())
+            .and_then(|volume| player.save_volume(volume)),
+        Control::VolumeDown => player
+            .adjust_volume(-i16::from(VOLUME_STEP))
+            .and_then(|volume| player.save_volume(volume)),
+        Control::Mute => player.toggle_mute().map(|_| ()),
+        // Saved once the knob settles, not to wear the flash on every detent
+        Control::KnobTurned(detents) => player.adjust_volume(detents.into()).map(|_| ()),
+        Control::KnobSettled => {
+            let volume = player.decoder.lock().unwrap().get_volume();
+            player.save_volume(volume)
+        }
+    };
+    if let Err(err) = result {
+        warn!("Front panel {:?} failed: {:?}", control, err);
+    }
+}
+
+/// Applies a command received from the MQTT broker.
+fn on_mqtt_command(player: &Player, command: MqttCommand) {
+    let result = match command {
+        MqttCommand::Station(station) => {
+            if Station::get_name_from_id(&station).is_none() {
+                warn!("Unknown station {:?}", station);
+                return;
+            }
+            // Tuned on FM when it has no web URL
+            player.switch_station(&station, true)
+        }
+        MqttCommand::Volume(volume) => player
+            .set_volume(volume)
+            .and_then(|volume| player.save_volume(volume)),
+        MqttCommand::Paused(paused) => player.set_paused(paused).map(|_| ()),
+    };
+    if let Err(err) = result {
+        warn!("MQTT command failed: {:?}", err);
+    }
+}
+
+/// Applies a command received on the `/ws` WebSocket.
+fn on_ws_command(player: &Player, command: WsCommand) -> Result<()> {
+    match command {
+        WsCommand::SetStation { station } => {
+            if Station::get_name_from_id(&station).is_none() {
+                return Err(anyhow::anyhow!("Unknown station {:?}", station));
+            }
+            // Tuned on FM when it has no web URL
+            player.switch_station(&station, true)
+        }
+        WsCommand::SetVolume { volume } => player
+            .set_volume(volume)
+            .and_then(|volume| player.save_volume(volume)),
+        WsCommand::Play => player.set_paused(false).map(|_| ()),
+        WsCommand::Pause => player.set_paused(true).map(|_| ()),
+    }
+}
+
+/// Silences the decoder before a panic takes the firmware down, instead of leaving it
+/// screeching on a half sent chunk.
+fn install_panic_hook(decoder: Arc<Mutex<Mp3Decoder>>, chip_select_pins: [i32; 2]) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        match decoder.try_lock() {
+            Ok(mut decoder) => decoder.shutdown(),
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().shutdown(),
+            // Possibly held by the panicking thread itself, only release the chip selects
+            Err(TryLockError::WouldBlock) => {
+                for pin in chip_select_pins {
+                    unsafe { gpio_set_level(pin, 1) };
+                }
+            }
+        }
+        default_hook(info);
+    }));
+}
+
+/// Starts a JSON response, readable by the page at `CONFIG.cors_origin`.
+fn json_response<C: Connection>(
+    req: Request<C>,
+    status: u16,
+) -> core::result::Result<Response<C>, C::Error> {
+    req.into_response(
+        status,
+        None,
+        &[
+            ("Content-Type", "application/json"),
+            ("Access-Control-Allow-Origin", CONFIG.cors_origin),
+        ],
+    )
+}
+
+/// Starts a plain response, so cross-origin callers can also read the errors.
+fn status_response<C: Connection>(
+    req: Request<C>,
+    status: u16,
+) -> core::result::Result<Response<C>, C::Error> {
+    req.into_response(
+        status,
+        None,
+        &[("Access-Control-Allow-Origin", CONFIG.cors_origin)],
+    )
+}
+
+/// Checks the credentials, then reads and parses the JSON body of at most `max_len` bytes.
+///
+/// Answers 401, 413 or 400 itself and returns `None`, the handler then only has to return.
+fn read_authorized_json<'r, 'c, T: DeserializeOwned>(
+    mut req: Request<&'r mut EspHttpConnection<'c>>,
+    credentials: &Credentials,
+    max_len: usize,
+) -> Result<Option<(Request<&'r mut EspHttpConnection<'c>>, T)>> {
+    if !credentials.is_authorized(req.header("Authorization")) {
+        unauthorized_response(req)?.write_all("Unauthorized".as_bytes())?;
+        return Ok(None);
+    }
+    let len = req.content_len().unwrap_or(0) as usize;
+    if len > max_len {
+        status_response(req, 413)?.write_all("Request too big".as_bytes())?;
+        return Ok(None);
+    }
+    let mut buf = vec![0; len];
+    req.read_exact(&mut buf)?;
+    match serde_json::from_slice(&buf) {
+        Ok(data) => Ok(Some((req, data))),
+        Err(err) => {
+            debug!("Invalid JSON body: {:?}", err);
+            json_response(req, 400)?.write_all(&serde_json::to_vec(&ErrorData {
+                error: "JSON error",
+            })?)?;
+            Ok(None)
+        }
+    }
+}
 
-        // if (client.available() > 0) {
-        //     // The buffer size 64 seems to be optimal. At 32 and 128 the sound might be brassy.
-        //     uint8_t bytesread = client.read(mp3buff, 64);
-        //     player.playChunk(mp3buff, bytesread);
-        // }
+/// Asks the browser for the Basic Auth credentials.
+fn unauthorized_response<C: Connection>(
+    req: Request<C>,
+) -> core::result::Result<Response<C>, C::Error> {
+    req.into_response(
+        401,
+        None,
+        &[
+            ("WWW-Authenticate", AUTH_REALM_HEADER),
+            ("Access-Control-Allow-Origin", CONFIG.cors_origin),
+        ],
+    )
+}
+
+/// Reads the saved configuration, sized from the stored blob so a bigger one isn't dropped.
+fn read_config(nvs: &EspNvs<NvsDefault>) -> Option<LastConfiguration> {
+    let len = match nvs.blob_len(CONFIG_NVS_KEY) {
+        Ok(Some(len)) => len,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("Couldn't get key {} because {:?}", CONFIG_NVS_KEY, e);
+            return None;
+        }
+    };
+    let mut buf = vec![0; len];
+    match nvs.get_raw(CONFIG_NVS_KEY, &mut buf) {
+        Ok(Some(data)) => from_bytes::<LastConfiguration>(data)
+            .map_err(|e| warn!("Converting {:?} failed because: {:?}", data, e))
+            .ok(),
+        Ok(None) => None,
+        Err(e) => {
+            warn!("Couldn't get key {} because {:?}", CONFIG_NVS_KEY, e);
+            None
+        }
     }
 }
 
+fn store_last_configuration(
+    nvs: &mut EspNvs<NvsDefault>,
+    key: &str,
+    configuration: &LastConfiguration,
+) {
+    let data = match to_vec::<LastConfiguration, CONFIG_BLOB_SIZE>(configuration) {
+        Ok(data) => data,
+        Err(e) => {
+            error!(
+                "Key {} not updated, {:?} doesn't fit in {} bytes: {:?}",
+                key, configuration, CONFIG_BLOB_SIZE, e
+            );
+            return;
+        }
+    };
+    match nvs.set_raw(key, &data) {
+        Ok(_) => info!("Key {} updated", key),
+        Err(e) => info!("key {} not updated {:?}", key, e),
+    };
+}
+
 fn templated(content: impl AsRef<str>) -> String {
     format!(
         r#"
@@ -393,8 +2712,47 @@ fn templated(content: impl AsRef<str>) -> String {
     )
 }
 
-fn index_html() -> String {
-    templated("Hello from ISS!")
+/// Landing page, with what is playing when it was requested.
+fn index_html(station_name: &str, volume: u8, time: Option<DateTime<Local>>) -> String {
+    let clock = time.map_or("--:--".to_string(), |time| {
+        time.format("%d/%m/%Y %H:%M").to_string()
+    });
+    templated(format!(
+        r#"<h1>Rustdio</h1>
+        <p>Station: {}</p>
+        <p>Volume: {}%</p>
+        <p>Time: {}</p>
+        <ul>
+            <li><a href="/radio">Control the radio</a></li>
+            <li><a href="/status">Status</a></li>
+            <li><a href="/metrics">Metrics</a></li>
+        </ul>"#,
+        html_escape(station_name),
+        volume,
+        clock
+    ))
+}
+
+/// Control page with the `<option>` of every station, user ones included.
+fn control_radio_html() -> String {
+    let options: String = Station::all()
+        .iter()
+        .map(|station| {
+            format!(
+                "    <option value=\"{}\">{}</option>\n",
+                html_escape(station.id),
+                html_escape(station.name)
+            )
+        })
+        .collect();
+    CONTROL_RADIO_HTML.replace("<!-- station options -->\n", &options)
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 // fn fm_frequency_page(val: f32) -> String {
@@ -0,0 +1,215 @@
+use crate::events::EVENTS;
+use crate::vs1053::PlaybackState;
+use anyhow::Result;
+use esp_idf_svc::{
+    mqtt::client::{
+        EspMqttClient, EspMqttConnection, EventPayload, LwtConfiguration, MqttClientConfiguration,
+        QoS,
+    },
+    nvs::{EspNvs, NvsDefault},
+    sys::EspError,
+};
+use log::{info, warn};
+pub use rustdio_core::mqtt::MqttCommand;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+const MQTT_NVS_KEY: &str = "mqtt";
+const MAX_CONFIG_LEN: usize = 512;
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+const MQTT_THREAD_STACK_SIZE: usize = 6 * 1024;
+
+/// Broker to publish the state to, stored as JSON in the NVS.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MqttConfig {
+    /// e.g. `mqtt://192.168.1.10:1883`, MQTT being disabled when empty
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The state is published to `<prefix>/state`, the commands read from `<prefix>/set/...`
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+}
+
+fn default_topic_prefix() -> String {
+    "rustdio".to_string()
+}
+
+impl MqttConfig {
+    /// `None` when no broker was configured.
+    pub fn load_from_nvs(nvs: &EspNvs<NvsDefault>) -> Option<Self> {
+        let mut buf = [0; MAX_CONFIG_LEN];
+        let config: MqttConfig = match nvs.get_raw(MQTT_NVS_KEY, &mut buf) {
+            Ok(Some(data)) => serde_json::from_slice(data)
+                .map_err(|e| warn!("Converting {} failed because: {:?}", MQTT_NVS_KEY, e))
+                .ok()?,
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Couldn't get key {} because {:?}", MQTT_NVS_KEY, e);
+                return None;
+            }
+        };
+        (!config.url.is_empty()).then_some(config)
+    }
+
+    /// Replaces the stored broker, an empty `url` disabling MQTT from the next boot.
+    pub fn save_to_nvs(&self, nvs: &mut EspNvs<NvsDefault>) -> Result<(), EspError> {
+        if self.url.is_empty() {
+            nvs.remove(MQTT_NVS_KEY)?;
+            return Ok(());
+        }
+        // Serializing strings can't fail
+        nvs.set_raw(MQTT_NVS_KEY, &serde_json::to_vec(self).unwrap())?;
+        Ok(())
+    }
+}
+
+/// Published, retained, to `<prefix>/state` on every change.
+#[derive(Serialize)]
+pub struct MqttState {
+    pub station_id: String,
+    pub station_name: Option<String>,
+    pub volume: u8,
+    pub playback: PlaybackState,
+    /// ICY title of the webradio
+    pub title: Option<String>,
+}
+
+/// Publishes what `state` returns on connection and whenever an event is published on the
+/// bus, and calls `on_command` for each command received.
+///
+/// The client reconnects by itself after `RECONNECT_DELAY`, `<prefix>/availability` telling
+/// whether the radio is connected.
+pub fn spawn_mqtt<S, C>(
+    config: MqttConfig,
+    state: S,
+    mut on_command: C,
+) -> Result<[JoinHandle<()>; 2]>
+where
+    S: FnMut() -> MqttState + Send + 'static,
+    C: FnMut(MqttCommand) + Send + 'static,
+{
+    let topics = Topics::new(&config.topic_prefix);
+    let (client, connection) = EspMqttClient::new(
+        &config.url,
+        &MqttClientConfiguration {
+            client_id: Some("rustdio"),
+            username: config.username.as_deref(),
+            password: config.password.as_deref(),
+            reconnect_timeout: Some(RECONNECT_DELAY),
+            lwt: Some(LwtConfiguration {
+                topic: &topics.availability,
+                payload: b"offline",
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            ..Default::default()
+        },
+    )?;
+    info!("MQTT broker {}", config.url);
+    let client = Arc::new(Mutex::new(client));
+    let state = Arc::new(Mutex::new(state));
+
+    let connection_client = client.clone();
+    let connection_state = state.clone();
+    let connection_topics = topics.clone();
+    let connection_thread = thread::Builder::new()
+        .name("mqtt".into())
+        .stack_size(MQTT_THREAD_STACK_SIZE)
+        .spawn(move || {
+            handle_connection(
+                connection,
+                &connection_client,
+                &connection_state,
+                &connection_topics,
+                &mut on_command,
+            )
+        })?;
+
+    let events = EVENTS.subscribe();
+    let state_thread = thread::Builder::new()
+        .name("mqtt-state".into())
+        .stack_size(MQTT_THREAD_STACK_SIZE)
+        .spawn(move || {
+            // Every event changes something in the state, which is published whole
+            for _event in events.iter() {
+                let current_state = (state.lock().unwrap())();
+                publish_state(&mut client.lock().unwrap(), &topics.state, &current_state);
+            }
+        })?;
+    Ok([connection_thread, state_thread])
+}
+
+#[derive(Clone)]
+struct Topics {
+    prefix: String,
+    state: String,
+    availability: String,
+    commands: String,
+}
+
+impl Topics {
+    fn new(prefix: &str) -> Self {
+        Topics {
+            prefix: prefix.to_string(),
+            state: format!("{prefix}/state"),
+            availability: format!("{prefix}/availability"),
+            commands: format!("{prefix}/set/+"),
+        }
+    }
+}
+
+fn publish_state(client: &mut EspMqttClient<'static>, topic: &str, state: &MqttState) {
+    let Ok(payload) = serde_json::to_vec(state) else {
+        return;
+    };
+    if let Err(err) = client.publish(topic, QoS::AtMostOnce, true, &payload) {
+        warn!("Unable to publish the MQTT state: {:?}", err);
+    }
+}
+
+fn handle_connection(
+    mut connection: EspMqttConnection,
+    client: &Mutex<EspMqttClient<'static>>,
+    state: &Mutex<impl FnMut() -> MqttState>,
+    topics: &Topics,
+    on_command: &mut impl FnMut(MqttCommand),
+) {
+    while let Ok(event) = connection.next() {
+        match event.payload() {
+            EventPayload::Connected(_) => {
+                info!("MQTT connected");
+                // Taken before the client, the state locking the player
+                let current_state = (state.lock().unwrap())();
+                let mut client = client.lock().unwrap();
+                // Subscriptions are lost with the session, they are made again on reconnection
+                if let Err(err) = client.subscribe(&topics.commands, QoS::AtLeastOnce) {
+                    warn!("Unable to subscribe to {}: {:?}", topics.commands, err);
+                }
+                let _ = client.publish(&topics.availability, QoS::AtLeastOnce, true, b"online");
+                publish_state(&mut client, &topics.state, &current_state);
+            }
+            EventPayload::Disconnected => warn!("MQTT disconnected, reconnecting"),
+            EventPayload::Received {
+                topic: Some(topic),
+                data,
+                ..
+            } => match MqttCommand::parse(&topics.prefix, topic, data) {
+                Some(command) => {
+                    info!("MQTT command {:?}", command);
+                    on_command(command);
+                }
+                None => warn!("Invalid MQTT command on {}", topic),
+            },
+            EventPayload::Error(err) => warn!("MQTT error: {:?}", err),
+            _ => {}
+        }
+    }
+    info!("MQTT connection closed");
+}
@@ -1,110 +1,163 @@
-// use chrono::{DateTime, Utc};
-// use futures::{pin_mut, prelude::*, select};
-// use std::{sync::Mutex, time::Duration};
-// use stoppable_thread::{SimpleAtomicBool, StoppableHandle};
+use chrono::{DateTime, Datelike, FixedOffset, Local, Utc};
+use esp_idf_svc::{
+    sntp::{EspSntp, SntpConf, SyncStatus},
+    sys::{esp_sntp_getreachability, tzset, EspError},
+};
+use std::{
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use stoppable_thread::StoppableHandle;
 
-// use crate::ProgramAppState;
+const NTP_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+const STOPPING_INTERVAL: Duration = Duration::from_secs(1);
+const SYNC_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Earlier times can only come from a clock that was never set
+const MIN_VALID_YEAR: i32 = 2024;
+/// Fallbacks of the configured servers, only the first `CONFIG_LWIP_SNTP_MAX_SERVERS` of
+/// both being used.
+const NTP_SERVERS: &[&str] = &[
+    "pool.ntp.org",
+    "time.google.com",
+    "time.cloudflare.com",
+    "time.windows.com",
+];
 
-// const NTP_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
-// const STOPPING_INTERVAL: Duration = Duration::from_secs(1);
-// pub struct Ntp {
-//     local_time_offset: Mutex<chrono::Duration>,
-// }
+pub struct Ntp {
+    sntp: Mutex<Option<EspSntp<'static>>>,
+    /// In the order they are tried
+    servers: Vec<&'static str>,
+}
 
-// impl Default for Ntp {
-//     fn default() -> Self {
-//         Self::new()
-//     }
-// }
+//https://dev.to/theembeddedrustacean/edge-iot-with-rust-on-esp-ntp-3llk
 
-// //https://dev.to/theembeddedrustacean/edge-iot-with-rust-on-esp-ntp-3llk
+impl Ntp {
+    /// Starts synchronizing in the background, see `sync_blocking` to wait for the time.
+    ///
+    /// `utc_offset` is the local timezone used by `current_local_time`. The `servers` are
+    /// tried before the public `NTP_SERVERS`, e.g. a LAN server where the pools are blocked.
+    pub fn new(utc_offset: FixedOffset, servers: &[&'static str]) -> Result<Self, EspError> {
+        set_timezone(utc_offset);
+        let mut all_servers: Vec<&'static str> = Vec::new();
+        for server in servers.iter().chain(NTP_SERVERS) {
+            if !server.is_empty() && !all_servers.contains(server) {
+                all_servers.push(server);
+            }
+        }
+        Ok(Ntp {
+            sntp: Mutex::new(Some(start_sntp(&all_servers)?)),
+            servers: all_servers,
+        })
+    }
 
-// impl Ntp {
-//     /// This will create a new updated instance of NTP, which will block.
-//     pub fn new() -> Self {
-//         let instance = Ntp {
-//             local_time_offset: Mutex::new(chrono::Duration::zero()),
-//         };
-//         instance.update_offset();
-//         instance
-//     }
+    /// Waits up to `timeout` for the first synchronization, returns whether it completed.
+    ///
+    /// SNTP keeps trying afterwards, the clock being set whenever a server answers.
+    pub fn sync_blocking(&self, timeout: Duration) -> bool {
+        match self.current_time() {
+            Some(time) => log::info!("Synchronizing with NTP Server, RTC time is {time}"),
+            None => log::info!("Synchronizing with NTP Server"),
+        }
+        let start = Instant::now();
+        loop {
+            let status = self
+                .sntp
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|sntp| sntp.get_sync_status());
+            match status {
+                Some(SyncStatus::Completed) => break,
+                Some(_) if start.elapsed() < timeout => sleep(SYNC_POLL_INTERVAL),
+                Some(_) => {
+                    log::warn!("NTP not synchronized after {:?}, going on", timeout);
+                    return false;
+                }
+                None => return false,
+            }
+        }
+        match self.reachable_server() {
+            Some(server) => log::info!("NTP Time Sync Completed with {server}"),
+            None => log::info!("NTP Time Sync Completed"),
+        }
+        true
+    }
 
-//     pub fn start_time_thread(&self, state: ProgramAppState) -> StoppableHandle<()> {
-//         stoppable_thread::spawn(|stopped| {
-//             Runtime::new()
-//                 .unwrap()
-//                 .block_on(Ntp::ntp_update_loop(stopped, state))
-//         })
-//     }
+    /// First server that answered the last polls.
+    fn reachable_server(&self) -> Option<&'static str> {
+        self.servers
+            .iter()
+            .take(SntpConf::default().servers.len())
+            .enumerate()
+            .find(|(index, _)| unsafe { esp_sntp_getreachability(*index as u8) } != 0)
+            .map(|(_, server)| *server)
+    }
 
-//     async fn ntp_update_loop(stopped: &SimpleAtomicBool, state: ProgramAppState) {
-//         let mut ntp_update_interval = interval(NTP_REFRESH_INTERVAL);
-//         let mut stopped_interval = interval(STOPPING_INTERVAL);
-//         while !stopped.get() {
-//             let ntp_update_tick = ntp_update_interval.tick().fuse();
-//             let stopped_tick = stopped_interval.tick().fuse();
-//             pin_mut!(ntp_update_tick, stopped_tick);
+    /// Resynchronizes the time every `NTP_REFRESH_INTERVAL`, until the handle is stopped.
+    pub fn start_time_thread(self: &Arc<Self>) -> StoppableHandle<()> {
+        let ntp = self.clone();
+        stoppable_thread::spawn(move |stopped| {
+            let mut last_update = Instant::now();
+            while !stopped.get() {
+                if last_update.elapsed() >= NTP_REFRESH_INTERVAL {
+                    ntp.update();
+                    last_update = Instant::now();
+                }
+                sleep(STOPPING_INTERVAL);
+            }
+        })
+    }
 
-//             // tokio::select! {
-//             //     _ = ntp_update_tick => {
-//             //         state.ntp.update_offset();
-//             //     },
-//             //     _ = stopped_tick => {}
-//             // }
-//             // select! {
-//             //     _ = ntp_update_tick => {
-//             //         state.ntp.update_offset();
-//             //     },
-//             //     _ = stopped_tick => {}
-//             // }
-//         }
-//     }
+    /// Restarts SNTP, which triggers an immediate synchronization.
+    fn update(&self) {
+        log::debug!("Updating NTP time");
+        let mut sntp = self.sntp.lock().unwrap();
+        // Only one SNTP instance may exist at a time
+        *sntp = None;
+        match start_sntp(&self.servers) {
+            Ok(new_sntp) => *sntp = Some(new_sntp),
+            Err(error) => log::error!("NTP update failed: {error:?}"),
+        }
+    }
 
-//     /// Updates Ntp offset from one of the servers.
-//     fn update_offset(&self) {
-//         const NTP_SERVERS: &[&str] = &[
-//             "pool.ntp.org:123",
-//             "time.nist.gov:123",
-//             "time.google.com:123",
-//             "time.windows.com:123",
-//             "ntp.ubuntu.com:123",
-//         ];
+    pub fn current_local_time(&self) -> DateTime<Local> {
+        Utc::now().with_timezone(&Local)
+    }
 
-//         log::debug!("Updating NTP time");
-//         let client = rsntp::SntpClient::new();
+    /// Local time, `None` until it was set once.
+    ///
+    /// The system clock is kept by the RTC between NTP synchronizations, so the time stays
+    /// right while the WiFi is down, and across software resets as the RTC keeps running.
+    pub fn current_time(&self) -> Option<DateTime<Local>> {
+        local_time()
+    }
+}
 
-//         let mut sync_res = None;
-//         for server in NTP_SERVERS {
-//             match client.synchronize(server) {
-//                 Ok(res) => {
-//                     sync_res = Some(res);
-//                     break;
-//                 }
-//                 Err(error) => log::warn!("Ntp update from {server} failed: {error:?}"),
-//             }
-//         }
-//         let Some(sync_res) = sync_res else {
-//             log::error!("NTP update failed from all servers");
-//             return;
-//         };
+/// Same as `Ntp::current_time`, for the modules without the `Ntp`.
+pub fn local_time() -> Option<DateTime<Local>> {
+    let now = Utc::now().with_timezone(&Local);
+    (now.year() >= MIN_VALID_YEAR).then_some(now)
+}
 
-//         let sntp_res = sync_res.clock_offset();
-//         let offset_duration = sntp_res.into_chrono_duration().unwrap() * sntp_res.signum();
+fn start_sntp(servers: &[&'static str]) -> Result<EspSntp<'static>, EspError> {
+    let mut conf = SntpConf::default();
+    for (slot, server) in conf.servers.iter_mut().zip(servers) {
+        *slot = server;
+    }
+    EspSntp::new(&conf)
+}
 
-//         log::info!("Offset from NTP is: {offset_duration}");
-
-//         let Ok(mut offset) = self.local_time_offset.lock() else {
-//             log::warn!("Failure of locking offset while setting current_time");
-//             return;
-//         };
-//         *offset = offset_duration;
-//     }
-
-//     pub fn current_time(&self) -> DateTime<Utc> {
-//         let Ok(offset) = self.local_time_offset.lock() else {
-//             log::error!("Failure of locking offset while getting current_time");
-//             return Utc::now();
-//         };
-//         Utc::now().checked_add_signed(*offset).unwrap()
-//     }
-// }
+/// Sets the `TZ` used by `Local`, POSIX offsets being west of UTC.
+fn set_timezone(utc_offset: FixedOffset) {
+    let seconds = utc_offset.local_minus_utc();
+    let sign = if seconds > 0 { '-' } else { '+' };
+    let tz = format!(
+        "UTC{sign}{:02}:{:02}",
+        seconds.abs() / 3600,
+        seconds.abs() % 3600 / 60
+    );
+    log::info!("Timezone set to {tz}");
+    std::env::set_var("TZ", tz);
+    unsafe { tzset() };
+}
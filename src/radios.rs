@@ -1,146 +1,191 @@
-pub struct Station<'a> {
-    pub id: &'a str,
-    pub name: &'a str,
-    pub fm_frequency: f32,
-    pub web_url: &'a str,
+use esp_idf_svc::{
+    nvs::{EspNvs, NvsDefault},
+    sys::{EspError, ESP_ERR_NVS_NOT_ENOUGH_SPACE, ESP_ERR_NVS_VALUE_TOO_LONG},
+};
+use log::{info, warn};
+use rustdio_core::radios::USER_STATIONS;
+pub use rustdio_core::radios::{Station, StationData, FM_BAND_MAX_MHZ, FM_BAND_MIN_MHZ};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+
+const STATIONS_NVS_KEY: &str = "stations";
+const FAVORITES_NVS_KEY: &str = "favorites";
+const VOLUMES_NVS_KEY: &str = "volumes";
+
+#[derive(Debug)]
+pub enum StationError {
+    AlreadyExists,
+    NotFound,
+    NoSpace,
+    Nvs(EspError),
+    Json(serde_json::Error),
 }
 
-static STATIONS: [Station; 18] = [
-    Station {
-        id: "bfm_business",
-        name: "BFM Business",
-        fm_frequency: 96.4,
-        web_url: "",
-    },
-    Station {
-        id: "cherie_fm",
-        name: "Cherie FM",
-        fm_frequency: 91.3,
-        web_url: "",
-    },
-    Station {
-        id: "europe_1",
-        name: "Europe 1",
-        fm_frequency: 104.7,
-        web_url: "",
-    },
-    Station {
-        id: "europe_2",
-        name: "Europe 2",
-        fm_frequency: 103.5,
-        web_url: "http://europe2.lmn.fm/europe2.mp3",
-    },
-    Station {
-        id: "fip",
-        name: "FIP",
-        fm_frequency: 105.1,
-        web_url: "http://icecast.radiofrance.fr/fip-hifi.aac",
-    },
-    Station {
-        id: "france_info",
-        name: "France Info",
-        fm_frequency: 105.5,
-        web_url: "http://icecast.radiofrance.fr/franceinfo-hifi.aac",
-    },
-    Station {
-        id: "france_inter",
-        name: "France Inter",
-        fm_frequency: 87.6,
-        web_url: "",
-    },
-    Station {
-        id: "france_inter_2",
-        name: "France Inter Test 2",
-        fm_frequency: 87.8,
-        web_url: "",
-    },
-    Station {
-        id: "le_mouv",
-        name: "Le Mouv",
-        fm_frequency: 92.1,
-        web_url: "",
-    },
-    Station {
-        id: "nostalgie",
-        name: "Nostalgie",
-        fm_frequency: 90.4,
-        web_url: "https://scdn.nrjaudio.fm/adwz2/fr/30601/mp3_128.mp3",
-    },
-    Station {
-        id: "nrj",
-        name: "NRJ",
-        fm_frequency: 100.3,
-        web_url: "https://scdn.nrjaudio.fm/adwz2/fr/30001/mp3_128.mp3",
-    },
-    Station {
-        id: "radio_enghien",
-        name: "Station Enghien",
-        fm_frequency: 98.0,
-        web_url: "",
-    },
-    Station {
-        id: "rfm",
-        name: "RFM",
-        fm_frequency: 103.9,
-        web_url: "http://stream.rfm.fr/rfm.mp3",
-    },
-    Station {
-        id: "rire_et_chansons",
-        name: "Rire & Chansons",
-        fm_frequency: 97.4,
-        web_url: "https://scdn.nrjaudio.fm/adwz2/fr/30401/mp3_128.mp3",
-    },
-    Station {
-        id: "rmc",
-        name: "RMC",
-        fm_frequency: 103.1,
-        web_url: "http://audio.bfmtv.com/rmcradio_128.mp3",
-    },
-    Station {
-        id: "rtl",
-        name: "RTL",
-        fm_frequency: 104.3,
-        web_url: "http://icecast.rtl.fr/rtl-1-44-128?listen=webCwsBCggNCQgLDQUGBAcGBg",
-    },
-    Station {
-        id: "rtl2",
-        name: "RL2",
-        fm_frequency: 105.9,
-        web_url: "http://icecast.rtl2.fr/rtl2-1-44-128?listen=webCwsBCggNCQgLDQUGBAcGBg",
-    },
-    Station {
-        id: "tsf_jazz",
-        name: "TSF Jazz",
-        fm_frequency: 1.0,
-        web_url: "https://tsfjazz.ice.infomaniak.ch/tsfjazz-high.mp3",
-    },
-];
+/// Persistence in NVS of the user stations, the favorites and the volume set on each station.
+pub trait StationStore {
+    /// Volume to switch to `id` at: the one last set on it, or its `default_volume`.
+    fn volume_from_nvs(nvs: &EspNvs<NvsDefault>, id: &str) -> Option<u8>;
+    /// Remembers the volume the user set on `id`, overriding its `default_volume`.
+    fn save_volume_to_nvs(
+        nvs: &mut EspNvs<NvsDefault>,
+        id: &str,
+        volume: u8,
+    ) -> Result<(), StationError>;
+    /// Reads the user stations, returns an empty list if none were stored or they are unreadable.
+    fn load_from_nvs(nvs: &EspNvs<NvsDefault>) -> Vec<StationData>;
+    fn save_to_nvs(nvs: &mut EspNvs<NvsDefault>, stations: &[&Station])
+        -> Result<(), StationError>;
+    /// Favorite stations in the order they were added, skipping the ones that no longer exist.
+    fn favorites(nvs: &EspNvs<NvsDefault>) -> Vec<&'static Station<'static>>;
+    /// Adds or removes a favorite, adding it twice or removing a non-favorite changes nothing.
+    fn set_favorite(
+        nvs: &mut EspNvs<NvsDefault>,
+        id: &str,
+        favorite: bool,
+    ) -> Result<(), StationError>;
+    /// Merges the stored user stations with the built-in ones, skipping already known ids.
+    fn merge_user_stations(stations: Vec<StationData>);
+    /// Adds a user station and persists the whole user list.
+    fn add_to_nvs(
+        nvs: &mut EspNvs<NvsDefault>,
+        station: StationData,
+    ) -> Result<&'static Station<'static>, StationError>;
+}
 
-impl Station<'_> {
-    pub fn get_name_from_id(id: &str) -> Option<&str> {
-        for station in &STATIONS {
-            if station.id == id {
-                return Some(station.name);
-            }
+impl StationStore for Station<'_> {
+    fn volume_from_nvs(nvs: &EspNvs<NvsDefault>, id: &str) -> Option<u8> {
+        load_json::<BTreeMap<String, u8>>(nvs, VOLUMES_NVS_KEY)
+            .get(id)
+            .copied()
+            .or_else(|| Self::find(id)?.default_volume)
+    }
+
+    fn save_volume_to_nvs(
+        nvs: &mut EspNvs<NvsDefault>,
+        id: &str,
+        volume: u8,
+    ) -> Result<(), StationError> {
+        if Self::find(id).is_none() {
+            return Err(StationError::NotFound);
+        }
+        let mut volumes: BTreeMap<String, u8> = load_json(nvs, VOLUMES_NVS_KEY);
+        if volumes.insert(id.to_string(), volume) == Some(volume) {
+            return Ok(());
         }
-        None
+        save_json(nvs, VOLUMES_NVS_KEY, &volumes)
     }
 
-    pub fn get_fm_frequency_from_id(id: &str) -> Option<f32> {
-        for station in &STATIONS {
-            if station.id == id {
-                return Some(station.fm_frequency);
+    fn load_from_nvs(nvs: &EspNvs<NvsDefault>) -> Vec<StationData> {
+        load_json(nvs, STATIONS_NVS_KEY)
+    }
+
+    fn save_to_nvs(
+        nvs: &mut EspNvs<NvsDefault>,
+        stations: &[&Station],
+    ) -> Result<(), StationError> {
+        save_json(nvs, STATIONS_NVS_KEY, stations)
+    }
+
+    fn favorites(nvs: &EspNvs<NvsDefault>) -> Vec<&'static Station<'static>> {
+        load_json::<Vec<String>>(nvs, FAVORITES_NVS_KEY)
+            .iter()
+            .filter_map(|id| Self::find(id))
+            .collect()
+    }
+
+    fn set_favorite(
+        nvs: &mut EspNvs<NvsDefault>,
+        id: &str,
+        favorite: bool,
+    ) -> Result<(), StationError> {
+        let mut ids: Vec<String> = load_json(nvs, FAVORITES_NVS_KEY);
+        let position = ids.iter().position(|favorite_id| favorite_id == id);
+        match (favorite, position) {
+            (true, None) => {
+                if Self::find(id).is_none() {
+                    return Err(StationError::NotFound);
+                }
+                ids.push(id.to_string());
+            }
+            (false, Some(position)) => {
+                ids.remove(position);
             }
+            _ => return Ok(()),
         }
-        None
+        save_json(nvs, FAVORITES_NVS_KEY, &ids)
     }
 
-    pub fn get_web_url_from_id(id: &str) -> Option<&str> {
-        for station in &STATIONS {
-            if station.id == id {
-                return Some(station.web_url);
+    fn merge_user_stations(stations: Vec<StationData>) {
+        for station in stations {
+            if Self::find(&station.id).is_some() {
+                warn!("Skipping user station {}, id already exists", station.id);
+                continue;
             }
+            USER_STATIONS.lock().unwrap().push(station.leak());
+        }
+        info!(
+            "{} user stations loaded",
+            USER_STATIONS.lock().unwrap().len()
+        );
+    }
+
+    fn add_to_nvs(
+        nvs: &mut EspNvs<NvsDefault>,
+        station: StationData,
+    ) -> Result<&'static Station<'static>, StationError> {
+        if Self::find(&station.id).is_some() {
+            return Err(StationError::AlreadyExists);
+        }
+        let mut user_stations = USER_STATIONS.lock().unwrap();
+        let new_station = station.as_station();
+        let mut stations: Vec<&Station> = user_stations.iter().copied().collect();
+        stations.push(&new_station);
+        Self::save_to_nvs(nvs, &stations)?;
+
+        let station = station.leak();
+        user_stations.push(station);
+        Ok(station)
+    }
+}
+
+/// Reads a JSON blob, returns the default value if it wasn't stored or is unreadable.
+fn load_json<T: DeserializeOwned + Default>(nvs: &EspNvs<NvsDefault>, key: &str) -> T {
+    let len = match nvs.blob_len(key) {
+        Ok(Some(len)) => len,
+        Ok(None) => return T::default(),
+        Err(e) => {
+            warn!("Couldn't get key {} because {:?}", key, e);
+            return T::default();
+        }
+    };
+    let mut buf = vec![0; len];
+    match nvs.get_raw(key, &mut buf) {
+        Ok(Some(data)) => serde_json::from_slice(data).unwrap_or_else(|e| {
+            warn!("Converting {} failed because: {:?}", key, e);
+            T::default()
+        }),
+        Ok(None) => T::default(),
+        Err(e) => {
+            warn!("Couldn't get key {} because {:?}", key, e);
+            T::default()
+        }
+    }
+}
+
+fn save_json<T: Serialize + ?Sized>(
+    nvs: &mut EspNvs<NvsDefault>,
+    key: &str,
+    value: &T,
+) -> Result<(), StationError> {
+    let data = serde_json::to_vec(value).map_err(StationError::Json)?;
+    match nvs.set_raw(key, &data) {
+        Ok(_) => Ok(()),
+        Err(e)
+            if e.code() == ESP_ERR_NVS_NOT_ENOUGH_SPACE as i32
+                || e.code() == ESP_ERR_NVS_VALUE_TOO_LONG as i32 =>
+        {
+            Err(StationError::NoSpace)
         }
-        None
+        Err(e) => Err(StationError::Nvs(e)),
     }
 }
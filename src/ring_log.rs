@@ -0,0 +1,61 @@
+use crate::ntp::local_time;
+use esp_idf_svc::sys::esp_timer_get_time;
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex};
+
+/// Oldest entries are dropped past this many.
+const MAX_ENTRIES: usize = 100;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogKind {
+    Station,
+    Reconnect,
+    Underrun,
+    /// Playback the watchdog found stalled
+    Stall,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LogEntry {
+    /// Local time as RFC 3339, `None` until NTP answered once
+    pub time: Option<String>,
+    pub uptime_secs: u64,
+    pub kind: LogKind,
+    pub message: String,
+}
+
+/// Recent playback history, kept in memory until the next reboot.
+pub struct RingLog {
+    entries: Mutex<VecDeque<LogEntry>>,
+}
+
+impl RingLog {
+    const fn new() -> Self {
+        RingLog {
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, kind: LogKind, message: impl Into<String>) {
+        let entry = LogEntry {
+            time: local_time().map(|time| time.to_rfc3339()),
+            uptime_secs: (unsafe { esp_timer_get_time() } / 1_000_000) as u64,
+            kind,
+            message: message.into(),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Oldest first.
+    pub fn entries(&self) -> Vec<LogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+pub static RING_LOG: RingLog = RingLog::new();
@@ -0,0 +1,65 @@
+use crate::vs1053::{ClockedSpi, DSPError};
+use embedded_hal::spi::{ErrorType, Operation, SpiDevice};
+use esp_idf_hal::{
+    gpio::AnyOutputPin,
+    spi::{config::Config as SpiConfig, SpiDeviceDriver, SpiDriver, SpiError},
+    sys::EspError,
+    units::Hertz,
+};
+use log::warn;
+
+/// A device on a shared SPI bus, whose clock can be changed by adding it back to the bus.
+///
+/// Chip select is left to the caller, as the VS1053 has two of them.
+pub struct SpiBusDevice {
+    bus: &'static SpiDriver<'static>,
+    device: SpiDeviceDriver<'static, &'static SpiDriver<'static>>,
+    hz: u32,
+}
+
+impl SpiBusDevice {
+    pub fn new(bus: &'static SpiDriver<'static>, hz: u32) -> Result<Self, EspError> {
+        Ok(Self {
+            bus,
+            device: add_device(bus, hz)?,
+            hz,
+        })
+    }
+}
+
+fn add_device(
+    bus: &'static SpiDriver<'static>,
+    hz: u32,
+) -> Result<SpiDeviceDriver<'static, &'static SpiDriver<'static>>, EspError> {
+    let config = SpiConfig::default().baudrate(Hertz(hz));
+    SpiDeviceDriver::new(bus, None::<AnyOutputPin>, &config)
+}
+
+impl ErrorType for SpiBusDevice {
+    type Error = SpiError;
+}
+
+impl SpiDevice for SpiBusDevice {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), SpiError> {
+        self.device.transaction(operations)
+    }
+}
+
+impl ClockedSpi for SpiBusDevice {
+    fn clock(&self) -> u32 {
+        self.hz
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), DSPError> {
+        if hz == self.hz {
+            return Ok(());
+        }
+        // The current device is only replaced, and removed from the bus, once the new one exists
+        self.device = add_device(self.bus, hz).map_err(|error| {
+            warn!("Unable to set the SPI clock to {hz} Hz: {error:?}");
+            DSPError::Spi
+        })?;
+        self.hz = hz;
+        Ok(())
+    }
+}
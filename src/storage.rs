@@ -0,0 +1,25 @@
+use esp_idf_svc::sys::{esp, esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register, EspError};
+use log::info;
+
+/// Where the `storage` SPIFFS partition is mounted, e.g. `/storage/jingle.mp3`
+pub const MOUNT_POINT: &str = "/storage";
+/// Played at boot when present, see `POST /jingle`
+pub const JINGLE_PATH: &str = "/storage/jingle.mp3";
+/// Where an upload is written, until complete
+pub const JINGLE_UPLOAD_PATH: &str = "/storage/jingle.tmp";
+const MAX_OPEN_FILES: usize = 4;
+
+/// Mounts the `storage` partition at `MOUNT_POINT`, formatting it if it was never used.
+///
+/// Files are then accessed through `std::fs`.
+pub fn mount_storage() -> Result<(), EspError> {
+    let conf = esp_vfs_spiffs_conf_t {
+        base_path: c"/storage".as_ptr(),
+        partition_label: c"storage".as_ptr(),
+        max_files: MAX_OPEN_FILES,
+        format_if_mount_failed: true,
+    };
+    esp!(unsafe { esp_vfs_spiffs_register(&conf) })?;
+    info!("Mounted storage partition at {MOUNT_POINT}");
+    Ok(())
+}
@@ -0,0 +1,527 @@
+use crate::ring_log::{LogKind, RING_LOG};
+use crate::vs1053::DSPError;
+use esp_idf_svc::{
+    http::{
+        client::{Configuration as HttpConfiguration, EspHttpConnection},
+        Method,
+    },
+    io::Read,
+    sys::{esp_crt_bundle_attach, esp_get_free_heap_size},
+};
+use log::{info, warn};
+use rustdio_core::icy::IcyMetadata;
+use std::{
+    collections::VecDeque,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Bytes read from the network at once
+pub const STREAM_READ_SIZE: usize = 64;
+// The chunk size 64 seems to be optimal. At 32 and 128 the sound might be brassy.
+pub const DEFAULT_PLAY_CHUNK_SIZE: usize = 64;
+pub const PLAY_CHUNK_SIZE_RANGE: RangeInclusive<usize> = 16..=1024;
+
+const STREAM_HTTP_BUFFER_SIZE: usize = 1024;
+const STREAM_HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// The TLS handshake runs on the streaming thread and needs a few more KB than plain HTTP
+const STREAM_THREAD_STACK_SIZE: usize = 12 * 1024;
+const PLAYBACK_THREAD_STACK_SIZE: usize = 6 * 1024;
+const STREAM_BUFFER_SIZE: usize = 32 * 1024;
+/// Audio buffered before starting, or resuming after the buffer ran empty.
+const STREAM_BUFFER_PREFILL: usize = STREAM_BUFFER_SIZE / 2;
+/// Fill below which the network is not keeping up, 0.25 s of a 128 kbps stream.
+const STREAM_BUFFER_LOW: usize = STREAM_BUFFER_SIZE / 8;
+/// Heap needed to open a TLS connection.
+///
+/// mbedTLS allocates its record buffers (up to 16 KB in and 4 KB out with
+/// `CONFIG_MBEDTLS_DYNAMIC_BUFFER` shrinking them once connected) plus the handshake state
+/// and the certificate chain being verified against the bundle, ~40 KB at peak.
+const TLS_MIN_FREE_HEAP: u32 = 48 * 1024;
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static PLAY_CHUNK_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_PLAY_CHUNK_SIZE);
+
+/// Sets the size of the chunks handed from the stream buffer to the decoder, from the next
+/// chunk on. Returns `false`, changing nothing, outside of `PLAY_CHUNK_SIZE_RANGE`.
+///
+/// Each chunk locks the buffer then the decoder once: smaller chunks interleave the network
+/// and the decoder more finely but cost more locking, larger ones hold the decoder longer
+/// while the network waits for room in the buffer.
+pub fn set_play_chunk_size(size: usize) -> bool {
+    if !PLAY_CHUNK_SIZE_RANGE.contains(&size) {
+        return false;
+    }
+    PLAY_CHUNK_SIZE.store(size, Ordering::Relaxed);
+    true
+}
+
+pub fn play_chunk_size() -> usize {
+    PLAY_CHUNK_SIZE.load(Ordering::Relaxed)
+}
+
+/// Receives the audio and metadata of a webradio.
+pub trait StreamSink {
+    fn play(&mut self, chunk: &[u8]) -> Result<(), DSPError>;
+    fn set_title(&mut self, title: Option<String>);
+
+    /// Called once audio is about to play, after connecting or refilling the buffer.
+    fn connected(&mut self) {}
+
+    /// Called before waiting `delay` to reconnect a dropped stream.
+    fn reconnecting(&mut self, _delay: Duration) {}
+
+    /// Called when the buffered audio runs low, and with `false` once it refilled.
+    fn buffering(&mut self, _buffering: bool) {}
+
+    /// Called when playing failed midway, to silence whatever was left half sent.
+    fn abort(&mut self) {}
+}
+
+/// An HTTP connection to a webradio, yielding the raw audio body.
+///
+/// Chunked transfer encoding is decoded by the ESP-IDF HTTP client and ICY metadata is
+/// stripped, so `read` only ever returns audio bytes.
+pub struct WebStream {
+    connection: EspHttpConnection,
+    icy: Option<IcyMetadata>,
+}
+
+impl WebStream {
+    /// Connects to `url`, `https://` ones being verified against the ESP-IDF certificate bundle.
+    pub fn open(url: &str) -> Result<Self, DSPError> {
+        let is_tls = url.starts_with("https://");
+        // A handshake running out of memory only shows as a connection error, check beforehand
+        if is_tls {
+            let free_heap = unsafe { esp_get_free_heap_size() };
+            if free_heap < TLS_MIN_FREE_HEAP {
+                warn!("Only {free_heap} bytes of heap left, not enough for TLS to {url}");
+                return Err(DSPError::Tls);
+            }
+        }
+        let connection_error = if is_tls {
+            DSPError::Tls
+        } else {
+            DSPError::Http
+        };
+
+        let mut connection = EspHttpConnection::new(&HttpConfiguration {
+            buffer_size: Some(STREAM_HTTP_BUFFER_SIZE),
+            timeout: Some(STREAM_HTTP_TIMEOUT),
+            crt_bundle_attach: is_tls.then_some(esp_crt_bundle_attach),
+            ..Default::default()
+        })
+        .map_err(|error| {
+            warn!("Failed to create HTTP connection for {url}: {error:?}");
+            DSPError::Http
+        })?;
+
+        // Connecting, and so the TLS handshake, happens when sending the request
+        connection
+            .initiate_request(Method::Get, url, &[("Icy-MetaData", "1")])
+            .map_err(|error| {
+                warn!("Failed to send HTTP request to {url}: {error:?}");
+                connection_error
+            })?;
+        connection.initiate_response().map_err(|error| {
+            warn!("Failed to get HTTP response from {url}: {error:?}");
+            DSPError::Http
+        })?;
+
+        let status = connection.status();
+        if !(200..300).contains(&status) {
+            warn!("Stream {url} answered with HTTP status {status}");
+            return Err(DSPError::Http);
+        }
+
+        let icy = connection
+            .header("icy-metaint")
+            .and_then(|interval| interval.trim().parse::<usize>().ok())
+            .filter(|interval| *interval > 0)
+            .map(IcyMetadata::new);
+        info!(
+            "Connected to stream {url}, ICY metadata interval:{:?}",
+            icy.as_ref().map(IcyMetadata::interval)
+        );
+
+        Ok(Self { connection, icy })
+    }
+
+    /// Reads the next audio bytes, returns 0 once the server closed the stream.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, DSPError> {
+        loop {
+            let len = self.connection.read(buf).map_err(|error| {
+                warn!("Failed to read from stream: {error:?}");
+                DSPError::Http
+            })?;
+            let Some(icy) = self.icy.as_mut() else {
+                return Ok(len);
+            };
+            if len == 0 {
+                return Ok(0);
+            }
+            let audio_len = icy.strip(&mut buf[..len]);
+            // A read made only of metadata is not the end of the stream
+            if audio_len > 0 {
+                return Ok(audio_len);
+            }
+        }
+    }
+
+    /// Returns the last `StreamTitle` received, only once.
+    pub fn take_title(&mut self) -> Option<String> {
+        self.icy.as_mut().and_then(IcyMetadata::take_title)
+    }
+}
+
+/// Streams `url` into `sink` by chunks of `STREAM_READ_SIZE` bytes, until the server closes
+/// the connection or `stop` is set.
+pub fn stream_url<S: StreamSink>(
+    url: &str,
+    stop: &AtomicBool,
+    sink: &mut S,
+) -> Result<(), DSPError> {
+    let mut stream = WebStream::open(url)?;
+    let mut buf = [0; STREAM_READ_SIZE];
+    sink.set_title(None);
+    sink.connected();
+
+    while !stop.load(Ordering::Relaxed) {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            info!("Stream {url} closed by server");
+            break;
+        }
+        if let Some(title) = stream.take_title() {
+            info!("Now playing: {title}");
+            sink.set_title(Some(title));
+        }
+        sink.play(&buf[..len])?;
+    }
+    Ok(())
+}
+
+/// Streams `url` into `sink` until `stop` is set, reconnecting with an exponential backoff
+/// whenever the stream ends or fails.
+pub fn stream_with_reconnect<S: StreamSink>(
+    url: &str,
+    stop: &AtomicBool,
+    sink: &mut S,
+) -> Result<(), DSPError> {
+    let mut delay = RECONNECT_MIN_DELAY;
+    loop {
+        let started = Instant::now();
+        let result = stream_url(url, stop, sink);
+        if stop.load(Ordering::Relaxed) {
+            return result;
+        }
+        // A stream that played for a while deserves a quick reconnection
+        if started.elapsed() > RECONNECT_MAX_DELAY {
+            delay = RECONNECT_MIN_DELAY;
+        }
+        warn!("Stream {url} dropped ({result:?}), reconnecting in {delay:?}");
+        RING_LOG.record(
+            LogKind::Reconnect,
+            format!("{url} dropped ({result:?}), reconnecting in {delay:?}"),
+        );
+        sink.reconnecting(delay);
+
+        let reconnect_at = Instant::now() + delay;
+        while Instant::now() < reconnect_at {
+            if stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            sleep(STOP_POLL_INTERVAL);
+        }
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+    }
+}
+
+/// Bounded FIFO of audio bytes between the network and the decoder.
+///
+/// The network thread keeps it full, so the decoder keeps playing through stalls as long
+/// as there is audio left: 32 KB is 2 s of a 128 kbps stream.
+pub struct StreamBuffer {
+    state: Mutex<BufferState>,
+    changed: Condvar,
+    /// Times the buffer ran empty while playing
+    underruns: AtomicU32,
+    /// Whether audio reaches the sink, i.e. not prefilling after a (re)connection or underrun
+    feeding: AtomicBool,
+}
+
+struct BufferState {
+    audio: VecDeque<u8>,
+    /// Metadata received from the network, forwarded to the sink by the decoder thread
+    title: Option<Option<String>>,
+    reconnecting: Option<Duration>,
+    /// Nothing is played until released, the network filling the buffer meanwhile
+    held: bool,
+}
+
+impl StreamBuffer {
+    fn new(held: bool) -> Self {
+        Self {
+            state: Mutex::new(BufferState {
+                audio: VecDeque::with_capacity(STREAM_BUFFER_SIZE),
+                title: None,
+                reconnecting: None,
+                held,
+            }),
+            changed: Condvar::new(),
+            underruns: AtomicU32::new(0),
+            feeding: AtomicBool::new(false),
+        }
+    }
+
+    pub fn fill_percent(&self) -> u8 {
+        let len = self.state.lock().unwrap().audio.len();
+        (len * 100 / STREAM_BUFFER_SIZE) as u8
+    }
+
+    /// Feeds `sink` until `stop` is set, waiting for `STREAM_BUFFER_PREFILL` bytes whenever
+    /// the buffer ran empty.
+    fn drain_into<S: StreamSink>(&self, stop: &AtomicBool, sink: &mut S) -> Result<(), DSPError> {
+        let mut chunk = Vec::with_capacity(*PLAY_CHUNK_SIZE_RANGE.end());
+        let mut prefilling = true;
+        let mut low = false;
+        while !stop.load(Ordering::Relaxed) {
+            let mut state = self.state.lock().unwrap();
+            if state.held {
+                drop(
+                    self.changed
+                        .wait_timeout(state, STOP_POLL_INTERVAL)
+                        .unwrap(),
+                );
+                continue;
+            }
+            if !prefilling && state.audio.is_empty() {
+                let underruns = self.underruns.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!("Stream buffer underrun #{underruns}, buffering");
+                RING_LOG.record(LogKind::Underrun, format!("Buffer underrun #{underruns}"));
+                prefilling = true;
+                self.feeding.store(false, Ordering::Relaxed);
+            }
+            let threshold = if prefilling { STREAM_BUFFER_PREFILL } else { 1 };
+            if state.audio.len() < threshold {
+                state = self
+                    .changed
+                    .wait_timeout(state, STOP_POLL_INTERVAL)
+                    .unwrap()
+                    .0;
+            }
+            let title = state.title.take();
+            let reconnecting = state.reconnecting.take();
+            let fill = state.audio.len();
+            let ready = fill >= threshold;
+            if ready {
+                let len = fill.min(play_chunk_size());
+                chunk.clear();
+                chunk.extend(state.audio.drain(..len));
+                self.changed.notify_all();
+            }
+            drop(state);
+
+            if let Some(title) = title {
+                sink.set_title(title);
+            }
+            if let Some(delay) = reconnecting {
+                sink.reconnecting(delay);
+            }
+            if !ready {
+                continue;
+            }
+            if prefilling {
+                prefilling = false;
+                self.feeding.store(true, Ordering::Relaxed);
+                sink.connected();
+            }
+            if !low && fill < STREAM_BUFFER_LOW {
+                low = true;
+                sink.buffering(true);
+            } else if low && fill >= STREAM_BUFFER_PREFILL {
+                low = false;
+                sink.buffering(false);
+            }
+            sink.play(&chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// The network side of a `StreamBuffer`, blocking while the buffer is full.
+struct BufferWriter<'a> {
+    buffer: &'a StreamBuffer,
+    stop: &'a AtomicBool,
+}
+
+impl StreamSink for BufferWriter<'_> {
+    fn play(&mut self, chunk: &[u8]) -> Result<(), DSPError> {
+        let mut state = self.buffer.state.lock().unwrap();
+        while state.audio.len() + chunk.len() > STREAM_BUFFER_SIZE {
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            state = self
+                .buffer
+                .changed
+                .wait_timeout(state, STOP_POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        state.audio.extend(chunk);
+        self.buffer.changed.notify_all();
+        Ok(())
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.buffer.state.lock().unwrap().title = Some(title);
+    }
+
+    fn reconnecting(&mut self, delay: Duration) {
+        self.buffer.state.lock().unwrap().reconnecting = Some(delay);
+    }
+}
+
+/// A webradio being played in the background, by a thread downloading it into a
+/// `StreamBuffer` and another one feeding the decoder from it.
+pub struct StreamThread {
+    stop: Arc<AtomicBool>,
+    buffer: Arc<StreamBuffer>,
+    network: JoinHandle<()>,
+    decoder: JoinHandle<()>,
+}
+
+impl StreamThread {
+    pub fn spawn<S>(url: String, sink: S) -> std::io::Result<Self>
+    where
+        S: StreamSink + Send + 'static,
+    {
+        Self::spawn_inner(url, sink, false)
+    }
+
+    /// Like `spawn`, but the audio is only buffered until `release`, e.g. while the previous
+    /// station still plays.
+    pub fn spawn_held<S>(url: String, sink: S) -> std::io::Result<Self>
+    where
+        S: StreamSink + Send + 'static,
+    {
+        Self::spawn_inner(url, sink, true)
+    }
+
+    fn spawn_inner<S>(url: String, mut sink: S, held: bool) -> std::io::Result<Self>
+    where
+        S: StreamSink + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let buffer = Arc::new(StreamBuffer::new(held));
+
+        let network_stop = stop.clone();
+        let network_buffer = buffer.clone();
+        let network = thread::Builder::new()
+            .name("webradio".into())
+            .stack_size(STREAM_THREAD_STACK_SIZE)
+            .spawn(move || {
+                let mut writer = BufferWriter {
+                    buffer: &network_buffer,
+                    stop: &network_stop,
+                };
+                match stream_with_reconnect(&url, &network_stop, &mut writer) {
+                    Ok(_) => info!("Stopped streaming {url}"),
+                    Err(err) => {
+                        warn!("Streaming {url} failed: {err:?}");
+                        RING_LOG.record(LogKind::Error, format!("Streaming {url} failed: {err:?}"));
+                    }
+                }
+            })?;
+
+        let decoder_stop = stop.clone();
+        let decoder_buffer = buffer.clone();
+        let decoder = thread::Builder::new()
+            .name("webradio-play".into())
+            .stack_size(PLAYBACK_THREAD_STACK_SIZE)
+            .spawn(move || {
+                if let Err(err) = decoder_buffer.drain_into(&decoder_stop, &mut sink) {
+                    warn!("Playing stream failed: {err:?}");
+                    RING_LOG.record(LogKind::Error, format!("Playing stream failed: {err:?}"));
+                    sink.abort();
+                    // Nothing would empty the buffer anymore
+                    decoder_stop.store(true, Ordering::Relaxed);
+                }
+            });
+        let decoder = match decoder {
+            Ok(decoder) => decoder,
+            Err(err) => {
+                stop.store(true, Ordering::Relaxed);
+                let _ = network.join();
+                return Err(err);
+            }
+        };
+
+        Ok(Self {
+            stop,
+            buffer,
+            network,
+            decoder,
+        })
+    }
+
+    /// Waits until enough audio is buffered to start playing without buffering again,
+    /// `false` if it took longer than `timeout` or the stream stopped.
+    pub fn wait_prefilled(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        let mut state = self.buffer.state.lock().unwrap();
+        while state.audio.len() < STREAM_BUFFER_PREFILL {
+            if self.stop.load(Ordering::Relaxed) || start.elapsed() >= timeout {
+                return false;
+            }
+            state = self
+                .buffer
+                .changed
+                .wait_timeout(state, STOP_POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    /// Starts playing a stream spawned with `spawn_held`.
+    pub fn release(&self) {
+        self.buffer.state.lock().unwrap().held = false;
+        self.buffer.changed.notify_all();
+    }
+
+    /// Percentage of the stream buffer filled with audio not played yet.
+    pub fn buffer_fill_percent(&self) -> u8 {
+        self.buffer.fill_percent()
+    }
+
+    /// Whether audio is reaching the decoder, `false` while buffering, e.g. when reconnecting.
+    pub fn is_feeding(&self) -> bool {
+        self.buffer.feeding.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the stream buffer ran empty, i.e. the network did not keep up.
+    pub fn underruns(&self) -> u32 {
+        self.buffer.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Asks the threads to stop and waits until they are done feeding the decoder.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.buffer.changed.notify_all();
+        if self.network.join().is_err() {
+            warn!("Webradio thread panicked");
+        }
+        if self.decoder.join().is_err() {
+            warn!("Webradio playback thread panicked");
+        }
+    }
+}
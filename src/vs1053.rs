@@ -1,26 +1,57 @@
+use crate::stream::{self, StreamSink};
+use crate::vs1053_patches::VS1053B_PATCHES;
 use anyhow::Result;
-use core::cmp::max;
-use embedded_hal::spi::{Operation, SpiDevice};
-use esp_idf_hal::gpio::{InputPin, OutputPin, PinDriver};
+use core::{cmp::max, num::NonZeroU32};
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::{Operation, SpiDevice},
+};
+use esp_idf_hal::{
+    delay::TickType,
+    gpio::{self, Input, InterruptType, PinDriver},
+    sys::EspError,
+    task::notification::Notification,
+};
 use log::warn;
-use std::{ffi::CStr, str, thread::sleep, time::Duration};
+use rustdio_core::vs1053::{sci_bass, sci_vol};
+pub use rustdio_core::vs1053::{Codec, StreamInfo};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::Read,
+    str,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    thread::sleep,
+    time::Duration,
+};
 
 const VS1053_CHUNK_SIZE: u8 = 32;
+const DEFAULT_DREQ_TIMEOUT: Duration = Duration::from_millis(2000);
+const FADE_STEP_MS: u32 = 20;
+const FILE_READ_SIZE: usize = 1024;
+// Register writes made before CLOCKF is set must not exceed CLKI/4 (3 MHz)
+const LOW_SPEED_SPI_HZ: u32 = 200_000;
 
 // SCI Register
 const SCI_MODE: u8 = 0x0;
 const SCI_STATUS: u8 = 0x1;
-#[allow(dead_code)]
 const SCI_BASS: u8 = 0x2;
 const SCI_CLOCKF: u8 = 0x3;
-// const SCI_DECODE_TIME: u8 = 0x4; // current decoded time in full seconds
+const SCI_DECODE_TIME: u8 = 0x4; // current decoded time in full seconds
 const SCI_AUDATA: u8 = 0x5;
 const SCI_WRAM: u8 = 0x6;
 const SCI_WRAMADDR: u8 = 0x7;
+// Bitrate of the stream being decoded, or the memory test result in test mode
+const SCI_HDAT0: u8 = 0x8;
+// Format of the stream being decoded
+const SCI_HDAT1: u8 = 0x9;
 // const SCI_AIADDR: u8 = 0xA;
 const SCI_VOL: u8 = 0xB;
+/// Both channels at -127 dB, the quietest before powering down the analog drivers
+const SCI_VOL_SILENT: u16 = 0xFEFE;
 // const SCI_AICTRL0: u8 = 0xC;
 // const SCI_AICTRL1: u8 = 0xD;
+const SCI_AICTRL3: u8 = 0xF; // VU meter levels, with the VS1053b patches
 const SCI_NUM_REGISTERS: u8 = 0xF;
 
 // SCI_MODE bits
@@ -28,14 +59,37 @@ const SM_SDINEW: u8 = 11; // Bitnumber in SCI_MODE always on
 const SM_RESET: u8 = 2; // Bitnumber in SCI_MODE soft reset
 #[allow(dead_code)]
 const SM_CANCEL: u8 = 3; // Bitnumber in SCI_MODE cancel song
-                         // const SM_TESTS: u8 = 5; // Bitnumber in SCI_MODE for tests
+const SM_TESTS: u8 = 5; // Bitnumber in SCI_MODE for tests
 const SM_LINE1: u8 = 14; // Bitnumber in SCI_MODE for Line input
-                         // const SM_STREAM: u8 = 6; // Bitnumber in SCI_MODE for Streaming Mode
+const SM_STREAM: u8 = 6; // Bitnumber in SCI_MODE for Streaming Mode
+
+// SCI_CLOCKF values
+const CLOCKF_MP3: u16 = 6 << 12; // SC_MULT 3.0x = 12.2 MHz
+const CLOCKF_AAC: u16 = 0x8800; // SC_MULT 3.5x, SC_ADD up to 4.5x when decoding needs it
+
+// SCI_STATUS bits
+const SS_VU_ENABLE: u8 = 9; // Bitnumber in SCI_STATUS for the VU meter of the VS1053b patches
+
+// SDI test mode sequences, see VS1053b Datasheet (1.31) / 9.12
+const MEMORY_TEST_SEQUENCE: [u8; 8] = [0x4D, 0xEA, 0x6D, 0x54, 0, 0, 0, 0];
+const SINE_TEST_START: [u8; 3] = [0x53, 0xEF, 0x6E];
+const SINE_TEST_END: [u8; 8] = [0x45, 0x78, 0x69, 0x74, 0, 0, 0, 0];
+// The memory test takes 1 100 000 clock cycles, 90 ms at 12.288 MHz
+const MEMORY_TEST_DURATION: Duration = Duration::from_millis(200);
+/// Memory test result of a VS1053b whose memories all passed
+pub const MEMORY_TEST_OK: u16 = 0x83FF;
 
 const ADDR_REG_GPIO_DDR_RW: u16 = 0xc017;
 // const ADDR_REG_GPIO_VAL_R: u16 = 0xc018;
 const ADDR_REG_GPIO_ODATA_RW: u16 = 0xc019;
-// const ADDR_REG_I2S_CONFIG_RW: u16 = 0xc040;
+const ADDR_REG_I2S_CONFIG_RW: u16 = 0xc040;
+const PARA_PLAY_SPEED: u16 = 0x1e04;
+const PARA_END_FILL_BYTE: u16 = 0x1e06;
+const PARA_RATE_TUNE: u16 = 0x1e07; // Extra parameter, 32 bits over two consecutive words
+const ADDR_OLD_CLOCK_4KHZ: u16 = 0x5b1c;
+/// Only handled once the VS1053b patches are loaded
+const PARA_PLAY_MODE: u16 = 0x1e09;
+const PLAY_MODE_MONO_OUTPUT: u16 = 1 << 0;
 
 macro_rules! _bv {
     ($bit:expr) => {
@@ -43,104 +97,201 @@ macro_rules! _bv {
     };
 }
 
-fn contains(str: *const u8, substr: &str) -> bool {
-    // Convert the raw pointer to a CStr
+// Waits for a DREQ rising edge, returns whether it happened before the timeout
+fn wait_for_dreq_interrupt<DREQ: gpio::InputPin>(
+    dreq: &mut PinDriver<'_, DREQ, Input>,
+    timeout: Duration,
+) -> Result<bool, EspError> {
+    let notification = Notification::new();
+    let notifier = notification.notifier();
+
+    dreq.set_interrupt_type(InterruptType::PosEdge)?;
+    // Safety: the callback only notifies this task and is unsubscribed before returning
     unsafe {
-        if str.is_null() {
-            return false;
-        }
+        dreq.subscribe(move || {
+            notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
+        })?;
+    }
+    dreq.enable_interrupt()?;
+
+    // DREQ may have risen before the interrupt was armed
+    let ready = dreq.is_high() || notification.wait(TickType::from(timeout).ticks()).is_some();
+    dreq.unsubscribe()?;
+    Ok(ready)
+}
 
-        // Create a CStr from the raw pointer
-        let c_str = CStr::from_ptr(str as *const i8);
+/// DREQ input, which may be able to wait for the decoder without polling.
+pub trait DataRequestPin: InputPin {
+    /// Whether DREQ rose before `timeout`, `None` when it can only be polled.
+    fn wait_for_rising_edge(&mut self, _timeout: Duration) -> Option<bool> {
+        None
+    }
+}
 
-        // Convert the CStr to a Rust &str
-        if let Ok(str_slice) = c_str.to_str() {
-            // Check if the &str contains the substring "Fast"
-            return str_slice.contains(substr);
+impl<T: gpio::InputPin> DataRequestPin for PinDriver<'_, T, Input> {
+    fn wait_for_rising_edge(&mut self, timeout: Duration) -> Option<bool> {
+        match wait_for_dreq_interrupt(self, timeout) {
+            Ok(ready) => Some(ready),
+            Err(err) => {
+                warn!("DREQ interrupt unavailable, polling instead: {:?}", err);
+                None
+            }
         }
     }
+}
 
-    false
+/// An `SpiDevice` whose clock can be changed once created.
+pub trait ClockedSpi: SpiDevice {
+    fn clock(&self) -> u32;
+    fn set_clock(&mut self, hz: u32) -> Result<(), DSPError>;
 }
 
-fn map(x: i64, in_min: i64, in_max: i64, out_min: i64, out_max: i64) -> i64 {
-    (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min
+/// Ramps the volume from `from` to `target` over `duration_ms`, one `set_volume` call every
+/// `FADE_STEP_MS`.
+///
+/// Lets a shared decoder be locked for each step only, see `VS1053::fade_to` otherwise.
+pub fn fade(
+    from: u8,
+    target: u8,
+    duration_ms: u32,
+    mut set_volume: impl FnMut(u8) -> Result<(), DSPError>,
+) -> Result<(), DSPError> {
+    let steps = max(1, duration_ms / FADE_STEP_MS) as i32;
+    let (from, target) = (from as i32, target.min(100) as i32);
+    for step in 1..=steps {
+        set_volume((from + (target - from) * step / steps) as u8)?;
+        if step < steps {
+            sleep(Duration::from_millis(FADE_STEP_MS.into()));
+        }
+    }
+    Ok(())
 }
 
-pub struct VS1053<SPI, XCS, XDCS, DREQ> {
+pub struct VS1053<SPI, XRST, XCS, XDCS, DREQ> {
     spi: SPI,
-    low_spi: SPI,
+    /// Clock of `spi` outside of the slow register writes
+    high_speed_hz: u32,
+    /// Longest wait for DREQ before `DataRequestTimeout`
+    dreq_timeout: Duration,
+    /// Soft resets attempted by `play_chunk2` when DREQ times out
+    dreq_retries: u8,
+    /// Configured once by the caller, the methods only toggle or read them
+    xrst_pin: Option<XRST>,
     xcs_pin: XCS,
     xdcs_pin: XDCS,
     dreq_pin: DREQ,
     current_volume: u8,
     current_balance: i8,
+    current_tone: Tone,
+    mono: bool,
+    /// `current_volume` is kept while muted, and applied again by `unmute`
+    muted: bool,
+    stream_title: Option<String>,
+    playback_state: PlaybackState,
 }
 
-impl<SPI, XCS, XDCS, DREQ> VS1053<SPI, XCS, XDCS, DREQ>
+impl<SPI, XRST, XCS, XDCS, DREQ> VS1053<SPI, XRST, XCS, XDCS, DREQ>
 where
-    SPI: SpiDevice,
+    SPI: ClockedSpi,
+    XRST: OutputPin,
     XCS: OutputPin,
     XDCS: OutputPin,
-    DREQ: InputPin,
+    DREQ: DataRequestPin,
 {
-    pub fn new(spi: SPI, low_spi: SPI, xcs_pin: XCS, xdcs_pin: XDCS, dreq_pin: DREQ) -> Self {
-        Self {
+    /// Without `xrst_pin`, `begin()` falls back to a reset through the CS/DCS lines.
+    ///
+    /// CS and DCS are driven high right away, output drivers usually starting low.
+    pub fn new(
+        spi: SPI,
+        xrst_pin: Option<XRST>,
+        xcs_pin: XCS,
+        xdcs_pin: XDCS,
+        dreq_pin: DREQ,
+    ) -> Self {
+        let mut decoder = Self {
+            high_speed_hz: spi.clock(),
+            dreq_timeout: DEFAULT_DREQ_TIMEOUT,
+            dreq_retries: 0,
             spi,
-            low_spi,
+            xrst_pin,
             xcs_pin,
             xdcs_pin,
             dreq_pin,
             current_volume: 50,
             current_balance: 0,
-        }
+            current_tone: Tone::default(),
+            mono: false,
+            muted: false,
+            stream_title: None,
+            playback_state: PlaybackState::Stopped,
+        };
+        // Both interfaces of the chip would be selected until begin() otherwise
+        let _ = decoder.control_mode_off();
+        let _ = decoder.data_mode_off();
+        decoder
     }
 
-    fn set_cs_pin(&mut self, is_high: bool) -> Result<(), DSPError> {
-        let mut xcs = match PinDriver::output(&mut self.xcs_pin) {
-            Ok(pin) => pin,
-            Err(err) => {
-                warn!("Set DCS pin failed because: {:?}", err);
-                return Err(DSPError::UnableToSetCSPin);
-            }
+    // Pulls XRST low then high again, does nothing without a reset pin
+    fn hardware_reset(&mut self) -> Result<(), DSPError> {
+        let Some(xrst) = self.xrst_pin.as_mut() else {
+            return Ok(());
         };
-        if is_high {
-            let _ = xcs.set_high();
-        } else {
-            let _ = xcs.set_low();
-        }
+        xrst.set_low().map_err(|err| {
+            warn!("Set XRST pin failed because: {:?}", err);
+            DSPError::UnableToSetXRSTPin
+        })?;
+        sleep(Duration::from_millis(5));
+        xrst.set_high().map_err(|err| {
+            warn!("Set XRST pin failed because: {:?}", err);
+            DSPError::UnableToSetXRSTPin
+        })?;
+        sleep(Duration::from_millis(100));
         Ok(())
     }
 
-    fn set_dcs_pin(&mut self, is_high: bool) -> Result<(), DSPError> {
-        let mut xdcs = match PinDriver::output(&mut self.xdcs_pin) {
-            Ok(pin) => pin,
-            Err(err) => {
-                warn!("Set XDCS pin failed because: {:?}", err);
-                return Err(DSPError::UnableToSetDCSPin);
-            }
+    fn set_cs_pin(&mut self, is_high: bool) -> Result<(), DSPError> {
+        let level = if is_high {
+            self.xcs_pin.set_high()
+        } else {
+            self.xcs_pin.set_low()
         };
-        if is_high {
-            let _ = xdcs.set_high();
+        level.map_err(|err| {
+            warn!("Set CS pin failed because: {:?}", err);
+            DSPError::UnableToSetCSPin
+        })
+    }
+
+    fn set_dcs_pin(&mut self, is_high: bool) -> Result<(), DSPError> {
+        let level = if is_high {
+            self.xdcs_pin.set_high()
         } else {
-            let _ = xdcs.set_low();
-        }
-        Ok(())
+            self.xdcs_pin.set_low()
+        };
+        level.map_err(|err| {
+            warn!("Set XDCS pin failed because: {:?}", err);
+            DSPError::UnableToSetDCSPin
+        })
+    }
+
+    fn is_dreq_high(&mut self) -> Result<bool, DSPError> {
+        self.dreq_pin.is_high().map_err(|err| {
+            warn!("Get DREQ pin failed because: {:?}", err);
+            DSPError::UnableToGetDREQPin
+        })
     }
 
+    // Waits on a DREQ interrupt, polling only if the pin has none
     fn await_data_request(&mut self) -> Result<(), DSPError> {
-        let dreq = match PinDriver::input(&mut self.dreq_pin) {
-            Ok(pin) => pin,
-            Err(err) => {
-                warn!(
-                    "Get DREQ pin for _await_data_request failed because: {:?}",
-                    err
-                );
-                Err(DSPError::UnableToGetDREQPin)
-            }?,
-        };
-        for _i in 0..=2000 {
-            if !dreq.is_high() {
+        if self.is_dreq_high()? {
+            return Ok(());
+        }
+        match self.dreq_pin.wait_for_rising_edge(self.dreq_timeout) {
+            Some(true) => return Ok(()),
+            Some(false) => return Err(DSPError::DataRequestTimeout),
+            None => {}
+        }
+        for _i in 0..=self.dreq_timeout.as_millis() {
+            if !self.is_dreq_high()? {
                 sleep(Duration::from_millis(1));
             } else {
                 return Ok(());
@@ -167,7 +318,7 @@ where
         self.set_dcs_pin(true)
     }
 
-    fn sdi_send_buffer(&mut self, mut data: *mut u8, mut length: usize) {
+    fn sdi_send_buffer(&mut self, mut data: *mut u8, mut length: usize) -> Result<(), DSPError> {
         let mut chunk_length: usize; // Length of chunk 32 byte or shorter
 
         let _ = self.data_mode_on();
@@ -189,53 +340,29 @@ where
                 let data_slice = std::slice::from_raw_parts(data, chunk_length);
 
                 // Call write_bytes using the slice
-                self.write_bytes(data_slice)
-                    .expect("Error when write_bytes in sdi_send_buffer");
+                self.write_bytes(data_slice)?;
 
                 // Move the data pointer forward by chunk_length
                 data = data.add(chunk_length);
             }
         }
-        let _ = self.data_mode_off();
+        self.data_mode_off()
     }
 
-    #[allow(dead_code)]
-    fn sdi_send_fillers(&mut self, mut length: usize) {
-        let _ = self.data_mode_on();
+    /// Sends `length` end fill bytes, by chunks of up to 32 bytes.
+    fn sdi_send_fillers(&mut self, mut length: usize) -> Result<(), DSPError> {
+        // Read once, before the data mode as reading WRAM needs the control one
+        let end_fill_byte = (self._wram_read(PARA_END_FILL_BYTE)? & 0xFF) as u8;
+        let fillers = [end_fill_byte; VS1053_CHUNK_SIZE as usize];
 
+        let _ = self.data_mode_on();
         while length > 0 {
             let _ = self.await_data_request(); // Wait for space available
-
-            let chunk_length = if length > VS1053_CHUNK_SIZE.into() {
-                VS1053_CHUNK_SIZE
-            } else {
-                length
-                    .try_into()
-                    .expect("Failed to put length in a u8 for sdi_send_fillers")
-            };
-
-            length -= chunk_length as usize;
-
-            for _ in 0..chunk_length {
-                let efb = self
-                    ._wram_read(0x1E06)
-                    .expect("Failed to wram_read in sdi_send_fillers");
-                let end_fill_byte = efb & 0xFF;
-                let lsb: u8 = (end_fill_byte & 0xFF) as u8;
-                let msb: u8 = (end_fill_byte >> 8) as u8;
-                let _ = self
-                    .spi
-                    .transaction(&mut [Operation::Write(&[msb, lsb])])
-                    .map_err(|error| {
-                        log::warn!(
-                            "Failed to make SPI transaction for sdi_send_fillers: {error:?}"
-                        );
-                        DSPError::Spi
-                    });
-            }
+            let chunk_length = length.min(VS1053_CHUNK_SIZE.into());
+            length -= chunk_length;
+            self.write_bytes(&fillers[..chunk_length])?;
         }
-
-        let _ = self.data_mode_off();
+        self.data_mode_off()
     }
 
     fn wram_write(&mut self, address: u16, data: u16) -> Result<(), DSPError> {
@@ -248,44 +375,82 @@ where
         self.read_register(SCI_WRAM) // Read back result
     }
 
+    /// Changes the high speed SPI clock once `begin()` succeeded, going back to the previous
+    /// clock if the registers can't be read back reliably at `hz`.
+    pub fn set_spi_speed(&mut self, hz: u32) -> Result<(), DSPError> {
+        let previous_hz = self.high_speed_hz;
+        if hz == previous_hz {
+            return Ok(());
+        }
+        self.spi.set_clock(hz)?;
+        self.high_speed_hz = hz;
+        // test_comm goes through SCI_VOL, which is restored afterwards
+        let communicates =
+            self.test_comm("Fast SPI, Testing VS1053 read/write registers at new speed...");
+        if let Err(err) = communicates {
+            warn!("VS1053 unreliable at {hz} Hz SPI ({err:?}), staying at {previous_hz} Hz");
+            self.spi.set_clock(previous_hz)?;
+            self.high_speed_hz = previous_hz;
+            self.set_volume(self.current_volume)?;
+            return Err(err);
+        }
+        log::info!("VS1053 SPI clock set to {hz} Hz");
+        self.set_volume(self.current_volume)
+    }
+
+    /// Some boards need longer than the default 2 s, e.g. while the patches are loaded.
+    pub fn set_dreq_timeout(&mut self, timeout: Duration) {
+        self.dreq_timeout = timeout;
+    }
+
+    /// Lets `play_chunk2` soft reset the decoder up to `retries` times when DREQ times out,
+    /// instead of failing on the first timeout.
+    pub fn set_dreq_retries(&mut self, retries: u8) {
+        self.dreq_retries = retries;
+    }
+
     pub fn begin(&mut self) -> Result<(), DSPError> {
         self.set_dcs_pin(true)?;
         self.set_cs_pin(true)?;
-        sleep(Duration::from_millis(100));
-        log::info!("Reset VS1053... \n");
-        self.set_dcs_pin(false)?;
-        self.set_cs_pin(false)?;
-        sleep(Duration::from_millis(500));
-        log::info!("End reset VS1053... \n");
-        self.set_dcs_pin(true)?;
-        self.set_cs_pin(true)?;
-        sleep(Duration::from_millis(500));
-
-        log::info!("Pre test_comm slow");
-        if self.test_comm("Slow SPI,Testing VS1053 read/write registers...\n".as_ptr()) {
-            log::info!("Post test_comm slow");
-            // SLOWSPI
-            self.write_register(false, SCI_AUDATA, 44101)?; // 44.1kHz stereo
-                                                            // The next clocksetting allows SPI clocking at 5 MHz, 4 MHz is safe then.
-            self.write_register(false, SCI_CLOCKF, 6 << 12)?; // Normal clock settings multiplyer 3.0 = 12.2 MHz
-                                                              // SPI Clock to 4 MHz. Now you can set high speed SPI clock.
-
-            // FASTSPI
-            self.write_register(true, SCI_MODE, _bv!(SM_SDINEW) | _bv!(SM_LINE1))?;
-            log::info!("Pre test_comm fast");
-            let _ =
-                self.test_comm("Fast SPI, Testing VS1053 read/write registers again...\n".as_ptr());
-            sleep(Duration::from_millis(10));
-            log::info!("Pre await_data_request");
-            self.await_data_request()?;
-            log::info!("Post await_data_request");
-
-            let efb = self._wram_read(0x1E06)?;
-            let end_fill_byte = efb & 0xFF;
-            log::info!("endFillByte is {:X}\n", end_fill_byte);
-            self.print_details("After last clocksetting");
+        if self.xrst_pin.is_some() {
+            log::info!("Hardware reset VS1053... \n");
+            self.hardware_reset()?;
+            log::info!("End hardware reset VS1053... \n");
+        } else {
             sleep(Duration::from_millis(100));
+            log::info!("Reset VS1053... \n");
+            self.set_dcs_pin(false)?;
+            self.set_cs_pin(false)?;
+            sleep(Duration::from_millis(500));
+            log::info!("End reset VS1053... \n");
+            self.set_dcs_pin(true)?;
+            self.set_cs_pin(true)?;
+            sleep(Duration::from_millis(500));
         }
+
+        log::info!("Pre test_comm slow");
+        self.test_comm("Slow SPI, Testing VS1053 read/write registers...")?;
+        log::info!("Post test_comm slow");
+        // SLOWSPI
+        self.write_register(false, SCI_AUDATA, 44101)?; // 44.1kHz stereo
+                                                        // The next clocksetting allows SPI clocking at 5 MHz, 4 MHz is safe then.
+        self.write_register(false, SCI_CLOCKF, CLOCKF_MP3)?; // Normal clock settings multiplyer 3.0 = 12.2 MHz
+                                                             // SPI Clock to 4 MHz. Now you can set high speed SPI clock.
+
+        // FASTSPI
+        self.write_register(true, SCI_MODE, _bv!(SM_SDINEW) | _bv!(SM_LINE1))?;
+        log::info!("Pre test_comm fast");
+        self.test_comm("Fast SPI, Testing VS1053 read/write registers again...")?;
+        sleep(Duration::from_millis(10));
+        log::info!("Pre await_data_request");
+        self.await_data_request()?;
+        log::info!("Post await_data_request");
+
+        let efb = self._wram_read(PARA_END_FILL_BYTE)?;
+        let end_fill_byte = efb & 0xFF;
+        log::info!("endFillByte is {:X}\n", end_fill_byte);
+        self.print_details("After last clocksetting")?;
+        sleep(Duration::from_millis(100));
         Ok(())
     }
 
@@ -304,66 +469,51 @@ where
         Ok(u16::from_be_bytes(buf))
     }
 
+    // Slow writes lower the SPI clock to LOW_SPEED_SPI_HZ for the time of the write only
     fn write_register(&mut self, is_high_speed: bool, reg: u8, value: u16) -> Result<(), DSPError> {
         let lsb: u8 = (value & 0xFF) as u8;
         let msb: u8 = (value >> 8) as u8;
+        if !is_high_speed {
+            self.spi.set_clock(LOW_SPEED_SPI_HZ)?;
+        }
         self.control_mode_on()?;
 
-        if is_high_speed {
-            self.spi
-                .transaction(&mut [Operation::Write(&[0x2, reg, msb, lsb])])
-                .map_err(|error| {
-                    log::warn!("Failed to make SPI transaction for write_register: {error:?}");
-                    DSPError::Spi
-                })?;
-        } else {
-            self.low_spi
-                .transaction(&mut [Operation::Write(&[0x2, reg, msb, lsb])])
-                .map_err(|error| {
-                    log::warn!("Failed to make SPI transaction for LS write_register: {error:?}");
-                    DSPError::Spi
-                })?;
+        let result = self
+            .spi
+            .transaction(&mut [Operation::Write(&[0x2, reg, msb, lsb])])
+            .map_err(|error| {
+                log::warn!("Failed to make SPI transaction for write_register: {error:?}");
+                DSPError::Spi
+            });
+        if !is_high_speed {
+            self.spi.set_clock(self.high_speed_hz)?;
         }
+        result?;
 
         self.await_data_request()?;
         self.control_mode_off()?;
         Ok(())
     }
 
+    // Raw SDI write, the caller is responsible for being in data mode
     fn write_bytes(&mut self, data: &[u8]) -> Result<(), DSPError> {
-        self.control_mode_on()?;
-
         self.spi
             .transaction(&mut [Operation::Write(data)])
             .map_err(|error| {
                 log::warn!("Failed to make SPI transaction for write_bytes: {error:?}");
                 DSPError::Spi
-            })?;
-
-        self.await_data_request()?;
-        self.control_mode_off()?;
-        Ok(())
+            })
     }
 
-    fn test_comm(&mut self, header: *const u8) -> bool {
+    fn test_comm(&mut self, header: &str) -> Result<(), DSPError> {
         // Test the communication with the VS1053 module.  The result will be returned.
         // If DREQ is low, there is problably no VS1053 connected. Pull the line HIGH
         // in order to prevent an endless loop waiting for this signal.  The rest of the
         // software will still work, but readbacks from VS1053 will fail.
-        {
-            let dreq = match PinDriver::input(&mut self.dreq_pin) {
-                Ok(pin) => pin,
-                Err(err) => {
-                    warn!("Get DREQ pin for test_comm failed because: {:?}", err);
-                    None
-                }
-                .expect("DREQ test_comm failed"),
-            };
-            if !dreq.is_high() {
-                log::warn!("VS1053 not properly installed!\n");
-                //     pinMode(dreq_pin, INPUT_PULLUP); // DREQ is now input with pull-up
-                return false;
-            }
+        if !self.is_dreq_high()? {
+            log::warn!("VS1053 not properly installed, DREQ stays low!\n");
+            //     pinMode(dreq_pin, INPUT_PULLUP); // DREQ is now input with pull-up
+            return Err(DSPError::DataRequestTimeout);
         }
         // // Further TESTING.  Check if SCI bus can write and read without errors.
         // // We will use the volume setting for this.
@@ -372,24 +522,21 @@ where
 
         let (mut r1, mut r2);
         let mut cnt = 0;
+        let mut first_mismatch = None;
         let mut delta: usize = 300; // 3 for fast SPI
 
-        if contains(header, "Fast") {
+        if header.contains("Fast") {
             delta = 3; // Fast SPI, more loops
         }
-        log::info!("header:{:?}", header);
+        log::info!("{}", header);
 
         for i in (0..0xFFFF).step_by(delta) {
             if cnt >= 20 {
                 break;
             }
-            let _ = self.write_register(true, SCI_VOL, i); // Write data to SCI_VOL
-            r1 = self
-                .read_register(SCI_VOL)
-                .expect("First SCI_VOL test_comm read"); // Read back for the first time
-            r2 = self
-                .read_register(SCI_VOL)
-                .expect("Second SCI_VOL test_comm read"); // Read back a second time
+            self.write_register(true, SCI_VOL, i)?; // Write data to SCI_VOL
+            r1 = self.read_register(SCI_VOL)?; // Read back for the first time
+            r2 = self.read_register(SCI_VOL)?; // Read back a second time
             if r1 != r2 || i != r1 || i != r2 {
                 // Check for 2 equal reads
                 log::info!(
@@ -398,6 +545,11 @@ where
                     r1,
                     r2
                 );
+                first_mismatch.get_or_insert(DSPError::CommVerifyFailed {
+                    expected: i,
+                    got1: r1,
+                    got2: r2,
+                });
                 cnt += 1;
                 sleep(Duration::from_millis(10));
             } else {
@@ -405,39 +557,108 @@ where
             }
             // yield(); // Allow ESP firmware to do some bookkeeping
         }
-        cnt == 0 // Return the result
+        match first_mismatch {
+            Some(mismatch) => Err(mismatch),
+            None => Ok(()),
+        }
     }
 
     pub fn set_volume(&mut self, vol: u8) -> Result<(), DSPError> {
         // Set volume.  Both left and right.
         // Input value is 0..100.  100 is the loudest.
-        let (mut value_l, mut value_r) = (vol, vol); // Values to send to SCI_VOL
-
+        let vol = vol.min(100);
         self.current_volume = vol; // Save for later use
+        if self.muted {
+            // Still written, as resets bring the volume back to the maximum
+            return self.write_register(true, SCI_VOL, SCI_VOL_SILENT);
+        }
+        // Volume left and right
+        self.write_register(true, SCI_VOL, sci_vol(vol, self.current_balance))
+    }
+
+    /// Silences the output, the volume set meanwhile being the one `unmute` restores.
+    pub fn mute(&mut self) -> Result<(), DSPError> {
+        self.muted = true;
+        self.write_register(true, SCI_VOL, SCI_VOL_SILENT)
+    }
+
+    pub fn unmute(&mut self) -> Result<(), DSPError> {
+        self.muted = false;
+        self.set_volume(self.current_volume)
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Sets the balance, -100 (left only) to 100 (right only), and applies it to the volume.
+    pub fn set_balance(&mut self, balance: i8) -> Result<(), DSPError> {
+        self.current_balance = balance.clamp(-100, 100);
+        self.set_volume(self.current_volume)
+    }
 
-        match self.current_balance {
-            balance if balance < 0 => value_r = max(0, vol.saturating_add(balance.unsigned_abs())),
-            balance if balance > 0 => value_l = max(0, vol.saturating_sub(balance as u8)),
-            _ => {}
+    /// Sets bass and treble enhancement, packed into SCI_BASS (VS1053b datasheet 9.6.3).
+    ///
+    /// - `bass_freq`: bass lower limit frequency in 10 Hz steps, 2..15 (20..150 Hz)
+    /// - `bass_amp`: bass enhancement in 1 dB steps, 0..15, 0 disables it
+    /// - `treble_freq`: treble lower limit frequency in 1000 Hz steps, 1..15 (1..15 kHz)
+    /// - `treble_amp`: treble control in 1.5 dB steps, signed -8..7 stored as a 4 bits two's
+    ///   complement (8..15 mean -8..-1), 0 disables it
+    pub fn set_bass_treble(
+        &mut self,
+        bass_freq: u8,
+        bass_amp: u8,
+        treble_freq: u8,
+        treble_amp: u8,
+    ) -> Result<(), DSPError> {
+        let value = sci_bass(bass_freq, bass_amp, treble_freq, treble_amp)
+            .ok_or(DSPError::InvalidParameter)?;
+        self.write_register(true, SCI_BASS, value)?;
+        self.current_tone = Tone {
+            bass_freq,
+            bass_amp,
+            treble_freq,
+            treble_amp,
         };
+        Ok(())
+    }
 
-        value_l = map(value_l.into(), 0, 100, 0xFE, 0x00) as u8; // 0..100% to left channel
-        value_r = map(value_r.into(), 0, 100, 0xFE, 0x00) as u8; // 0..100% to right channel
+    pub fn set_tone_settings(&mut self, tone: Tone) -> Result<(), DSPError> {
+        self.set_bass_treble(
+            tone.bass_freq,
+            tone.bass_amp,
+            tone.treble_freq,
+            tone.treble_amp,
+        )
+    }
 
-        self.write_register(true, SCI_VOL, ((value_l as u16) << 8) | value_r as u16)
-        // Volume left and right
+    pub fn get_tone(&self) -> Tone {
+        self.current_tone
     }
 
-    pub fn set_balance(&mut self, balance: i8) {
-        if balance > 100 {
-            self.current_balance = 100;
-        } else if balance < -100 {
-            self.current_balance = -100;
+    /// Mixes both channels to each output, e.g. for a single speaker build.
+    ///
+    /// The mixing is done by the VS1053b patches (`playMode` parameter), without them the
+    /// output stays stereo: centering the balance is then the closest there is, each channel
+    /// still only playing on its own side.
+    pub fn set_mono(&mut self, mono: bool) -> Result<(), DSPError> {
+        let play_mode = self._wram_read(PARA_PLAY_MODE)?;
+        let play_mode = if mono {
+            play_mode | PLAY_MODE_MONO_OUTPUT
         } else {
-            self.current_balance = balance;
-        }
+            play_mode & !PLAY_MODE_MONO_OUTPUT
+        };
+        self.wram_write(PARA_PLAY_MODE, play_mode)?;
+        self.mono = mono;
+        Ok(())
     }
 
+    pub fn is_mono(&self) -> bool {
+        self.mono
+    }
+
+    #[deprecated(note = "use set_bass_treble, which does not read through a raw pointer")]
+    #[allow(dead_code)]
     pub fn set_tone(&mut self, rtone: *mut u8) {
         // Set bass/treble (4 nibbles) or : [u8; 4]
         // Set tone characteristics.  See documentation for the 4 nibbles.
@@ -453,26 +674,35 @@ where
         let _ = self.write_register(true, SCI_BASS, value); // Volume left and right
     }
 
+    /// Steps the volume from its current value to `target` over `duration_ms`.
+    pub fn fade_to(&mut self, target: u8, duration_ms: u32) -> Result<(), DSPError> {
+        fade(self.current_volume, target, duration_ms, |volume| {
+            self.set_volume(volume)
+        })
+    }
+
     pub fn get_volume(&mut self) -> u8 {
         // Get the current volume setting.
         self.current_volume
     }
 
-    #[allow(dead_code)]
-    fn get_balance(&mut self) -> i8 {
+    pub fn get_balance(&mut self) -> i8 {
         // Get the current balance setting.
         self.current_balance
     }
 
     #[allow(dead_code)]
-    fn start_song(&mut self) {
-        self.sdi_send_fillers(10);
+    /// Prepares the decoder for a new song and marks the playback as started.
+    pub fn start_song(&mut self) -> Result<(), DSPError> {
+        self.playback_state = PlaybackState::Playing;
+        self.sdi_send_fillers(10)
     }
 
     //TODO: test and take this one or the second function
-    pub fn play_chunk(&mut self, data: &u8, len: usize) {
+    #[allow(dead_code)]
+    pub fn play_chunk(&mut self, data: &u8, len: usize) -> Result<(), DSPError> {
         let data_ptr = data as *const u8 as *mut u8; // Cast &u8 to *mut u8
-        self.sdi_send_buffer(data_ptr, len);
+        self.sdi_send_buffer(data_ptr, len)
     }
 
     pub fn play_chunk2(&mut self, data: &[u8], chunk_size: usize) -> Result<(), DSPError> {
@@ -486,7 +716,18 @@ where
             } else {
                 remaining
             };
-            self.await_data_request()?;
+            let mut retries = self.dreq_retries;
+            while let Err(err) = self.await_data_request() {
+                if !matches!(err, DSPError::DataRequestTimeout) || retries == 0 {
+                    return Err(err);
+                }
+                retries -= 1;
+                warn!("DREQ timed out, soft resetting the decoder");
+                self.soft_reset();
+                // The reset brings the volume back to the maximum
+                self.set_volume(self.current_volume)?;
+                let _ = self.data_mode_on();
+            }
 
             // Send a chunk of data
             self.write_bytes(&data[offset..offset + chunk_length])?;
@@ -498,26 +739,131 @@ where
         Ok(())
     }
 
+    /// Plays a whole file, e.g. from the storage partition, as a song of its own.
+    ///
+    /// The file is fed by `VS1053_CHUNK_SIZE` bytes as soon as DREQ allows it, which blocks the
+    /// caller for the length of the file: a decoder shared with the HTTP handlers must only be
+    /// locked by a background thread for short files.
     #[allow(dead_code)]
-    fn stop_song(&mut self) {
+    pub fn play_file(&mut self, path: &str) -> Result<(), DSPError> {
+        let mut file = File::open(path).map_err(|error| {
+            warn!("Failed to open {path}: {error:?}");
+            DSPError::File
+        })?;
+        let mut buf = [0; FILE_READ_SIZE];
+        self.start_song()?;
+        loop {
+            let len = file.read(&mut buf).map_err(|error| {
+                warn!("Failed to read {path}: {error:?}");
+                DSPError::File
+            })?;
+            if len == 0 {
+                break;
+            }
+            self.play_chunk2(&buf[..len], VS1053_CHUNK_SIZE.into())?;
+        }
+        log::info!("Played {path}");
+        self.stop_song()
+    }
+
+    /// Pauses the playback, the chunks received meanwhile are dropped.
+    pub fn pause(&mut self) {
+        if self.playback_state == PlaybackState::Playing {
+            self.playback_state = PlaybackState::Paused;
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.playback_state == PlaybackState::Paused {
+            self.playback_state = PlaybackState::Playing;
+        }
+    }
+
+    pub fn playback_state(&self) -> PlaybackState {
+        self.playback_state
+    }
+
+    /// Streams a webradio from `url` until the server closes the connection.
+    #[allow(dead_code)]
+    pub fn connect_to_host(&mut self, url: &str) -> Result<(), DSPError> {
+        let never_stop = AtomicBool::new(false);
+        stream::stream_url(url, &never_stop, self)
+    }
+
+    /// Streams a webradio from `url` forever, reconnecting when the stream drops.
+    #[allow(dead_code)]
+    pub fn stream_with_reconnect(&mut self, url: &str) -> Result<(), DSPError> {
+        let never_stop = AtomicBool::new(false);
+        stream::stream_with_reconnect(url, &never_stop, self)
+    }
+
+    /// Title of the song currently streamed, as announced by the ICY metadata.
+    #[allow(dead_code)]
+    pub fn current_stream_title(&self) -> Option<String> {
+        self.stream_title.clone()
+    }
+
+    /// Cancels the song being decoded, flushing the decoder buffers.
+    pub fn stop_song(&mut self) -> Result<(), DSPError> {
         let mut modereg: u16; // Read from mode register
 
-        self.sdi_send_fillers(2052);
+        self.playback_state = PlaybackState::Stopped;
+
+        self.sdi_send_fillers(2052)?;
         sleep(Duration::from_millis(10));
-        let _ = self.write_register(true, SCI_MODE, _bv!(SM_SDINEW) | _bv!(SM_CANCEL));
+        self.write_register(true, SCI_MODE, _bv!(SM_SDINEW) | _bv!(SM_CANCEL))?;
         for i in 0..=200 {
-            self.sdi_send_fillers(32);
-            modereg = self
-                .read_register(SCI_MODE)
-                .expect("Failed to read SCI_MODE in stop_song()"); // Read status
+            self.sdi_send_fillers(32)?;
+            modereg = self.read_register(SCI_MODE)?; // Read status
             if (modereg & _bv!(SM_CANCEL)) == 0 {
-                self.sdi_send_fillers(2052);
+                self.sdi_send_fillers(2052)?;
                 log::info!("Song stopped correctly after {:?} msec\n", i * 10);
-                return;
+                return Ok(());
             }
             sleep(Duration::from_millis(10));
         }
-        self.print_details("Song stopped incorrectly!");
+        self.print_details("Song stopped incorrectly!")
+    }
+
+    /// Leaves the chip silent with both chip selects released, e.g. after a failed stream.
+    pub fn safe_stop(&mut self) {
+        let _ = self.data_mode_off();
+        let _ = self.control_mode_off();
+        if let Err(err) = self.stop_song() {
+            warn!("Unable to stop the song ({:?}), resetting", err);
+            self.soft_reset();
+        }
+    }
+
+    /// Silences the chip for good, before a crash or when it is torn down.
+    ///
+    /// The soft reset drops the patches and settings, `begin()` has to run again.
+    pub fn shutdown(&mut self) {
+        let _ = self.data_mode_off();
+        let _ = self.control_mode_off();
+        self.playback_state = PlaybackState::Stopped;
+        self.soft_reset();
+    }
+
+    /// Recovers a wedged decoder without a reboot: soft resets it, then applies the MP3 mode,
+    /// clock, volume and balance again and checks it still answers.
+    ///
+    /// Like the other soft resets, the patches must be reloaded afterwards.
+    pub fn reset(&mut self) -> Result<(), DSPError> {
+        let _ = self.data_mode_off();
+        let _ = self.control_mode_off();
+        self.playback_state = PlaybackState::Stopped;
+        // Soft resets the decoder once it is out of the MIDI mode
+        self.switch_to_mp3_mode();
+        self.write_register(false, SCI_CLOCKF, CLOCKF_MP3)?;
+        self.write_register(true, SCI_MODE, _bv!(SM_SDINEW) | _bv!(SM_LINE1))?;
+        // The reset brings the volume back to the maximum, the balance being applied with it
+        self.set_volume(self.current_volume)?;
+        if !self.is_chip_connected()? {
+            return Err(DSPError::ChipNotConnected);
+        }
+        log::info!("VS1053 reset");
+        Ok(())
     }
 
     fn soft_reset(&mut self) {
@@ -535,35 +881,37 @@ where
     //  * 160 kbit/s and VBR should not be used. For details, see Application Notes for VS10XX. This
     //  * mode only works with MP3 and WAV files."
     // */
-    // fn streamModeOn() {
-    //     LOG("Performing streamModeOn\n");
-    //     writeRegister(SCI_MODE, _BV(SM_SDINEW) | _BV(SM_STREAM));
-    //     delay(10);
-    //     await_data_request();
-    // }
-
-    // fn streamModeOff() {
-    //     LOG("Performing streamModeOff\n");
-    //     writeRegister(SCI_MODE, _BV(SM_SDINEW));
-    //     delay(10);
-    //     await_data_request();
-    // }
-
-    fn print_details(&mut self, header: &str) {
+    pub fn stream_mode_on(&mut self) -> Result<(), DSPError> {
+        log::info!("Performing stream_mode_on\n");
+        // Keeps the other mode bits, e.g. SM_LINE1 for the FM input
+        let mode = self.read_register(SCI_MODE)?;
+        self.write_register(true, SCI_MODE, mode | _bv!(SM_STREAM))?;
+        sleep(Duration::from_millis(10));
+        self.await_data_request()
+    }
+
+    pub fn stream_mode_off(&mut self) -> Result<(), DSPError> {
+        log::info!("Performing stream_mode_off\n");
+        let mode = self.read_register(SCI_MODE)?;
+        self.write_register(true, SCI_MODE, mode & !_bv!(SM_STREAM))?;
+        sleep(Duration::from_millis(10));
+        self.await_data_request()
+    }
+
+    fn print_details(&mut self, header: &str) -> Result<(), DSPError> {
         let mut regbuf: [u16; 16] = [0; 16];
 
         log::info!("{}", header);
         log::info!("REG   Contents\n");
         log::info!("---   -----\n");
         for i in 0..=SCI_NUM_REGISTERS {
-            regbuf[i as usize] = self
-                .read_register(i)
-                .expect("Failed to read_register in print_details()");
+            regbuf[i as usize] = self.read_register(i)?;
         }
         for i in 0..=SCI_NUM_REGISTERS {
             sleep(Duration::from_millis(5));
             log::info!("{}", &format!("{:3X} - {:5X}\n", i, regbuf[i as usize]));
         }
+        Ok(())
     }
 
     // /**
@@ -583,48 +931,98 @@ where
         self.soft_reset();
     }
 
-    // fn disableI2sOut() {
-    //     wram_write(ADDR_REG_I2S_CONFIG_RW, 0x0000);
-
-    //     // configure GPIO0 4-7 (I2S) as input (default)
-    //     // leave other GPIOs unchanged
-    //     uint16_t cur_ddr = wram_read(ADDR_REG_GPIO_DDR_RW);
-    //     wram_write(ADDR_REG_GPIO_DDR_RW, cur_ddr & ~0x00f0);
-    // }
-
-    // fn enableI2sOut(VS1053_I2S_RATE i2sRate) {
-    //     // configure GPIO0 4-7 (I2S) as output
-    //     // leave other GPIOs unchanged
-    //     uint16_t cur_ddr = wram_read(ADDR_REG_GPIO_DDR_RW);
-    //     wram_write(ADDR_REG_GPIO_DDR_RW, cur_ddr | 0x00f0);
-
-    //     uint16_t i2s_config = 0x000c; // Enable MCLK(3); I2S(2)
-    //     switch (i2sRate) {
-    //         case VS1053_I2S_RATE_192_KHZ:
-    //             i2s_config |= 0x0002;
-    //             break;
-    //         case VS1053_I2S_RATE_96_KHZ:
-    //             i2s_config |= 0x0001;
-    //             break;
-    //         default:
-    //         case VS1053_I2S_RATE_48_KHZ:
-    //             // 0x0000
-    //             break;
-    //     }
-
-    //     wram_write(ADDR_REG_I2S_CONFIG_RW, i2s_config );
-    // }
+    /// Runs the built-in memory test, returning SCI_HDAT0 which is `MEMORY_TEST_OK` when all
+    /// memories passed.
+    ///
+    /// The decoder has to be reset after the test, the clock and volume are set again but the
+    /// patches must be reloaded.
+    pub fn run_memory_test(&mut self) -> Result<u16, DSPError> {
+        self.playback_state = PlaybackState::Stopped;
+        self.write_register(true, SCI_MODE, _bv!(SM_SDINEW) | _bv!(SM_TESTS))?;
+        self.play_chunk2(&MEMORY_TEST_SEQUENCE, MEMORY_TEST_SEQUENCE.len())?;
+        sleep(MEMORY_TEST_DURATION);
+        let result = self.read_register(SCI_HDAT0)?;
+        log::info!("VS1053 memory test result:{:04X}", result);
+
+        self.soft_reset();
+        self.write_register(false, SCI_CLOCKF, CLOCKF_MP3)?;
+        self.write_register(true, SCI_MODE, _bv!(SM_SDINEW) | _bv!(SM_LINE1))?;
+        self.set_volume(self.current_volume)?;
+        Ok(result)
+    }
+
+    /// Outputs a sine for `duration_ms` through the SDI test mode.
+    ///
+    /// `freq` is the `n` byte of the sine test: the sample rate index (44100, 48000, 32000,
+    /// 22050, 24000, 16000, 11025 or 12000 Hz) in bits 7:5 and the skip speed S in bits 4:0,
+    /// the sine being at sample rate * S / 128 Hz. `0x44` plays 1 kHz.
+    pub fn sine_test(&mut self, freq: u8, duration_ms: u32) -> Result<(), DSPError> {
+        self.write_register(true, SCI_MODE, _bv!(SM_SDINEW) | _bv!(SM_TESTS))?;
+        let mut start = [0; 8];
+        start[..SINE_TEST_START.len()].copy_from_slice(&SINE_TEST_START);
+        start[SINE_TEST_START.len()] = freq;
+        self.play_chunk2(&start, start.len())?;
+        sleep(Duration::from_millis(duration_ms.into()));
+        self.play_chunk2(&SINE_TEST_END, SINE_TEST_END.len())?;
+        self.write_register(true, SCI_MODE, _bv!(SM_SDINEW) | _bv!(SM_LINE1))
+    }
+
+    /// Adjusts the internal clock to the codec, AAC needing more than the 3.0x enough for MP3.
+    ///
+    /// The VS1053 detects the format of the data itself, but an AAC stream decoded with the
+    /// MP3 clock settings only produces noise.
+    pub fn set_codec(&mut self, codec: Codec) -> Result<(), DSPError> {
+        let clockf = match codec {
+            Codec::Mp3 => CLOCKF_MP3,
+            // Unknown streams get the clock able to decode anything
+            Codec::Aac
+            | Codec::Wav
+            | Codec::Wma
+            | Codec::Ogg
+            | Codec::Flac
+            | Codec::Midi
+            | Codec::Unknown => CLOCKF_AAC,
+        };
+        self.write_register(true, SCI_CLOCKF, clockf)?;
+        sleep(Duration::from_millis(1));
+        self.await_data_request()
+    }
+
+    #[allow(dead_code)]
+    pub fn disable_i2s_out(&mut self) -> Result<(), DSPError> {
+        self.wram_write(ADDR_REG_I2S_CONFIG_RW, 0x0000)?;
+
+        // configure GPIO0 4-7 (I2S) as input (default)
+        // leave other GPIOs unchanged
+        let cur_ddr = self._wram_read(ADDR_REG_GPIO_DDR_RW)?;
+        self.wram_write(ADDR_REG_GPIO_DDR_RW, cur_ddr & !0x00f0)
+    }
+
+    #[allow(dead_code)]
+    pub fn enable_i2s_out(&mut self, rate: I2sRate) -> Result<(), DSPError> {
+        // configure GPIO0 4-7 (I2S) as output
+        // leave other GPIOs unchanged
+        let cur_ddr = self._wram_read(ADDR_REG_GPIO_DDR_RW)?;
+        self.wram_write(ADDR_REG_GPIO_DDR_RW, cur_ddr | 0x00f0)?;
+
+        let i2s_config: u16 = 0x000c; // Enable MCLK(3); I2S(2)
+        let rate_bits: u16 = match rate {
+            I2sRate::Khz192 => 0x0002,
+            I2sRate::Khz96 => 0x0001,
+            I2sRate::Khz48 => 0x0000,
+        };
+
+        self.wram_write(ADDR_REG_I2S_CONFIG_RW, i2s_config | rate_bits)
+    }
 
     // /**
     //  * A lightweight method to check if VS1053 is correctly wired up (power supply and connection to SPI interface).
     //  *
     //  * @return true if the chip is wired up correctly
     //  */
-    pub fn is_chip_connected(&mut self) -> bool {
-        let status: u16 = self
-            .read_register(SCI_STATUS)
-            .expect("Failed to read SCI_STATUS for is_chip_connected()");
-        !(status == 0 || status == 0xFFFF)
+    pub fn is_chip_connected(&mut self) -> Result<bool, DSPError> {
+        let status: u16 = self.read_register(SCI_STATUS)?;
+        Ok(!(status == 0 || status == 0xFFFF))
     }
 
     // /**
@@ -632,11 +1030,14 @@ where
     //  * VLSI datasheet: 0 for VS1001, 1 for VS1011, 2 for VS1002, 3 for VS1003, 4 for VS1053 and VS8053,
     //  * 5 for VS1033, 7 for VS1103, and 6 for VS1063.
     //  */
-    pub fn get_chip_version(&mut self) -> u16 {
-        let status: u16 = self
-            .read_register(SCI_STATUS)
-            .expect("Failed to read SCI_STATUS for get_chip_version()");
-        (status & 0x00F0) >> 4
+    pub fn get_chip_version(&mut self) -> Result<u16, DSPError> {
+        let status: u16 = self.read_register(SCI_STATUS)?;
+        Ok((status & 0x00F0) >> 4)
+    }
+
+    /// `get_chip_version`, named after the chip it stands for.
+    pub fn chip_model(&mut self) -> Result<ChipVersion, DSPError> {
+        self.get_chip_version().map(ChipVersion::from)
     }
 
     // /**
@@ -659,9 +1060,18 @@ where
     //  *
     //  * @return current decoded time in full seconds
     //  */
-    // uint16_t VS1053::getDecodedTime() {
-    //     return read_register(SCI_DECODE_TIME);
-    // }
+    #[allow(dead_code)]
+    pub fn get_decoded_time(&mut self) -> Result<u16, DSPError> {
+        self.read_register(SCI_DECODE_TIME)
+    }
+
+    /// Codec, bitrate and sample rate detected in the stream being decoded.
+    pub fn stream_info(&mut self) -> Result<StreamInfo, DSPError> {
+        let hdat0 = self.read_register(SCI_HDAT0)?;
+        let hdat1 = self.read_register(SCI_HDAT1)?;
+        let audata = self.read_register(SCI_AUDATA)?;
+        Ok(StreamInfo::from_registers(hdat0, hdat1, audata))
+    }
 
     // /**
     //  * Clears decoded time (sets SCI_DECODE_TIME register to 0x00)
@@ -671,74 +1081,222 @@ where
     //  * overwritten by the firmware. A write to SCI_DECODE_TIME also resets the
     //  * byteRate calculation.
     //  */
-    // fn clearDecodedTime() {
-    //     writeRegister(SCI_DECODE_TIME, 0x00);
-    //     writeRegister(SCI_DECODE_TIME, 0x00);
-    // }
+    #[allow(dead_code)]
+    pub fn clear_decoded_time(&mut self) -> Result<(), DSPError> {
+        self.write_register(true, SCI_DECODE_TIME, 0x00)?;
+        self.write_register(true, SCI_DECODE_TIME, 0x00)
+    }
 
-    // /**
-    //  * Fine tune the data rate
-    //  */
-    // fn adjustRate(long ppm2) {
-    //     writeRegister(SCI_WRAMADDR, 0x1e07);
-    //     writeRegister(SCI_WRAM, ppm2);
-    //     writeRegister(SCI_WRAM, ppm2 >> 16);
-    //     // oldClock4KHz = 0 forces  adjustment calculation when rate checked.
-    //     writeRegister(SCI_WRAMADDR, 0x5b1c);
-    //     writeRegister(SCI_WRAM, 0);
-    //     // Write to AUDATA or CLOCKF checks rate and recalculates adjustment.
-    //     writeRegister(SCI_AUDATA, read_register(SCI_AUDATA));
-    // }
+    /// Approximate left and right output levels, from the VU meter of the VS1053b patches.
+    ///
+    /// The VU meter only exists once the patches are loaded with `load_user_code` (see
+    /// `load_default_vs1053_patches`), it is enabled on the first call. Levels are reported in
+    /// about 1 dB steps, higher is louder, and are zero when nothing is playing.
+    #[allow(dead_code)]
+    pub fn get_audio_level(&mut self) -> Result<(u8, u8), DSPError> {
+        if self.playback_state != PlaybackState::Playing {
+            return Ok((0, 0));
+        }
+        let status = self.read_register(SCI_STATUS)?;
+        if status & _bv!(SS_VU_ENABLE) == 0 {
+            self.write_register(true, SCI_STATUS, status | _bv!(SS_VU_ENABLE))?;
+        }
+        let levels = self.read_register(SCI_AICTRL3)?;
+        Ok(((levels >> 8) as u8, levels as u8))
+    }
 
-    // /**
-    //  * Load a patch or plugin
-    //  *
-    //  * Patches can be found on the VLSI Website http://www.vlsi.fi/en/support/software/vs10xxpatches.html
-    //  *
-    //  * Please note that loadUserCode only works for compressed plugins (file ending .plg).
-    //  * To include them, rename them to file ending .h
-    //  * Please also note that, in order to avoid multiple definitions, if you are using more than one patch,
-    //  * it is necessary to rename the name of the array plugin[] and the name of PLUGIN_SIZE to names of your choice.
-    //  * example: after renaming plugin[] to plugin_myname[] and PLUGIN_SIZE to PLUGIN_MYNAME_SIZE
-    //  * the method is called by player.loadUserCode(plugin_myname, PLUGIN_MYNAME_SIZE)
-    //  * It is also possible to just rename the array plugin[] to a name of your choice
-    //  * example: after renaming plugin[] to plugin_myname[]
-    //  * the method is called by player.loadUserCode(plugin_myname, sizeof(plugin_myname)/sizeof(plugin_myname[0]))
-    //  */
-    // fn loadUserCode(const unsigned short* plugin, unsigned short plugin_size) {
-    //     int i = 0;
-    //     while (i<plugin_size) {
-    //         unsigned short addr, n, val;
-    //         addr = plugin[i++];
-    //         n = plugin[i++];
-    //         if (n & 0x8000U) { /* RLE run, replicate n samples */
-    //             n &= 0x7FFF;
-    //             val = plugin[i++];
-    //             while (n--) {
-    //                 writeRegister(addr, val);
-    //             }
-    //         } else {           /* Copy run, copy n samples */
-    //             while (n--) {
-    //                 val = plugin[i++];
-    //                 writeRegister(addr, val);
-    //             }
-    //         }
-    //     }
-    // }
+    /// Fine tunes the data rate by `ppm2` (in units of 1/2 ppm, i.e. 2000 makes it 0.1% faster),
+    /// to keep long streams in sync with the rate they are received at.
+    #[allow(dead_code)]
+    pub fn adjust_rate(&mut self, ppm2: i32) -> Result<(), DSPError> {
+        self.write_register(true, SCI_WRAMADDR, PARA_RATE_TUNE)?;
+        self.write_register(true, SCI_WRAM, ppm2 as u16)?;
+        self.write_register(true, SCI_WRAM, (ppm2 >> 16) as u16)?;
+        // oldClock4KHz = 0 forces adjustment calculation when rate checked.
+        self.wram_write(ADDR_OLD_CLOCK_4KHZ, 0)?;
+        // Write to AUDATA or CLOCKF checks rate and recalculates adjustment.
+        let audata = self.read_register(SCI_AUDATA)?;
+        self.write_register(true, SCI_AUDATA, audata)
+    }
 
-    // /**
-    //  * Load the latest generic firmware patch
-    //  */
-    // fn loadDefaultVs1053Patches() {
-    //    loadUserCode(PATCHES,PATCHES_SIZE);
-    // };
+    /// Plays `speed` times faster than normal, 0 and 1 being the normal speed.
+    ///
+    /// Every frame is still decoded but only one in `speed` is played, so this only affects the
+    /// frame based codecs (e.g. MP3 or AAC), see VS1053b Datasheet (1.31) / 10.11. The speed
+    /// is reset by a software reset.
+    #[allow(dead_code)]
+    pub fn set_play_speed(&mut self, speed: u8) -> Result<(), DSPError> {
+        self.wram_write(PARA_PLAY_SPEED, speed.into())
+    }
+
+    /// Loads a patch or plugin, given as the compressed 16 bits words of a `.plg` file.
+    ///
+    /// Patches can be found on the VLSI Website http://www.vlsi.fi/en/support/software/vs10xxpatches.html
+    /// Each record is an address, a count, then either one value repeated `count & 0x7FFF` times
+    /// (RLE run, high bit set) or `count` values to copy.
+    pub fn load_user_code(&mut self, plugin: &[u16]) -> Result<(), DSPError> {
+        let mut words = plugin.iter().copied();
+        while let Some(addr) = words.next() {
+            let n = words.next().ok_or(DSPError::InvalidPlugin)?;
+            if n & 0x8000 != 0 {
+                // RLE run, replicate n samples
+                let val = words.next().ok_or(DSPError::InvalidPlugin)?;
+                for _ in 0..(n & 0x7FFF) {
+                    self.write_register(true, addr as u8, val)?;
+                }
+            } else {
+                // Copy run, copy n samples
+                for _ in 0..n {
+                    let val = words.next().ok_or(DSPError::InvalidPlugin)?;
+                    self.write_register(true, addr as u8, val)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the latest generic firmware patch, fixing among others AAC decoding bugs.
+    ///
+    /// A soft reset drops the patches, load them after `switch_to_mp3_mode` or `reset`.
+    pub fn load_default_vs1053_patches(&mut self) -> Result<(), DSPError> {
+        if VS1053B_PATCHES.is_empty() {
+            return Err(DSPError::InvalidPlugin);
+        }
+        self.load_user_code(VS1053B_PATCHES)?;
+        log::info!("Loaded {} words of VS1053b patches", VS1053B_PATCHES.len());
+        Ok(())
+    }
+}
+
+impl<SPI, XRST, XCS, XDCS, DREQ> StreamSink for VS1053<SPI, XRST, XCS, XDCS, DREQ>
+where
+    SPI: ClockedSpi,
+    XRST: OutputPin,
+    XCS: OutputPin,
+    XDCS: OutputPin,
+    DREQ: DataRequestPin,
+{
+    fn play(&mut self, chunk: &[u8]) -> Result<(), DSPError> {
+        match self.playback_state {
+            // A live stream can't be held, what is received while paused is skipped
+            PlaybackState::Paused => return Ok(()),
+            PlaybackState::Stopped => self.start_song()?,
+            PlaybackState::Playing => {}
+        }
+        self.play_chunk2(chunk, VS1053_CHUNK_SIZE.into())
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.stream_title = title;
+    }
+
+    fn abort(&mut self) {
+        self.safe_stop();
+    }
+}
+
+impl<SPI, XRST, XCS, XDCS, DREQ> StreamSink for Arc<Mutex<VS1053<SPI, XRST, XCS, XDCS, DREQ>>>
+where
+    SPI: ClockedSpi,
+    XRST: OutputPin,
+    XCS: OutputPin,
+    XDCS: OutputPin,
+    DREQ: DataRequestPin,
+{
+    fn play(&mut self, chunk: &[u8]) -> Result<(), DSPError> {
+        self.lock().unwrap().play(chunk)
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.lock().unwrap().set_title(title);
+    }
+
+    fn abort(&mut self) {
+        self.lock().unwrap().abort();
+    }
+}
+
+/// Bass and treble settings, see `VS1053::set_bass_treble`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tone {
+    pub bass_freq: u8,
+    pub bass_amp: u8,
+    pub treble_freq: u8,
+    pub treble_amp: u8,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// Chip family, as reported by the SS_VER bits of SCI_STATUS.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChipVersion {
+    Vs1001,
+    Vs1011,
+    Vs1002,
+    Vs1003,
+    /// Also the VS8053
+    Vs1053,
+    Vs1033,
+    Vs1103,
+    /// Also the VS1163
+    Vs1063,
+    Unknown(u16),
+}
+
+impl From<u16> for ChipVersion {
+    fn from(version: u16) -> Self {
+        match version {
+            0 => ChipVersion::Vs1001,
+            1 => ChipVersion::Vs1011,
+            2 => ChipVersion::Vs1002,
+            3 => ChipVersion::Vs1003,
+            4 => ChipVersion::Vs1053,
+            5 => ChipVersion::Vs1033,
+            6 => ChipVersion::Vs1063,
+            7 => ChipVersion::Vs1103,
+            version => ChipVersion::Unknown(version),
+        }
+    }
+}
+
+/// Sample rate of the I2S output
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, Default)]
+pub enum I2sRate {
+    #[default]
+    Khz48,
+    Khz96,
+    Khz192,
 }
 
 #[derive(Copy, Clone, Debug)]
 pub enum DSPError {
     Spi,
+    /// A register read back differently from what was written to it
+    CommVerifyFailed {
+        expected: u16,
+        got1: u16,
+        got2: u16,
+    },
+    UnableToSetXRSTPin,
     UnableToSetCSPin,
     UnableToSetDCSPin,
     UnableToGetDREQPin,
     DataRequestTimeout,
+    Http,
+    /// A local file could not be opened or read
+    File,
+    /// TLS handshake failed, usually for lack of memory
+    Tls,
+    InvalidPlugin,
+    InvalidParameter,
+    /// The chip status reads as all zeros or ones, e.g. unpowered or unwired
+    ChipNotConnected,
 }
@@ -0,0 +1,6 @@
+// VS1053b generic firmware patches, as the compressed 16 bits words of the `.plg` file.
+//
+// Download the latest `vs1053b-patches.plg` from
+// http://www.vlsi.fi/en/support/software/vs10xxpatches.html and paste the content of its
+// `plugin[]` array below. While empty, `load_default_vs1053_patches` fails.
+pub static VS1053B_PATCHES: &[u16] = &[];
@@ -0,0 +1,58 @@
+use log::{info, warn};
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// Playback without the decoded time advancing for this long is considered stalled
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+const WATCHDOG_THREAD_STACK_SIZE: usize = 4 * 1024;
+
+static STALLS: AtomicU32 = AtomicU32::new(0);
+
+/// Number of stalls recovered from since boot.
+pub fn stall_count() -> u32 {
+    STALLS.load(Ordering::Relaxed)
+}
+
+/// Calls `restart` when the playback stops progressing.
+///
+/// `decoded_time` returns the decoded seconds of the stream being played, `None` when nothing
+/// should be progressing, e.g. on FM, while paused or while the stream buffers.
+pub fn spawn_watchdog<P, R>(mut decoded_time: P, mut restart: R) -> std::io::Result<JoinHandle<()>>
+where
+    P: FnMut() -> Option<u16> + Send + 'static,
+    R: FnMut() + Send + 'static,
+{
+    thread::Builder::new()
+        .name("watchdog".into())
+        .stack_size(WATCHDOG_THREAD_STACK_SIZE)
+        .spawn(move || {
+            let mut last_progress: Option<(u16, Instant)> = None;
+            loop {
+                sleep(CHECK_INTERVAL);
+                let Some(time) = decoded_time() else {
+                    last_progress = None;
+                    continue;
+                };
+                match last_progress {
+                    Some((last_time, since)) if last_time == time => {
+                        if since.elapsed() < STALL_TIMEOUT {
+                            continue;
+                        }
+                        let stalls = STALLS.fetch_add(1, Ordering::Relaxed) + 1;
+                        warn!(
+                            "Playback stalled at {}s, restarting it ({} stalls)",
+                            time, stalls
+                        );
+                        restart();
+                        last_progress = None;
+                        info!("Playback restarted");
+                    }
+                    _ => last_progress = Some((time, Instant::now())),
+                }
+            }
+        })
+}
@@ -0,0 +1,138 @@
+use crate::events::EVENTS;
+use esp_idf_svc::{
+    http::server::{
+        ws::{EspHttpWsConnection, EspHttpWsDetachedSender},
+        EspHttpServer,
+    },
+    sys::{EspError, ESP_FAIL},
+    ws::FrameType,
+};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::RecvTimeoutError,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+const MAX_CLIENTS: usize = 4;
+const MAX_COMMAND_LEN: usize = 256;
+/// Time between the checks of a silent client, to notice it disconnected
+const CLOSED_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const CLIENT_THREAD_STACK_SIZE: usize = 4 * 1024;
+
+/// Each client holds a thread forwarding the events, so their number is capped.
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Command sent by a client, e.g. `{"command":"setVolume","volume":40}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum WsCommand {
+    SetStation { station: String },
+    SetVolume { volume: u8 },
+    Play,
+    Pause,
+}
+
+#[derive(Deserialize)]
+struct WsMessage {
+    #[serde(flatten)]
+    command: WsCommand,
+    /// Value of an `Authorization` header, browsers not letting a WebSocket set one
+    authorization: Option<String>,
+}
+
+/// Serves `/ws`: the events of the bus are pushed to every client as JSON, and each command
+/// received is answered with `{"ok":true}` or `{"error":"..."}`.
+///
+/// `is_authorized` checks the `authorization` of the commands, the events being public.
+pub fn register_ws_handler<A, C>(
+    server: &mut EspHttpServer<'static>,
+    is_authorized: A,
+    on_command: C,
+) -> Result<(), EspError>
+where
+    A: Fn(Option<&str>) -> bool + Send + Sync + 'static,
+    C: Fn(WsCommand) -> anyhow::Result<()> + Send + Sync + 'static,
+{
+    server.ws_handler("/ws", move |ws: &mut EspHttpWsConnection| {
+        if ws.is_new() {
+            if CLIENTS.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                CLIENTS.fetch_sub(1, Ordering::SeqCst);
+                warn!(
+                    "WebSocket client refused, {} already connected",
+                    MAX_CLIENTS
+                );
+                // Closes the session
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+            if let Err(err) = spawn_client(ws.create_detached_sender()?) {
+                CLIENTS.fetch_sub(1, Ordering::SeqCst);
+                warn!("Unable to serve WebSocket client: {:?}", err);
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+            info!("WebSocket client {} connected", ws.session());
+            return Ok(());
+        }
+        if ws.is_closed() {
+            // Its thread stops on its next send or check
+            info!("WebSocket client {} disconnected", ws.session());
+            return Ok(());
+        }
+
+        // An empty buffer only gets the length of the frame
+        let (_, len) = ws.recv(&mut [])?;
+        if len > MAX_COMMAND_LEN {
+            warn!("WebSocket command of {} bytes dropped", len);
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+        let mut buf = vec![0; len];
+        let (frame_type, _) = ws.recv(&mut buf)?;
+        if !matches!(frame_type, FrameType::Text(false)) {
+            return Ok(());
+        }
+        let reply = match serde_json::from_slice::<WsMessage>(&buf) {
+            Err(_) => json!({ "error": "JSON error" }),
+            Ok(message) if !is_authorized(message.authorization.as_deref()) => {
+                json!({ "error": "Unauthorized" })
+            }
+            Ok(message) => match on_command(message.command) {
+                Ok(()) => json!({ "ok": true }),
+                Err(err) => json!({ "error": err.to_string() }),
+            },
+        };
+        ws.send(FrameType::Text(false), reply.to_string().as_bytes())
+    })?;
+    Ok(())
+}
+
+fn spawn_client(mut sender: EspHttpWsDetachedSender) -> io::Result<JoinHandle<()>> {
+    let events = EVENTS.subscribe();
+    thread::Builder::new()
+        .name("ws-client".into())
+        .stack_size(CLIENT_THREAD_STACK_SIZE)
+        .spawn(move || {
+            // Returning drops the receiver, which unsubscribes on the next publish
+            loop {
+                match events.recv_timeout(CLOSED_CHECK_INTERVAL) {
+                    Ok(event) => {
+                        let message = event.to_json();
+                        if sender
+                            .send(FrameType::Text(false), message.as_bytes())
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) if !sender.is_closed() => {}
+                    Err(_) => break,
+                }
+            }
+            CLIENTS.fetch_sub(1, Ordering::SeqCst);
+        })
+}